@@ -0,0 +1,21 @@
+DROP INDEX users_updated_at_idx;
+DROP INDEX parts_updated_at_idx;
+DROP INDEX cars_updated_at_idx;
+
+ALTER TABLE users
+    DROP COLUMN updated_by,
+    DROP COLUMN created_by,
+    DROP COLUMN updated_at,
+    DROP COLUMN created_at;
+
+ALTER TABLE parts
+    DROP COLUMN updated_by,
+    DROP COLUMN created_by,
+    DROP COLUMN updated_at,
+    DROP COLUMN created_at;
+
+ALTER TABLE cars
+    DROP COLUMN updated_by,
+    DROP COLUMN created_by,
+    DROP COLUMN updated_at,
+    DROP COLUMN created_at;
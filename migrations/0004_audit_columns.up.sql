@@ -0,0 +1,21 @@
+ALTER TABLE cars
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN created_by TEXT,
+    ADD COLUMN updated_by TEXT;
+
+ALTER TABLE parts
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN created_by TEXT,
+    ADD COLUMN updated_by TEXT;
+
+ALTER TABLE users
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN created_by TEXT,
+    ADD COLUMN updated_by TEXT;
+
+CREATE INDEX cars_updated_at_idx ON cars (updated_at);
+CREATE INDEX parts_updated_at_idx ON parts (updated_at);
+CREATE INDEX users_updated_at_idx ON users (updated_at);
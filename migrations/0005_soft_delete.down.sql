@@ -0,0 +1,11 @@
+ALTER TABLE parts
+    DROP CONSTRAINT parts_car_id_fkey,
+    ADD CONSTRAINT parts_car_id_fkey FOREIGN KEY (car_id) REFERENCES cars (id);
+
+DROP INDEX users_deleted_at_idx;
+DROP INDEX parts_deleted_at_idx;
+DROP INDEX cars_deleted_at_idx;
+
+ALTER TABLE users DROP COLUMN deleted_at;
+ALTER TABLE parts DROP COLUMN deleted_at;
+ALTER TABLE cars DROP COLUMN deleted_at;
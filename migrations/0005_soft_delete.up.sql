@@ -0,0 +1,12 @@
+ALTER TABLE cars ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE parts ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX cars_deleted_at_idx ON cars (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX parts_deleted_at_idx ON parts (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX users_deleted_at_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- purging a car must not fail because of parts that still reference it
+ALTER TABLE parts
+    DROP CONSTRAINT parts_car_id_fkey,
+    ADD CONSTRAINT parts_car_id_fkey FOREIGN KEY (car_id) REFERENCES cars (id) ON DELETE SET NULL;
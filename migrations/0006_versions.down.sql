@@ -0,0 +1,2 @@
+ALTER TABLE parts DROP COLUMN version;
+ALTER TABLE cars DROP COLUMN version;
@@ -0,0 +1,2 @@
+ALTER TABLE cars ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE parts ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
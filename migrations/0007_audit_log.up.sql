@@ -0,0 +1,25 @@
+CREATE TABLE audit_log (
+    id          BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    actor       TEXT        NOT NULL,
+    entity      TEXT        NOT NULL,
+    entity_id   INTEGER     NOT NULL,
+    action      TEXT        NOT NULL,
+    changes     JSONB       NOT NULL,
+    request_id  TEXT
+);
+
+CREATE INDEX audit_log_entity_idx ON audit_log (entity, entity_id);
+CREATE INDEX audit_log_actor_idx ON audit_log (actor);
+CREATE INDEX audit_log_occurred_at_idx ON audit_log (occurred_at);
+
+-- the log is append-only, history must not be rewritten
+CREATE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only
+    BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
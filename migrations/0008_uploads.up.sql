@@ -0,0 +1,11 @@
+CREATE TABLE uploads (
+    id            UUID        PRIMARY KEY,
+    owner         TEXT        NOT NULL,
+    original_name TEXT        NOT NULL,
+    size          BIGINT      NOT NULL,
+    content_type  TEXT        NOT NULL,
+    sha256        TEXT        NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX uploads_owner_idx ON uploads (owner, created_at);
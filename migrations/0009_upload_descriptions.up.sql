@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN description TEXT;
@@ -0,0 +1,12 @@
+-- Uploads attached to a car or a part. An upload is attached to at most one of them.
+CREATE TABLE attachments (
+    upload_id  UUID        PRIMARY KEY REFERENCES uploads (id) ON DELETE CASCADE,
+    car_id     INTEGER     REFERENCES cars (id) ON DELETE CASCADE,
+    part_id    INTEGER     REFERENCES parts (id) ON DELETE CASCADE,
+    created_by TEXT        NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK ((car_id IS NULL) <> (part_id IS NULL))
+);
+
+CREATE INDEX attachments_car_idx ON attachments (car_id, created_at);
+CREATE INDEX attachments_part_idx ON attachments (part_id, created_at);
@@ -0,0 +1,2 @@
+-- Edges in pixels of the thumbnails made of an image upload so far, see `services::thumbnails`
+ALTER TABLE uploads ADD COLUMN thumbnails INT[] NOT NULL DEFAULT '{}';
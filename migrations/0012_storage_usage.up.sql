@@ -0,0 +1,9 @@
+-- Bytes of uploads each user has stored, kept up to date by the upload repository so that
+-- quotas can be checked without summing every upload
+CREATE TABLE storage_usage (
+    owner TEXT   PRIMARY KEY,
+    bytes BIGINT NOT NULL CHECK (bytes >= 0)
+);
+
+INSERT INTO storage_usage (owner, bytes)
+SELECT owner, SUM(size) FROM uploads GROUP BY owner;
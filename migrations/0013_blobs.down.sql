@@ -0,0 +1,3 @@
+-- Only uploads whose bytes are under their own id can still be read after this
+ALTER TABLE uploads DROP COLUMN blob;
+DROP TABLE blobs;
@@ -0,0 +1,27 @@
+-- The stored bytes of uploads, kept once per content. `key` is where the bytes are in storage:
+-- the id of the upload that first stored them. `refs` counts the uploads that point at them,
+-- blobs down to none are removed by `services::uploads::collect_garbage`.
+CREATE TABLE blobs (
+    key    TEXT    PRIMARY KEY,
+    -- hex SHA-256 of the content, what identical uploads are found by
+    sha256 TEXT    UNIQUE,
+    size   BIGINT  NOT NULL,
+    refs   INTEGER NOT NULL CHECK (refs >= 0)
+);
+
+CREATE INDEX blobs_unreferenced_idx ON blobs (key) WHERE refs = 0;
+
+-- Files uploaded so far keep their bytes under their own id. Of identical ones only the oldest
+-- can be shared from now on, the others have no `sha256` to be found by.
+INSERT INTO blobs (key, sha256, size, refs)
+SELECT id::TEXT,
+       CASE WHEN ROW_NUMBER() OVER (PARTITION BY sha256 ORDER BY created_at, id) = 1
+            THEN sha256 END,
+       size,
+       1
+FROM uploads;
+
+ALTER TABLE uploads ADD COLUMN blob TEXT REFERENCES blobs (key);
+UPDATE uploads SET blob = id::TEXT;
+ALTER TABLE uploads ALTER COLUMN blob SET NOT NULL;
+CREATE INDEX uploads_blob_idx ON uploads (blob);
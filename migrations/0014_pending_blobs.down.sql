@@ -0,0 +1,2 @@
+DELETE FROM blobs WHERE pending_since IS NOT NULL;
+ALTER TABLE blobs DROP COLUMN pending_since;
@@ -0,0 +1,4 @@
+-- Blobs are recorded before their bytes are stored, so bytes an upload left behind when it was
+-- cut off are still found by `services::uploads::collect_garbage`. Until the upload is recorded
+-- the row has no references, `pending_since` keeps it from being collected in the meantime.
+ALTER TABLE blobs ADD COLUMN pending_since TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- Admins may see and restore what was deleted and act on anyone's records
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
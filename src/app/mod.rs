@@ -1,9 +1,19 @@
 use crate::cache::create_cache;
 use crate::config::Config;
+use crate::controllers::conditional::CacheControl;
+use crate::controllers::idempotency::IdempotencyWindow;
+use crate::controllers::resumable::{TUS_RESUMABLE, UPLOAD_EXPIRES, UPLOAD_LENGTH, UPLOAD_OFFSET};
+use crate::db::postgres;
+use crate::jobs;
+use crate::rate_limit::RateLimitLayer;
 use crate::repositories::{
-    create_car_repository, create_part_repository, create_user_repository, run_migrations,
+    create_attachment_repository, create_audit_repository, create_car_repository,
+    create_part_repository, create_upload_repository, create_user_repository,
+    resumable::ResumableRepositoryImpl, run_migrations,
 };
 use crate::router::router;
+use crate::services::uploads::UploadSettings;
+use crate::storage::create_storage;
 use axum::body::{Body, Bytes};
 use axum::extract::{MatchedPath, Request};
 use axum::middleware::Next;
@@ -11,8 +21,10 @@ use axum::response::{IntoResponse, Response};
 use axum::{Extension, Router, middleware};
 use http_body_util::BodyExt;
 use hyper::StatusCode;
+use hyper::header::{CONTENT_TYPE, LOCATION};
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
 use tracing::info_span;
 
@@ -22,7 +34,24 @@ pub async fn create_app(config: &Config) -> Router {
     let user_repository = Arc::new(create_user_repository(config).await);
     let car_repository = Arc::new(create_car_repository(config).await);
     let part_repository = Arc::new(create_part_repository(config).await);
+    let audit_repository = Arc::new(create_audit_repository(config).await);
+    let upload_repository = Arc::new(create_upload_repository(config).await);
+    let attachment_repository = Arc::new(create_attachment_repository(config).await);
     let cache = Arc::new(create_cache(config).await);
+    let resumable_repository = Arc::new(ResumableRepositoryImpl::new(cache.clone()));
+    let storage = create_storage(config).await;
+
+    jobs::spawn_purge(
+        config,
+        user_repository.clone(),
+        car_repository.clone(),
+        part_repository.clone(),
+        attachment_repository.clone(),
+        storage.clone(),
+        cache.clone(),
+    );
+    jobs::spawn_resumable_expiry(config, resumable_repository.clone(), storage.clone());
+    jobs::spawn_blob_collection(config, upload_repository.clone(), storage.clone());
 
     let allow_origins = [
         "http://127.0.0.1:3000".parse().unwrap(),
@@ -54,20 +83,51 @@ pub async fn create_app(config: &Config) -> Router {
                 .on_failure(()),
         )
         .layer(middleware::from_fn(print_request_body))
+        .layer(middleware::from_fn(postgres::read_your_writes))
+        // Inside CORS, so preflights are not counted and 429s still carry the CORS headers
+        .layer(RateLimitLayer::new(config, cache.clone()))
         .layer(
             CorsLayer::new()
                 .allow_origin(allow_origins)
                 .allow_headers(Any)
-                .allow_methods(Any),
+                .allow_methods(Any)
+                // for browser clients of the resumable uploads
+                .expose_headers([
+                    LOCATION,
+                    TUS_RESUMABLE,
+                    UPLOAD_OFFSET,
+                    UPLOAD_LENGTH,
+                    UPLOAD_EXPIRES,
+                ]),
         )
         .layer(Extension(user_repository))
         .layer(Extension(car_repository))
         .layer(Extension(part_repository))
+        .layer(Extension(audit_repository))
+        .layer(Extension(upload_repository))
+        .layer(Extension(attachment_repository))
+        .layer(Extension(resumable_repository))
         .layer(Extension(cache))
+        .layer(Extension(CacheControl::from(config)))
+        .layer(Extension(IdempotencyWindow::from(config)))
+        .layer(Extension(Arc::new(UploadSettings::from(config))))
+        .layer(Extension(storage))
+        // Outermost, so the id is assigned before anything else sees the request and echoed back
+        // on every response. A client-supplied `x-request-id` is kept as is.
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
 }
 
 // middleware that shows how to consume the request body upfront
 async fn print_request_body(request: Request, next: Next) -> Result<impl IntoResponse, Response> {
+    // only JSON is logged: uploads must reach their handler as a stream, not buffered here
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return Ok(next.run(request).await);
+    }
     let request = buffer_request_body(request).await?;
 
     Ok(next.run(request).await)
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+// Single-flight: concurrent misses for the same key within this process share one load. The
+// first caller becomes the leader and loads, the others wait for the JSON it publishes.
+#[derive(Default)]
+pub struct Flights {
+    inflight: Mutex<HashMap<String, watch::Receiver<Option<Arc<str>>>>>,
+}
+
+pub enum Flight<'a> {
+    Leader(FlightLeader<'a>),
+    Follower(watch::Receiver<Option<Arc<str>>>),
+}
+
+// Removes the flight when dropped, so followers of a leader that failed or was cancelled stop
+// waiting instead of hanging.
+pub struct FlightLeader<'a> {
+    flights: &'a Flights,
+    key: String,
+    sender: watch::Sender<Option<Arc<str>>>,
+}
+
+impl Flights {
+    pub fn join(&self, key: &str) -> Flight<'_> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(receiver) = inflight.get(key) {
+            return Flight::Follower(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        inflight.insert(key.to_string(), receiver);
+        Flight::Leader(FlightLeader {
+            flights: self,
+            key: key.to_string(),
+            sender,
+        })
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inflight.lock().unwrap().len()
+    }
+}
+
+impl FlightLeader<'_> {
+    pub fn complete(self, json: Arc<str>) {
+        self.sender.send_replace(Some(json));
+    }
+}
+
+impl Drop for FlightLeader<'_> {
+    fn drop(&mut self) {
+        self.flights.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+// The leader's result, or `None` when it gave up without one
+pub async fn wait(mut receiver: watch::Receiver<Option<Arc<str>>>) -> Option<Arc<str>> {
+    let result = receiver.wait_for(Option::is_some).await.ok()?;
+    result.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_followers_get_the_leaders_result() {
+        let flights = Flights::default();
+        let Flight::Leader(leader) = flights.join("car:1") else {
+            panic!("first caller must lead");
+        };
+        let Flight::Follower(receiver) = flights.join("car:1") else {
+            panic!("second caller must follow");
+        };
+        leader.complete(Arc::from("{}"));
+        assert_eq!(wait(receiver).await.as_deref(), Some("{}"));
+        assert_eq!(flights.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_followers_stop_waiting_when_the_leader_gives_up() {
+        let flights = Flights::default();
+        let leader = flights.join("car:1");
+        let Flight::Follower(receiver) = flights.join("car:1") else {
+            panic!("second caller must follow");
+        };
+        drop(leader);
+        assert_eq!(wait(receiver).await, None);
+        assert!(matches!(flights.join("car:1"), Flight::Leader(_)));
+    }
+}
@@ -0,0 +1,219 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// In-process tier in front of Redis. Entries are kept as the same JSON that is stored in Redis,
+// bounded by `capacity` (least recently used go first) and by a TTL that is kept short, since
+// other instances can only tell this one about changes through pub/sub.
+pub struct LocalCache {
+    // `None` when the tier is disabled with a capacity of 0
+    entries: Option<Mutex<LruCache<String, LocalEntry>>>,
+    ttl: Duration,
+    // Serving from this tier is only safe while invalidations from other instances reach it
+    live: AtomicBool,
+    // Bumped by every invalidation, see `put`
+    generation: AtomicU64,
+}
+
+struct LocalEntry {
+    json: Arc<str>,
+    tags: Vec<String>,
+    expires_at: Instant,
+}
+
+impl LocalCache {
+    pub fn new(capacity: usize, ttl: Duration, live: bool) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+            ttl,
+            live: AtomicBool::new(live),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<str>> {
+        if !self.live.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.json.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // `ttl` is the entry's TTL in Redis, the local copy never outlives it. `since` is the
+    // generation read before the value was loaded: if anything was invalidated in the meantime
+    // the value may predate that write, so it is not kept.
+    pub fn put(&self, key: &str, json: Arc<str>, tags: &[&str], ttl: Duration, since: u64) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        if !self.live.load(Ordering::Relaxed) {
+            return;
+        }
+        let entry = LocalEntry {
+            json,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            expires_at: Instant::now() + ttl.min(self.ttl),
+        };
+        let mut entries = entries.lock().unwrap();
+        if self.generation() == since {
+            entries.put(key.to_string(), entry);
+        }
+    }
+
+    // The removals return how many entries they dropped
+    pub fn remove(&self, key: &str) -> usize {
+        self.remove_where(|k, _| k == key)
+    }
+
+    pub fn remove_tag(&self, tag: &str) -> usize {
+        self.remove_where(|_, entry| entry.tags.iter().any(|t| t == tag))
+    }
+
+    pub fn remove_prefix(&self, prefix: &str) -> usize {
+        self.remove_where(|key, _| key.starts_with(prefix))
+    }
+
+    fn remove_where(&self, matches: impl Fn(&str, &LocalEntry) -> bool) -> usize {
+        let Some(mut entries) = self.invalidate() else {
+            return 0;
+        };
+        let keys: Vec<String> = entries
+            .iter()
+            .filter(|(key, entry)| matches(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            entries.pop(key);
+        }
+        keys.len()
+    }
+
+    // Like `get`, but leaves the entry's place in the LRU order alone
+    pub fn contains(&self, key: &str) -> bool {
+        if !self.live.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Some(entries) = &self.entries else {
+            return false;
+        };
+        let entries = entries.lock().unwrap();
+        entries
+            .peek(key)
+            .is_some_and(|entry| entry.expires_at > Instant::now())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .as_ref()
+            .map_or(0, |entries| entries.lock().unwrap().len())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries
+            .as_ref()
+            .map_or(0, |entries| entries.lock().unwrap().cap().get())
+    }
+
+    // Always starts over empty: invalidations sent while it was not listening are lost
+    pub fn set_live(&self, live: bool) {
+        if let Some(mut entries) = self.invalidate() {
+            entries.clear();
+        }
+        self.live.store(live, Ordering::Relaxed);
+    }
+
+    // Bumps the generation while holding the lock, so no `put` can slip in between the check
+    // and the removal
+    fn invalidate(&self) -> Option<MutexGuard<'_, LruCache<String, LocalEntry>>> {
+        let entries = self.entries.as_ref().map(|entries| entries.lock().unwrap());
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: &str) -> Arc<str> {
+        Arc::from(value)
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = LocalCache::new(2, Duration::from_secs(60), true);
+        let ttl = Duration::from_secs(60);
+        cache.put("car:1", json("1"), &["car"], ttl, 0);
+        cache.put("car:2", json("2"), &["car"], ttl, 0);
+        cache.get("car:1");
+        cache.put("car:3", json("3"), &["car"], ttl, 0);
+        assert!(cache.get("car:1").is_some());
+        assert!(cache.get("car:2").is_none());
+        assert!(cache.get("car:3").is_some());
+    }
+
+    #[test]
+    fn test_expires_entries() {
+        let cache = LocalCache::new(10, Duration::ZERO, true);
+        cache.put("car:1", json("1"), &["car"], Duration::from_secs(60), 0);
+        assert!(cache.get("car:1").is_none());
+    }
+
+    #[test]
+    fn test_removes_by_tag() {
+        let cache = LocalCache::new(10, Duration::from_secs(60), true);
+        let ttl = Duration::from_secs(60);
+        cache.put("car:1", json("1"), &["car"], ttl, 0);
+        cache.put("part:1", json("1"), &["part"], ttl, 0);
+        cache.remove_tag("car");
+        assert!(cache.get("car:1").is_none());
+        assert!(cache.get("part:1").is_some());
+    }
+
+    #[test]
+    fn test_removes_by_prefix() {
+        let cache = LocalCache::new(10, Duration::from_secs(60), true);
+        let ttl = Duration::from_secs(60);
+        cache.put("car:1", json("1"), &["car"], ttl, 0);
+        cache.put("car:list:ab", json("[]"), &["car"], ttl, 0);
+        cache.put("part:1", json("1"), &["part"], ttl, 0);
+        assert_eq!(cache.remove_prefix("car:list"), 1);
+        assert!(cache.contains("car:1"));
+        assert!(!cache.contains("car:list:ab"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_skips_values_loaded_before_an_invalidation() {
+        let cache = LocalCache::new(10, Duration::from_secs(60), true);
+        let ttl = Duration::from_secs(60);
+        let since = cache.generation();
+        cache.remove("car:2");
+        cache.put("car:1", json("1"), &["car"], ttl, since);
+        assert!(cache.get("car:1").is_none());
+        cache.put("car:1", json("1"), &["car"], ttl, cache.generation());
+        assert!(cache.get("car:1").is_some());
+    }
+
+    #[test]
+    fn test_serves_nothing_while_not_live() {
+        let cache = LocalCache::new(10, Duration::from_secs(60), false);
+        cache.put("car:1", json("1"), &["car"], Duration::from_secs(60), 0);
+        assert!(cache.get("car:1").is_none());
+        cache.set_live(true);
+        assert!(cache.get("car:1").is_none());
+    }
+}
@@ -1,19 +1,904 @@
 use crate::config::Config;
+use crate::db::postgres;
 use crate::db::redis::{Redis, redis_connect};
+use crate::models::cache::{CacheEntry, CacheStats, RedisEntry, TierStats};
+use anyhow::Result;
 use axum::Extension;
-use std::sync::Arc;
+use breaker::CircuitBreaker;
+use chrono::{DateTime, Utc};
+use flight::{Flight, Flights};
+use futures::StreamExt;
+use local::LocalCache;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, ExistenceCheck, RedisResult, Script, SetExpiry, SetOptions};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+mod breaker;
+mod flight;
+mod local;
+
+// Instances tell each other to drop local copies through this channel, with `key:<key>` or
+// `tag:<tag>` messages
+const INVALIDATION_CHANNEL: &str = "cache:invalidate";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(25);
+const SCAN_BATCH: usize = 500;
+// Must outlive any load, a counter that expires mid-load could let its result through
+const GENERATION_TTL: i64 = 3600;
+
+// Caches an entry and adds it to its tag sets, unless one of the generation counters moved
+// since they were read before loading it.
+// KEYS: the entry, n generation counters, the tag sets
+// ARGV: json, expiry in seconds, n, the n expected generations
+static STORE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local n = tonumber(ARGV[3])
+        for i = 1, n do
+            if tonumber(redis.call('GET', KEYS[1 + i]) or '0') ~= tonumber(ARGV[3 + i]) then
+                return 0
+            end
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+        for i = n + 2, #KEYS do
+            redis.call('SADD', KEYS[i], KEYS[1])
+            redis.call('EXPIRE', KEYS[i], ARGV[2])
+        end
+        return 1
+        ",
+    )
+});
+
+// Deletes a load lock only while it still holds the token of the caller releasing it
+static UNLOCK: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) end return 0",
+    )
+});
 
 pub type CacheExt = Extension<Arc<CacheImpl>>;
 
+// Redis being down only fails startup when the cache is required, see `Config::cache_optional`
 pub async fn create_cache(config: &Config) -> CacheImpl {
-    let redis_pool = Arc::new(redis_connect(config).await);
-    CacheImpl::new(redis_pool)
+    let redis_pool = match redis_connect(config).await {
+        Ok(pool) => Some(Arc::new(pool)),
+        Err(err) if config.cache_optional => {
+            warn!(%err, "Invalid cache url, running without a cache");
+            None
+        }
+        Err(err) => panic!("Error connecting to cache: {err}"),
+    };
+    let cache = CacheImpl::new(redis_pool, CacheSettings::from(config));
+    if cache.redis_pool.is_some() {
+        match redis::Client::open(config.cache_url.as_str()) {
+            Ok(client) => spawn_invalidation_listener(client, cache.local.clone()),
+            Err(err) => warn!(%err, "Cannot listen for cache invalidations"),
+        }
+    }
+    if let Err(err) = cache.ping().await {
+        if !config.cache_optional {
+            panic!("Error connecting to cache: {err}");
+        }
+        warn!(%err, "Cache is unreachable, serving from the database until it is back");
+    }
+    cache
+}
+
+// Kinds of records kept in the cache. Each has its own key prefix and TTL, and every entry is
+// tagged with its entity so all of them can be dropped at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Entity {
+    Car,
+    Part,
+    User,
+}
+
+impl Entity {
+    pub const ALL: [Entity; 3] = [Entity::Car, Entity::Part, Entity::User];
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Entity::Car => "car",
+            Entity::Part => "part",
+            Entity::User => "user",
+        }
+    }
+
+    // `car:42`
+    pub fn key(&self, id: impl Display) -> String {
+        format!("{}:{}", self.prefix(), id)
+    }
+
+    // `car:list:<sha256>` for one page of a list. The query should be normalized first so
+    // that equivalent requests share an entry.
+    pub fn list_key(&self, query: &impl Serialize) -> Result<String> {
+        let digest = Sha256::digest(serde_json::to_vec(query)?);
+        Ok(self.key(format!("list:{:x}", digest)))
+    }
+
+    // Carried by every cached list of this entity, dropped on any write to it
+    pub fn list_tag(&self) -> String {
+        self.key("list")
+    }
+}
+
+// Seconds an entry lives in the cache, per entity
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTtl {
+    pub car: u64,
+    pub part: u64,
+    pub user: u64,
+}
+
+impl CacheTtl {
+    fn of(&self, entity: Entity) -> u64 {
+        match entity {
+            Entity::Car => self.car,
+            Entity::Part => self.part,
+            Entity::User => self.user,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSettings {
+    pub ttl: CacheTtl,
+    // per Redis call, including waiting for a connection
+    pub timeout: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+    pub local_capacity: usize,
+    pub local_ttl: Duration,
+    // stale-while-revalidate window, zero to turn it off
+    pub stale: Duration,
+    // how long a load lock is held at most
+    pub lock: Duration,
+}
+
+impl From<&Config> for CacheSettings {
+    fn from(config: &Config) -> Self {
+        Self {
+            ttl: CacheTtl {
+                car: config.cache_ttl_car_secs,
+                part: config.cache_ttl_part_secs,
+                user: config.cache_ttl_user_secs,
+            },
+            timeout: Duration::from_millis(config.cache_timeout_ms),
+            breaker_threshold: config.cache_breaker_threshold,
+            breaker_cooldown: Duration::from_secs(config.cache_breaker_cooldown_secs),
+            local_capacity: config.cache_local_capacity,
+            local_ttl: Duration::from_secs(config.cache_local_ttl_secs),
+            stale: Duration::from_secs(config.cache_stale_secs),
+            lock: Duration::from_millis(config.cache_lock_ms),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TierCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TierCounters {
+    // Both return the updated count
+    fn hit(&self) -> u64 {
+        self.hits.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn miss(&self) -> u64 {
+        self.misses.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn stats(&self) -> TierStats {
+        TierStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
 }
+
+// Two tiers: a small in-process LRU (L1) in front of Redis (L2).
+//
+// Redis is an optimisation, never a dependency: every call is bounded by a timeout, and when
+// Redis is slow, down or not configured at all (`redis_pool` is `None`) reads go straight to the
+// loader and invalidations are skipped. Entries then expire through their TTL.
 pub struct CacheImpl {
-    pub redis_pool: Redis,
+    redis_pool: Option<Redis>,
+    local: Arc<LocalCache>,
+    l1: TierCounters,
+    l2: TierCounters,
+    ttl: CacheTtl,
+    stale: Duration,
+    lock: Duration,
+    flights: Flights,
+    timeout: Duration,
+    breaker: CircuitBreaker,
+    // Redis calls that failed or timed out
+    failures: AtomicU64,
+    // Redis calls skipped because the breaker was open
+    bypassed: AtomicU64,
 }
+
 impl CacheImpl {
-    pub fn new(pool: Redis) -> Self {
-        Self { redis_pool: pool }
+    pub fn new(pool: Option<Redis>, settings: CacheSettings) -> Self {
+        // Without Redis there are no other instances to hear from, so the local tier is safe to
+        // use right away. With Redis it waits for the invalidation listener.
+        let local = LocalCache::new(settings.local_capacity, settings.local_ttl, pool.is_none());
+        Self {
+            redis_pool: pool,
+            local: Arc::new(local),
+            l1: TierCounters::default(),
+            l2: TierCounters::default(),
+            ttl: settings.ttl,
+            stale: settings.stale,
+            lock: settings.lock,
+            flights: Flights::default(),
+            timeout: settings.timeout,
+            breaker: CircuitBreaker::new(settings.breaker_threshold, settings.breaker_cooldown),
+            failures: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+        }
+    }
+
+    // Cache-aside read: returns the cached value for `key`, or calls `load` and caches what it
+    // returns. Errors from `load` are passed through and nothing is cached.
+    //
+    // A miss is loaded once however many callers ask at the same time: within this process
+    // through single-flight, across instances through a short lock in Redis. When a stale window
+    // is configured an expired entry keeps being served while one caller reloads it.
+    //
+    // A load that overlaps an invalidation may have read the row before the write, so its
+    // result is returned to the caller but not cached (see `Generations`). Loads read from the
+    // primary: one starting after an invalidation must not see a replica that has not caught
+    // up with the write, or the old row would be cached again.
+    pub async fn get_or_load<T, F, Fut>(
+        &self,
+        entity: Entity,
+        key: &str,
+        tags: &[String],
+        load: F,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let load = || postgres::on_primary(load());
+        let tags: Vec<&str> = tags
+            .iter()
+            .map(String::as_str)
+            .chain([entity.prefix()])
+            .collect();
+        let ttl = self.ttl.of(entity);
+        let generation = self.local.generation();
+
+        if let Some(value) = self.local.get(key).and_then(|json| decode(key, &json)) {
+            debug!(hits = self.l1.hit(), "L1 cache hit for {}", key);
+            return Ok(value);
+        }
+        self.l1.miss();
+
+        let stale = match self.read::<T>(key).await {
+            Some(Cached::Fresh(value)) => {
+                info!(hits = self.l2.hit(), "Cache hit for {}", key);
+                self.put_local(key, &value, &tags, ttl, generation)?;
+                return Ok(value);
+            }
+            Some(Cached::Stale(value)) => Some(value),
+            None => None,
+        };
+        info!(
+            misses = self.l2.miss(),
+            "Cache miss for {}, loading...", key
+        );
+
+        // Callers arriving after an invalidation must not join a load that started before it
+        let leader = match self.flights.join(&format!("{}@{}", generation, key)) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(receiver) => {
+                if let Some(value) = stale {
+                    return Ok(value);
+                }
+                if let Some(value) = flight::wait(receiver)
+                    .await
+                    .and_then(|json| decode(key, &json))
+                {
+                    return Ok(value);
+                }
+                // the leader failed, so this caller reports its own error
+                return load().await;
+            }
+        };
+
+        let generations = Generations {
+            local: generation,
+            redis: self.generations(key, &tags).await,
+        };
+        let value = match self.lock(key).await {
+            Lock::Acquired(token) => {
+                let loaded = load().await;
+                if let Ok(value) = &loaded {
+                    self.store(key, value, &tags, ttl, &generations).await?;
+                }
+                self.unlock(key, token).await;
+                loaded?
+            }
+            // another instance is loading it
+            Lock::Held => match stale {
+                Some(value) => value,
+                None => match self.wait_for_fresh(key).await {
+                    Some(value) => {
+                        self.put_local(key, &value, &tags, ttl, generation)?;
+                        value
+                    }
+                    None => {
+                        let value = load().await?;
+                        self.store(key, &value, &tags, ttl, &generations).await?;
+                        value
+                    }
+                },
+            },
+            Lock::Unavailable => {
+                let value = load().await?;
+                self.store(key, &value, &tags, ttl, &generations).await?;
+                value
+            }
+        };
+        leader.complete(serde_json::to_string(&value)?.into());
+        Ok(value)
+    }
+
+    // Call after the write has committed: anything loaded before then is either dropped here or
+    // refused when it is stored.
+    //
+    // The invalidations return how many entries they removed from Redis, or from the local tier
+    // when Redis is unavailable.
+    pub async fn invalidate(&self, key: &str) -> u64 {
+        let local = self.local.remove(key) as u64;
+        let generation_key = generation_key(key);
+        let mut pipe = redis::pipe();
+        // bumped first: a store that lands before the bump is deleted, any later one refused
+        pipe.incr(&generation_key, 1)
+            .ignore()
+            .expire(&generation_key, GENERATION_TTL)
+            .ignore()
+            .del(key)
+            .publish(INVALIDATION_CHANNEL, format!("key:{}", key))
+            .ignore();
+        let deleted: Option<(u64,)> = self.call(async |conn| pipe.query_async(conn).await).await;
+        deleted.map_or(local, |(deleted,)| deleted)
+    }
+
+    // Drops every entry cached with `tag`, e.g. `Entity::Car.prefix()` for all cars
+    pub async fn invalidate_tag(&self, tag: &str) -> u64 {
+        let local = self.local.remove_tag(tag) as u64;
+        let tag_key = tag_key(tag);
+        let generation_key = generation_key(&tag_key);
+        let deleted = self
+            .call(async |conn| {
+                // bumped before reading the members, see `invalidate`
+                redis::pipe()
+                    .incr(&generation_key, 1)
+                    .expire(&generation_key, GENERATION_TTL)
+                    .query_async::<()>(conn)
+                    .await?;
+                let keys: Vec<String> = conn.smembers(&tag_key).await?;
+                let mut pipe = redis::pipe();
+                pipe.del(&tag_key)
+                    .ignore()
+                    .publish(INVALIDATION_CHANNEL, format!("tag:{}", tag))
+                    .ignore();
+                if !keys.is_empty() {
+                    pipe.del(keys);
+                }
+                let deleted: Vec<u64> = pipe.query_async(conn).await?;
+                Ok(deleted.into_iter().sum())
+            })
+            .await;
+        deleted.unwrap_or(local)
+    }
+
+    // Drops every entry whose key starts with `prefix`. Meant for operators: unlike the other
+    // invalidations it walks the whole keyspace.
+    pub async fn invalidate_prefix(&self, prefix: &str) -> u64 {
+        let local = self.local.remove_prefix(prefix) as u64;
+        let pattern = format!("{}*", escape_glob(prefix));
+        let mut deleted = 0;
+        let mut cursor = 0;
+        loop {
+            // one call per batch, so that a large keyspace does not run into the timeout
+            let batch: Option<(u64, Vec<String>)> = self
+                .call(async |conn| {
+                    redis::cmd("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(&pattern)
+                        .arg("COUNT")
+                        .arg(SCAN_BATCH)
+                        .query_async(conn)
+                        .await
+                })
+                .await;
+            let Some((next, keys)) = batch else {
+                return local;
+            };
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    let generation_key = generation_key(key);
+                    pipe.incr(&generation_key, 1)
+                        .ignore()
+                        .expire(&generation_key, GENERATION_TTL)
+                        .ignore();
+                }
+                pipe.del(&keys);
+                let batch: Option<(u64,)> =
+                    self.call(async |conn| pipe.query_async(conn).await).await;
+                let Some((batch,)) = batch else {
+                    return local;
+                };
+                deleted += batch;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        let message = format!("prefix:{}", prefix);
+        self.call(async |conn| conn.publish(INVALIDATION_CHANNEL, message).await)
+            .await
+            .map_or(local, |()| deleted)
+    }
+
+    // What both tiers currently hold for `key`
+    pub async fn lookup(&self, key: &str) -> CacheEntry {
+        let cached: Option<(Option<String>, i64)> = self
+            .call(async |conn| redis::pipe().get(key).pttl(key).query_async(conn).await)
+            .await;
+        let redis = cached.and_then(|(json, expires_in_ms)| {
+            let value: Value = decode(key, &json?)?;
+            let fresh_until = value["fresh_until"]
+                .as_i64()
+                .and_then(DateTime::<Utc>::from_timestamp_millis);
+            Some(RedisEntry {
+                value: value.get("value").cloned().unwrap_or(value),
+                fresh_until,
+                expires_in_ms,
+            })
+        });
+        CacheEntry {
+            key: key.to_string(),
+            local: self.local.contains(key),
+            redis,
+        }
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let redis_keys = self
+            .call(async |conn| redis::cmd("DBSIZE").query_async(conn).await)
+            .await;
+        CacheStats {
+            redis_configured: self.redis_pool.is_some(),
+            redis_keys,
+            breaker_open: !self.breaker.allow(),
+            failures: self.failures.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+            local: self.l1.stats(),
+            local_entries: self.local.len(),
+            local_capacity: self.local.capacity(),
+            redis: self.l2.stats(),
+        }
+    }
+
+    async fn read<T: DeserializeOwned>(&self, key: &str) -> Option<Cached<T>> {
+        let json: Option<String> = self.call(async |conn| conn.get(key).await).await?;
+        let envelope: Envelope<T> = decode(key, &json?)?;
+        if envelope.fresh_until > Utc::now().timestamp_millis() {
+            Some(Cached::Fresh(envelope.value))
+        } else if self.stale.is_zero() {
+            None
+        } else {
+            Some(Cached::Stale(envelope.value))
+        }
+    }
+
+    // Current invalidation counters of the key and its tags, `None` when Redis is unavailable
+    async fn generations(&self, key: &str, tags: &[&str]) -> Option<Vec<u64>> {
+        let keys = generation_keys(key, tags);
+        let generations: Vec<Option<u64>> = self.call(async |conn| conn.mget(&keys).await).await?;
+        Some(
+            generations
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect(),
+        )
+    }
+
+    // Writes to both tiers, each only if nothing was invalidated since `generations` were read.
+    // Redis keeps the entry for the stale window past its TTL.
+    async fn store<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        tags: &[&str],
+        ttl: u64,
+        generations: &Generations,
+    ) -> Result<()> {
+        if let Some(expected) = &generations.redis {
+            let envelope = Envelope {
+                fresh_until: Utc::now().timestamp_millis() + (ttl * 1000) as i64,
+                value,
+            };
+            let json = serde_json::to_string(&envelope)?;
+            let mut invocation = STORE.prepare_invoke();
+            invocation
+                .key(key)
+                .key(generation_keys(key, tags))
+                .key(tags.iter().map(|tag| tag_key(tag)).collect::<Vec<_>>())
+                .arg(json)
+                .arg(ttl + self.stale.as_secs())
+                .arg(expected.len())
+                .arg(expected);
+            self.call(async |conn| invocation.invoke_async::<()>(conn).await)
+                .await;
+        }
+        self.put_local(key, value, tags, ttl, generations.local)
+    }
+
+    fn put_local<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        tags: &[&str],
+        ttl: u64,
+        since: u64,
+    ) -> Result<()> {
+        let json = serde_json::to_string(value)?;
+        self.local
+            .put(key, json.into(), tags, Duration::from_secs(ttl), since);
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str) -> Lock {
+        let token = rand::random::<u64>().to_string();
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(self.lock.as_millis() as u64));
+        let acquired: Option<Option<String>> = self
+            .call(async |conn| conn.set_options(lock_key(key), &token, options).await)
+            .await;
+        match acquired {
+            Some(Some(_)) => Lock::Acquired(token),
+            Some(None) => Lock::Held,
+            None => Lock::Unavailable,
+        }
+    }
+
+    // Only releases the lock if it is still ours, it may have expired and been taken over
+    async fn unlock(&self, key: &str, token: String) {
+        self.call(async |conn| {
+            UNLOCK
+                .key(lock_key(key))
+                .arg(token)
+                .invoke_async::<()>(conn)
+                .await
+        })
+        .await;
+    }
+
+    // Polls for the value loaded by the instance holding the lock, `None` once the lock would
+    // have expired
+    async fn wait_for_fresh<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let deadline = Instant::now() + self.lock;
+        while Instant::now() < deadline {
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            if let Some(Cached::Fresh(value)) = self.read(key).await {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let Some(pool) = &self.redis_pool else {
+            return Ok(());
+        };
+        let mut redis_conn = tokio::time::timeout(self.timeout, pool.get()).await??;
+        redis::cmd("PING")
+            .query_async::<()>(&mut *redis_conn)
+            .await?;
+        Ok(())
+    }
+
+    // Runs one Redis operation under the timeout and circuit breaker. `None` means the cache is
+    // unavailable and the caller should carry on without it; the failure is already logged.
+    // Public so the rate limiter shares the pool, timeout and breaker.
+    pub async fn call<T>(
+        &self,
+        op: impl AsyncFnOnce(&mut MultiplexedConnection) -> RedisResult<T>,
+    ) -> Option<T> {
+        let pool = self.redis_pool.as_ref()?;
+        if !self.breaker.allow() {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let result = tokio::time::timeout(self.timeout, async {
+            let mut redis_conn = pool.get().await?;
+            Ok::<_, anyhow::Error>(op(&mut redis_conn).await?)
+        })
+        .await;
+        match result {
+            Ok(Ok(value)) => {
+                self.breaker.record_success();
+                Some(value)
+            }
+            Ok(Err(err)) => {
+                self.record_failure(err);
+                None
+            }
+            Err(elapsed) => {
+                self.record_failure(elapsed);
+                None
+            }
+        }
+    }
+
+    fn record_failure(&self, err: impl Display) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(%err, failures, "Cache unavailable, falling back to the database");
+        if self.breaker.record_failure() {
+            warn!("Too many cache failures, bypassing the cache for a while");
+        }
+    }
+}
+
+// What Redis holds for a key. The entry outlives `fresh_until` by the stale window.
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    // unix millis
+    fresh_until: i64,
+    value: T,
+}
+
+// Invalidation counters read before a load. Every invalidation bumps the local one, and the
+// Redis ones of the key or tag it targets.
+struct Generations {
+    local: u64,
+    // `None` when they could not be read, the value is then not written to Redis
+    redis: Option<Vec<u64>>,
+}
+
+enum Cached<T> {
+    Fresh(T),
+    Stale(T),
+}
+
+enum Lock {
+    Acquired(String),
+    // another instance holds it
+    Held,
+    // Redis could not be asked
+    Unavailable,
+}
+
+fn lock_key(key: &str) -> String {
+    format!("lock:{}", key)
+}
+
+fn generation_key(key: &str) -> String {
+    format!("gen:{}", key)
+}
+
+// Counters of the key followed by those of its tags, in the order `STORE` checks them
+fn generation_keys(key: &str, tags: &[&str]) -> Vec<String> {
+    [generation_key(key)]
+        .into_iter()
+        .chain(tags.iter().map(|tag| generation_key(&tag_key(tag))))
+        .collect()
+}
+
+// SCAN patterns are globs, the prefix itself is matched literally
+fn escape_glob(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Set holding the keys of all entries with this tag
+fn tag_key(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+// A cached entry that no longer decodes (e.g. written by an older version of the model) is
+// treated as a miss, so it gets reloaded and overwritten.
+fn decode<T: DeserializeOwned>(key: &str, json: &str) -> Option<T> {
+    serde_json::from_str(json)
+        .inspect_err(|err| warn!(%err, "Ignoring undecodable cache entry {}", key))
+        .ok()
+}
+
+// Keeps the local tier coherent with other instances. It only serves entries while subscribed;
+// after a disconnect it starts over empty, since messages may have been missed.
+fn spawn_invalidation_listener(client: redis::Client, local: Arc<LocalCache>) {
+    tokio::spawn(async move {
+        loop {
+            match listen_for_invalidations(&client, &local).await {
+                Ok(()) => warn!("Cache invalidation channel closed, local cache paused"),
+                Err(err) => debug!(%err, "Cache invalidation channel unavailable"),
+            }
+            local.set_live(false);
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+async fn listen_for_invalidations(client: &redis::Client, local: &LocalCache) -> Result<()> {
+    let mut pubsub = tokio::time::timeout(RECONNECT_DELAY, client.get_async_pubsub()).await??;
+    pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+    local.set_live(true);
+    info!("Listening for cache invalidations");
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match payload.split_once(':') {
+            Some(("key", key)) => local.remove(key),
+            Some(("tag", tag)) => local.remove_tag(tag),
+            Some(("prefix", prefix)) => local.remove_prefix(prefix),
+            _ => {
+                warn!("Unknown cache invalidation message {}", payload);
+                0
+            }
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixture::cache::local_cache_fixture;
+    use anyhow::bail;
+    use once_cell::sync::Lazy;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::oneshot;
+
+    static INIT: Lazy<()> = Lazy::new(|| {
+        dotenv::from_filename(".env.test").ok();
+    });
+
+    #[test]
+    fn test_keys_are_prefixed_by_entity() {
+        assert_eq!(Entity::Car.key(42), "car:42");
+        assert_eq!(Entity::User.key("alice"), "user:alice");
+        assert_eq!(tag_key(Entity::Part.prefix()), "tag:part");
+    }
+
+    #[test]
+    fn test_prefixes_are_matched_literally() {
+        assert_eq!(escape_glob("car:list"), "car:list");
+        assert_eq!(escape_glob("car:*[1]?"), "car:\\*\\[1\\]\\?");
+    }
+
+    #[test]
+    fn test_list_keys_hash_the_query() {
+        let key = Entity::Car.list_key(&("Tesla", 1)).unwrap();
+        assert!(key.starts_with("car:list:"));
+        assert_eq!(key, Entity::Car.list_key(&("Tesla", 1)).unwrap());
+        assert_ne!(key, Entity::Car.list_key(&("Tesla", 2)).unwrap());
+        assert_ne!(key, Entity::Part.list_key(&("Tesla", 1)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_load_once() {
+        let cache = crate::tests::fixture::cache::cache_fixture();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(7)
+        };
+        let key = Entity::Car.key(1);
+        let reads = (0..10).map(|_| cache.get_or_load(Entity::Car, &key, &[], load));
+        for value in futures::future::join_all(reads).await {
+            assert_eq!(value.unwrap(), 7);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_callers_load_themselves_when_the_load_fails() {
+        let cache = crate::tests::fixture::cache::cache_fixture();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err::<i32, _>(anyhow::anyhow!("database is down"))
+        };
+        let key = Entity::Car.key(1);
+        let reads = (0..3).map(|_| cache.get_or_load(Entity::Car, &key, &[], load));
+        for value in futures::future::join_all(reads).await {
+            assert!(value.is_err());
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    // A load that read the row before an update committed answers with what it read, but must
+    // not leave that copy in the cache
+    #[tokio::test]
+    async fn test_no_stale_read_survives_an_invalidation() {
+        let cache = local_cache_fixture();
+        let db = Mutex::new("old");
+        let (loaded_tx, loaded_rx) = oneshot::channel();
+        let (resume_tx, resume_rx) = oneshot::channel();
+        let key = Entity::Car.key(1);
+
+        let stalled = cache.get_or_load(Entity::Car, &key, &[], || async {
+            let value = db.lock().unwrap().to_string();
+            loaded_tx.send(()).unwrap();
+            resume_rx.await.unwrap();
+            Ok(value)
+        });
+        let update = async {
+            loaded_rx.await.unwrap();
+            *db.lock().unwrap() = "new";
+            cache.invalidate(&key).await;
+            // does not wait for the load that started before the update
+            let load = || async { Ok(db.lock().unwrap().to_string()) };
+            let value = cache.get_or_load(Entity::Car, &key, &[], load).await;
+            assert_eq!(value.unwrap(), "new");
+            resume_tx.send(()).unwrap();
+        };
+        let (stalled, ()) = tokio::join!(stalled, update);
+        assert_eq!(stalled.unwrap(), "old");
+
+        let cached: Result<String> = cache
+            .get_or_load(Entity::Car, &key, &[], || async { bail!("not cached") })
+            .await;
+        assert_eq!(cached.unwrap(), "new");
+    }
+
+    // Needs the redis server from `compose-tests.yaml`
+    #[tokio::test]
+    #[ignore]
+    async fn test_get_or_load_caches_until_invalidated() {
+        Lazy::force(&INIT);
+        let cache = create_cache(&Config::init()).await;
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok(7)
+        };
+        let key = Entity::Car.key("test");
+        cache.invalidate(&key).await;
+
+        for _ in 0..2 {
+            let value = cache.get_or_load(Entity::Car, &key, &[], load).await;
+            assert_eq!(value.unwrap(), 7);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        cache.invalidate_tag(Entity::Car.prefix()).await;
+        let value = cache.get_or_load(Entity::Car, &key, &[], load).await;
+        assert_eq!(value.unwrap(), 7);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
     }
 }
@@ -2,16 +2,191 @@
 pub struct Config {
     pub database_url: String,
     pub cache_url: String,
+    // start and keep serving from the database when Redis is unreachable
+    pub cache_optional: bool,
+    // how long a single Redis call may take before it is treated as a failure
+    pub cache_timeout_ms: u64,
+    // consecutive failures after which Redis is skipped for `cache_breaker_cooldown_secs`
+    pub cache_breaker_threshold: u32,
+    pub cache_breaker_cooldown_secs: u64,
+    // read-only replicas for list and lookup queries, empty to read from the primary
+    pub replica_database_urls: Vec<String>,
+    pub replica_check_interval_secs: u64,
+    // how long a cached record lives, per entity
+    pub cache_ttl_car_secs: u64,
+    pub cache_ttl_part_secs: u64,
+    pub cache_ttl_user_secs: u64,
+    // in-process tier in front of Redis, a capacity of 0 turns it off
+    pub cache_local_capacity: usize,
+    pub cache_local_ttl_secs: u64,
+    // how long an expired entry may still be served while one caller reloads it, 0 to never
+    pub cache_stale_secs: u64,
+    // how long other instances wait for the one holding a key's load lock
+    pub cache_lock_ms: u64,
+    // `Cache-Control` of view and list responses; the default has clients revalidate every time
+    pub http_cache_control: String,
+    // comma separated `route=requests/seconds`, see `rate_limit::Policy`; empty to not limit
+    pub rate_limit_policies: String,
+    // take the client address from `X-Forwarded-For`, only safe behind a proxy that sets it
+    pub rate_limit_trust_proxy: bool,
+    // comma separated API keys counted in buckets of their own, other keys are ignored
+    pub rate_limit_api_keys: String,
+    // how long the first response to an `Idempotency-Key` is replayed to retries
+    pub idempotency_ttl_secs: u64,
+    // where uploaded files are written, and the largest one accepted
+    pub upload_dir: String,
+    pub upload_max_bytes: u64,
+    // files accepted in one multipart upload
+    pub upload_max_files: usize,
+    // bytes of uploads each user may store, 0 for no limit
+    pub upload_quota_bytes: u64,
+    // content types accepted by `/api/files/upload` and by `/api/upload/{file_name}`, comma
+    // separated `type/subtype` or `type/*`; empty to accept anything
+    pub upload_allowed_types: String,
+    pub upload_body_allowed_types: String,
+    // how long an unfinished resumable upload is kept after its last chunk
+    pub resumable_upload_ttl_secs: u64,
+    // longest edge in pixels of each thumbnail made of uploaded images, none when empty
+    pub thumbnail_sizes: Vec<u32>,
+    // where the bytes of uploads are kept: `local` (in `upload_dir`), `memory` or `s3`
+    pub storage_backend: String,
+    // bucket for the `s3` backend; an endpoint is only needed for S3 compatible servers, and
+    // empty credentials are taken from the `AWS_*` variables
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_endpoint: String,
+    pub s3_access_key_id: String,
+    pub s3_secret_access_key: String,
+    // signs download links, `JWT_SECRET` when unset, and how long a link is valid
+    pub download_link_secret: String,
+    pub download_link_ttl_secs: u64,
+    // how long soft-deleted rows are kept before the purge job removes them
+    pub soft_delete_retention_days: i64,
+    pub purge_interval_secs: u64,
 }
 
 impl Config {
     pub fn init() -> Config {
         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
         let cache_url = std::env::var("CACHE_URL").expect("CACHE_URL must be set");
+        let cache_optional = env_or("CACHE_OPTIONAL", false);
+        let cache_timeout_ms = env_or("CACHE_TIMEOUT_MS", 200);
+        let cache_breaker_threshold = env_or("CACHE_BREAKER_THRESHOLD", 5);
+        let cache_breaker_cooldown_secs = env_or("CACHE_BREAKER_COOLDOWN_SECS", 30);
+        let replica_database_urls = std::env::var("REPLICA_DATABASE_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let replica_check_interval_secs = env_or("REPLICA_CHECK_INTERVAL_SECS", 5);
+        let cache_ttl_car_secs = env_or("CACHE_TTL_CAR_SECS", 60);
+        let cache_ttl_part_secs = env_or("CACHE_TTL_PART_SECS", 60);
+        let cache_ttl_user_secs = env_or("CACHE_TTL_USER_SECS", 60);
+        let cache_local_capacity = env_or("CACHE_LOCAL_CAPACITY", 1000);
+        let cache_local_ttl_secs = env_or("CACHE_LOCAL_TTL_SECS", 5);
+        let cache_stale_secs = env_or("CACHE_STALE_SECS", 0);
+        let cache_lock_ms = env_or("CACHE_LOCK_MS", 2000);
+        let http_cache_control = env_or("HTTP_CACHE_CONTROL", "private, no-cache".to_string());
+        let rate_limit_policies = env_or(
+            "RATE_LIMIT_POLICIES",
+            "*=600/60,POST /api/auth/authorize=10/60".to_string(),
+        );
+        let rate_limit_trust_proxy = env_or("RATE_LIMIT_TRUST_PROXY", false);
+        let rate_limit_api_keys = env_or("RATE_LIMIT_API_KEYS", String::new());
+        let idempotency_ttl_secs = env_or("IDEMPOTENCY_TTL_SECS", 86400);
+        let upload_dir = env_or("UPLOAD_DIR", "uploads".to_string());
+        let upload_max_bytes = env_or("UPLOAD_MAX_BYTES", 10 * 1024 * 1024);
+        let upload_max_files = env_or("UPLOAD_MAX_FILES", 10);
+        let upload_quota_bytes = env_or("UPLOAD_QUOTA_BYTES", 1024 * 1024 * 1024);
+        let upload_allowed_types = env_or("UPLOAD_ALLOWED_TYPES", String::new());
+        let upload_body_allowed_types = env_or("UPLOAD_BODY_ALLOWED_TYPES", String::new());
+        let resumable_upload_ttl_secs = env_or("RESUMABLE_UPLOAD_TTL_SECS", 86400);
+        let thumbnail_sizes = std::env::var("THUMBNAIL_SIZES")
+            .map(|sizes| {
+                sizes
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|size| !size.is_empty())
+                    .map(|size| match size.parse() {
+                        Ok(size) if size > 0 => size,
+                        _ => panic!("THUMBNAIL_SIZES is invalid: {size:?}"),
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![128, 512]);
+        let storage_backend = env_or("STORAGE_BACKEND", "local".to_string());
+        let s3_bucket = env_or("S3_BUCKET", "uploads".to_string());
+        let s3_region = env_or("S3_REGION", "us-east-1".to_string());
+        let s3_endpoint = env_or("S3_ENDPOINT", String::new());
+        let s3_access_key_id = env_or("S3_ACCESS_KEY_ID", String::new());
+        let s3_secret_access_key = env_or("S3_SECRET_ACCESS_KEY", String::new());
+        let download_link_secret = std::env::var("DOWNLOAD_LINK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .or_else(|| std::env::var("JWT_SECRET").ok())
+            .unwrap_or_default();
+        let download_link_ttl_secs = env_or("DOWNLOAD_LINK_TTL_SECS", 300);
+        let soft_delete_retention_days = env_or("SOFT_DELETE_RETENTION_DAYS", 30);
+        let purge_interval_secs = env_or("PURGE_INTERVAL_SECS", 3600);
 
         Config {
             database_url,
             cache_url,
+            cache_optional,
+            cache_timeout_ms,
+            cache_breaker_threshold,
+            cache_breaker_cooldown_secs,
+            replica_database_urls,
+            replica_check_interval_secs,
+            cache_ttl_car_secs,
+            cache_ttl_part_secs,
+            cache_ttl_user_secs,
+            cache_local_capacity,
+            cache_local_ttl_secs,
+            cache_stale_secs,
+            cache_lock_ms,
+            http_cache_control,
+            rate_limit_policies,
+            rate_limit_trust_proxy,
+            rate_limit_api_keys,
+            idempotency_ttl_secs,
+            upload_dir,
+            upload_max_bytes,
+            upload_max_files,
+            upload_quota_bytes,
+            upload_allowed_types,
+            upload_body_allowed_types,
+            resumable_upload_ttl_secs,
+            thumbnail_sizes,
+            storage_backend,
+            s3_bucket,
+            s3_region,
+            s3_endpoint,
+            s3_access_key_id,
+            s3_secret_access_key,
+            download_link_secret,
+            download_link_ttl_secs,
+            soft_delete_retention_days,
+            purge_interval_secs,
         }
     }
 }
+
+// Optional settings fall back to a default when unset, but a value that does not parse is a
+// configuration mistake worth failing loudly on.
+fn env_or<T>(name: &str, default: T) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Debug,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| panic!("{name} is invalid: {e:?}")),
+        Err(_) => default,
+    }
+}
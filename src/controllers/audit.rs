@@ -0,0 +1,74 @@
+use crate::error::{AppError, AppJson};
+use crate::models::audit::{AuditContext, AuditList, AuditQuery};
+use crate::repositories::AuditRepoExt;
+use crate::router::AUDIT_TAG;
+use crate::services;
+use axum::extract::{Extension, FromRequestParts};
+use axum::http::request::Parts;
+use axum_extra::extract::Query;
+
+use super::Pagination;
+use super::auth::{AuthError, Claims};
+
+// Set by `SetRequestIdLayer`, so every audit entry can be traced back to its request
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// List audit log entries
+///
+/// Tries to get the audit log, newest first. Admins only.
+#[utoipa::path(
+    get,
+    path = "/list",
+    params(
+        ("entity" = inline(Option<String>), Query, description="Entity (car, part or user)"),
+        ("entityId" = inline(Option<i32>), Query, description="Entity Id"),
+        ("actor" = inline(Option<String>), Query, description="Who made the change"),
+        ("action" = inline(Option<String>), Query, description="create, update, delete, restore or purge"),
+        ("requestId" = inline(Option<String>), Query, description="Request Id"),
+        ("from" = inline(Option<String>), Query, description="Occurred at or after (RFC 3339)"),
+        ("to" = inline(Option<String>), Query, description="Occurred before (RFC 3339)"),
+        ("page" = inline(Option<usize>), Query, description="Page"),
+        ("perPage" = inline(Option<usize>), Query, description="PerPage"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = OK, body = AuditList),
+        (status = 403, description = "Not an admin")
+    ),
+    tag = AUDIT_TAG
+)]
+pub async fn list(
+    claims: Claims,
+    Query(conditions): Query<AuditQuery>,
+    Query(pagination): Query<Pagination>,
+    Extension(repo): AuditRepoExt,
+) -> Result<AppJson<AuditList>, AppError> {
+    claims.require_admin()?;
+    let entries = services::audit::find_all(repo.clone(), &conditions, &pagination).await?;
+    Ok(AppJson(entries))
+}
+
+// Mutating handlers take an `AuditContext` instead of `Claims`: it still requires a valid token,
+// and carries the request id along with the caller.
+impl<S> FromRequestParts<S> for AuditContext
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Ok(AuditContext {
+            actor: claims.sub,
+            request_id,
+        })
+    }
+}
@@ -1,6 +1,7 @@
-use crate::error::{AppError, AppJson};
-use crate::models::user::{User, UserAuth};
-use crate::repositories::UserRepoExt;
+use crate::cache::CacheExt;
+use crate::error::{ApiError, AppError, AppJson};
+use crate::models::user::{Profile, UserAuth};
+use crate::repositories::{UploadRepoExt, UserRepoExt};
 use crate::router::AUTH_TAG;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 
@@ -12,10 +13,11 @@ use utoipa::ToSchema;
 use validator::Validate;
 
 use crate::services;
+use crate::services::uploads::UploadSettingsExt;
 use axum::{
     Json, RequestPartsExt,
-    extract::{Extension, FromRequestParts},
-    http::{StatusCode, request::Parts},
+    extract::{Extension, FromRequestParts, OptionalFromRequestParts},
+    http::{StatusCode, header::AUTHORIZATION, request::Parts},
     response::{IntoResponse, Response},
 };
 use axum_extra::{
@@ -30,7 +32,7 @@ static KEYS: LazyLock<Keys> = LazyLock::new(|| {
 
 /// Account profile
 ///
-/// Current user profile
+/// Current user profile, with how much of the storage quota their uploads use
 #[utoipa::path(
     post,
     path = "/profile",
@@ -38,14 +40,18 @@ static KEYS: LazyLock<Keys> = LazyLock::new(|| {
     security(
         ("bearerAuth" = [])
     ),
-    responses((status = OK, body = User)),
+    responses((status = OK, body = Profile)),
 )]
 pub async fn profile(claims: Claims,
     Extension(repo): UserRepoExt,
- ) -> Result<AppJson<User>, AppError> {
+    Extension(cache): CacheExt,
+    Extension(uploads): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+ ) -> Result<AppJson<Profile>, AppError> {
     let username = claims.sub;
-    let user = services::users::view(repo.clone(), &username).await?;
-    Ok(AppJson(user))
+    let user = services::users::view(repo.clone(), cache, &username, false).await?;
+    let storage = services::uploads::usage(uploads, &settings, &username).await?;
+    Ok(AppJson(Profile { user, storage }))
 }
 
 /// Authorize with username and password
@@ -69,6 +75,7 @@ pub async fn authorize(
     let claims = Claims {
         sub: user.username,
         company: "ACME".to_owned(),
+        admin: user.is_admin,
         // Mandatory expiry time as UTC timestamp
         exp: 2000000000, // May 2033
     };
@@ -114,6 +121,27 @@ where
     }
 }
 
+// Lets public endpoints accept `Option<Claims>`: no `Authorization` header means anonymous, a
+// bad token is still rejected.
+impl<S> OptionalFromRequestParts<S> for Claims
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if !parts.headers.contains_key(AUTHORIZATION) {
+            return Ok(None);
+        }
+        <Claims as FromRequestParts<S>>::from_request_parts(parts, state)
+            .await
+            .map(Some)
+    }
+}
+
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
@@ -145,11 +173,26 @@ impl Keys {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    sub: String,
+    pub sub: String,
     company: String,
+    #[serde(default)]
+    admin: bool,
     exp: usize,
 }
 
+impl Claims {
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn require_admin(&self) -> anyhow::Result<()> {
+        if !self.is_admin() {
+            return Err(ApiError::Forbidden.into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, ToSchema, Validate)]
 pub struct AuthBody {
     access_token: String,
@@ -0,0 +1,131 @@
+use crate::cache::{CacheExt, Entity};
+use crate::error::{AppError, AppJson};
+use crate::models::cache::{
+    CacheEntry, CacheStats, EvictRequest, Evicted, WarmRequest, WarmResult,
+};
+use crate::repositories::{CarRepoExt, PartRepoExt, UserRepoExt};
+use crate::router::CACHE_TAG;
+use crate::services;
+use axum::{
+    Json,
+    extract::{Extension, Path},
+};
+
+use super::auth::Claims;
+
+/// Cache statistics
+///
+/// Hit and miss counts per tier, Redis health and local tier usage. Admins only.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = OK, body = CacheStats),
+        (status = 403, description = "Not an admin")
+    ),
+    tag = CACHE_TAG
+)]
+pub async fn stats(
+    claims: Claims,
+    Extension(cache): CacheExt,
+) -> Result<AppJson<CacheStats>, AppError> {
+    claims.require_admin()?;
+    Ok(AppJson(cache.stats().await))
+}
+
+/// Look up a cached record
+///
+/// Shows what the local tier and Redis hold for one car, part or user. Admins only.
+#[utoipa::path(
+    get,
+    path = "/lookup/{entity}/{id}",
+    params(
+        ("entity" = Entity, Path, description="car, part or user"),
+        ("id" = String, Path, description="Car or part id, or username"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = OK, body = CacheEntry),
+        (status = 403, description = "Not an admin")
+    ),
+    tag = CACHE_TAG
+)]
+pub async fn lookup(
+    claims: Claims,
+    Path((entity, id)): Path<(Entity, String)>,
+    Extension(cache): CacheExt,
+) -> Result<AppJson<CacheEntry>, AppError> {
+    claims.require_admin()?;
+    Ok(AppJson(cache.lookup(&entity.key(id)).await))
+}
+
+/// Evict cached records
+///
+/// Drops one record, every record of an entity, or every key starting with a prefix, on all
+/// instances. Admins only.
+#[utoipa::path(
+    post,
+    path = "/evict",
+    security(
+        ("bearerAuth" = [])
+    ),
+    request_body(content=EvictRequest, content_type="application/json", description="What to evict"),
+    responses(
+        (status = OK, body = Evicted),
+        (status = 400, description = "Neither an entity nor a valid prefix"),
+        (status = 403, description = "Not an admin")
+    ),
+    tag = CACHE_TAG
+)]
+pub async fn evict(
+    claims: Claims,
+    Extension(cache): CacheExt,
+    Json(request): Json<EvictRequest>,
+) -> Result<AppJson<Evicted>, AppError> {
+    claims.require_admin()?;
+    let evicted = services::cache::evict(cache, &request).await?;
+    Ok(AppJson(evicted))
+}
+
+/// Warm the cache
+///
+/// Loads the given records into the cache ahead of the reads. Admins only.
+#[utoipa::path(
+    post,
+    path = "/warm",
+    security(
+        ("bearerAuth" = [])
+    ),
+    request_body(content=WarmRequest, content_type="application/json", description="Records to load"),
+    responses(
+        (status = OK, body = WarmResult),
+        (status = 400, description = "Too many ids"),
+        (status = 403, description = "Not an admin")
+    ),
+    tag = CACHE_TAG
+)]
+pub async fn warm(
+    claims: Claims,
+    Extension(car_repo): CarRepoExt,
+    Extension(part_repo): PartRepoExt,
+    Extension(user_repo): UserRepoExt,
+    Extension(cache): CacheExt,
+    Json(request): Json<WarmRequest>,
+) -> Result<AppJson<WarmResult>, AppError> {
+    claims.require_admin()?;
+    let result = services::cache::warm(
+        car_repo,
+        part_repo,
+        user_repo,
+        cache,
+        request.entity,
+        &request.ids,
+    )
+    .await?;
+    Ok(AppJson(result))
+}
@@ -1,17 +1,25 @@
 use crate::cache::CacheExt;
 use crate::error::{AppError, AppJson};
-use crate::models::car::{Car, CarList, CarQuery, NewCar};
-use crate::repositories::CarRepoExt;
+use crate::models::attachment::{AttachmentOwner, AttachmentSummary, NewAttachment};
+use crate::models::audit::AuditContext;
+use crate::models::car::{Car, CarDetails, CarList, CarQuery, NewCar};
+use crate::repositories::{AttachmentRepoExt, CarRepoExt, UploadRepoExt};
 use crate::router::CARS_TAG;
 use crate::services;
+use crate::storage::StorageExt;
 use axum::{
     Json,
     extract::{Extension, Path},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use axum_extra::extract::Query;
+use uuid::Uuid;
 
 use super::auth::Claims;
-use super::{CommonQuery, Pagination};
+use super::conditional::{ConditionalGet, Validators, etag, if_match_versions};
+use super::files;
+use super::{CommonQuery, Pagination, ViewQuery, authorize_include_deleted};
 
 /// List Cars
 ///
@@ -22,25 +30,40 @@ use super::{CommonQuery, Pagination};
     params(
         ("name" = inline(Option<String>), Query, description="Car Name"),
         ("ids" = inline(Option<String>), Query, description="ids"),
+        ("updatedAfter" = inline(Option<String>), Query, description="Modified at or after (RFC 3339)"),
+        ("updatedBefore" = inline(Option<String>), Query, description="Modified before (RFC 3339)"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
         ("page" = inline(Option<usize>), Query, description="Page"),
         ("perPage" = inline(Option<usize>), Query, description="PerPage"),
         ("field" = inline(Option<String>), Query, description="Field"),
         ("order" = inline(Option<String>), Query, description="Order")
     ) ,
-    responses((status = OK, body = CarList)),
+    responses(
+        (status = OK, body = CarList, headers(
+            ("ETag" = String, description = "Hash of the page"),
+            ("Cache-Control" = String, description = "As configured")
+        )),
+        (status = 304, description = "Matches the If-None-Match ETag")
+    ),
     tag = CARS_TAG
 )]
 pub async fn list(
+    claims: Option<Claims>,
     Query(conditions): Query<CarQuery>,
     Query(query): Query<CommonQuery>,
     Query(pagination): Query<Pagination>,
     Extension(repo): CarRepoExt,
-) -> Result<AppJson<CarList>, AppError> {
+    Extension(cache): CacheExt,
+    conditional: ConditionalGet,
+) -> Result<Response, AppError> {
     println!("list params: {:?}", pagination);
     println!("conditions: {:?}", conditions);
     println!("ids: {:?}", query);
-    let cars = services::cars::find_all(repo.clone(), &conditions, &query, &pagination).await?;
-    Ok(AppJson(cars))
+    authorize_include_deleted(query.include_deleted, claims.as_ref())?;
+    let cars =
+        services::cars::find_all(repo.clone(), cache, &conditions, &query, &pagination).await?;
+    let validators = Validators::content(&cars)?;
+    Ok(conditional.respond(validators, cars))
 }
 
 ///
@@ -48,17 +71,41 @@ pub async fn list(
 #[utoipa::path(
     get,
     path = "/{car_id}",
-    params(("car_id" = i32, Path, description="Car Id")),
-    responses((status = OK, body = Car)),
+    params(
+        ("car_id" = i32, Path, description="Car Id"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
+    ),
+    responses(
+        (status = OK, body = CarDetails, headers(
+            ("ETag" = String, description = "Current version of the car"),
+            ("Last-Modified" = String, description = "When the car last changed"),
+            ("Cache-Control" = String, description = "As configured")
+        )),
+        (status = 304, description = "Matches If-None-Match, or unchanged since If-Modified-Since")
+    ),
     tag = CARS_TAG
 )]
 pub async fn view(
+    claims: Option<Claims>,
     Path(car_id): Path<i32>,
+    Query(view_query): Query<ViewQuery>,
     Extension(repo): CarRepoExt,
+    Extension(attachments): AttachmentRepoExt,
     Extension(cache): CacheExt,
-) -> Result<AppJson<Car>, AppError> {
-    let car = services::cars::view(repo.clone(), cache.clone(), car_id).await?;
-    Ok(AppJson(car))
+    conditional: ConditionalGet,
+) -> Result<impl IntoResponse, AppError> {
+    authorize_include_deleted(view_query.include_deleted, claims.as_ref())?;
+    let car = services::cars::view(
+        repo.clone(),
+        cache.clone(),
+        car_id,
+        view_query.include_deleted,
+    )
+    .await?;
+    let owner = AttachmentOwner::Car(car.id);
+    let attachments = services::attachments::find_all(attachments, owner).await?;
+    let validators = Validators::row(car.version, car.updated_at);
+    Ok(conditional.respond(validators, CarDetails { car, attachments }))
 }
 
 /// Create new Car
@@ -71,17 +118,21 @@ pub async fn view(
         security(
             ("bearerAuth" = [])
         ),
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key and body get the first response back instead of creating another car")),
         request_body(content=NewCar, content_type="application/json", description="New Car Information"),
         responses(
-            (status = 201, description = "Car item created successfully", body = Car)
+            (status = 201, description = "Car item created successfully", body = Car),
+            (status = 409, description = "A request with this Idempotency-Key is still being processed"),
+            (status = 422, description = "The Idempotency-Key was used with a different body")
         )
 )]
 pub async fn create(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): CarRepoExt,
+    Extension(cache): CacheExt,
     Json(new_car): Json<NewCar>,
 ) -> Result<AppJson<Car>, AppError> {
-    let car = services::cars::create(repo.clone(), &new_car).await?;
+    let car = services::cars::create(repo.clone(), cache, &new_car, &ctx).await?;
     Ok(AppJson(car))
 }
 
@@ -95,19 +146,25 @@ pub async fn create(
         security(
             ("bearerAuth" = [])
         ),
+        params(("If-Match" = String, Header, description = "ETag of the car being updated")),
         request_body(content=Car, content_type="application/json", description="Car To Update"),
         responses(
-            (status = 200, description = "Car item updated successfully", body = Car)
+            (status = 200, description = "Car item updated successfully", body = Car,
+                headers(("ETag" = String, description = "New version of the car"))),
+            (status = 412, description = "Car was modified since the given ETag"),
+            (status = 428, description = "If-Match header missing")
         )
 )]
 pub async fn update(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): CarRepoExt,
     Extension(cache): CacheExt,
+    headers: HeaderMap,
     Json(car): Json<Car>,
-) -> Result<AppJson<Car>, AppError> {
-    let car = services::cars::update(repo.clone(), cache, &car).await?;
-    Ok(AppJson(car))
+) -> Result<impl IntoResponse, AppError> {
+    let versions = if_match_versions(&headers)?;
+    let car = services::cars::update(repo.clone(), cache, &car, &versions, &ctx).await?;
+    Ok((etag(car.version), AppJson(car)))
 }
 
 /// Delete existing Car
@@ -116,22 +173,190 @@ pub async fn update(
 #[utoipa::path(
         delete,
         path = "/delete/{car_id}",
-        params(("car_id" = i32, Path, description="Car Id")),
+        params(
+            ("car_id" = i32, Path, description="Car Id"),
+            ("If-Match" = String, Header, description = "ETag of the car being deleted")
+        ),
         security(
             ("bearerAuth" = [])
         ),
         tag = CARS_TAG,
         responses(
-            (status = 200, description = "Car item deleted successfully", body = String)
+            (status = 200, description = "Car item deleted successfully", body = String),
+            (status = 412, description = "Car was modified since the given ETag"),
+            (status = 428, description = "If-Match header missing")
         )
 )]
 pub async fn delete(
-    _claims: Claims,
+    ctx: AuditContext,
+    Path(car_id): Path<i32>,
+    Extension(repo): CarRepoExt,
+    Extension(cache): CacheExt,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    let versions = if_match_versions(&headers)?;
+    services::cars::delete(repo.clone(), cache, car_id, &versions, &ctx).await?;
+    Ok(())
+}
+
+/// Restore deleted Car
+///
+/// Tries to bring back a soft-deleted Car. Admins only.
+#[utoipa::path(
+        post,
+        path = "/restore/{car_id}",
+        params(("car_id" = i32, Path, description="Car Id")),
+        security(
+            ("bearerAuth" = [])
+        ),
+        tag = CARS_TAG,
+        responses(
+            (status = 200, description = "Car item restored successfully", body = String),
+            (status = 403, description = "Not an admin"),
+            (status = 404, description = "No deleted car with this id")
+        )
+)]
+pub async fn restore(
+    claims: Claims,
+    ctx: AuditContext,
+    Path(car_id): Path<i32>,
+    Extension(repo): CarRepoExt,
+    Extension(cache): CacheExt,
+) -> Result<(), AppError> {
+    claims.require_admin()?;
+    services::cars::restore(repo.clone(), cache, car_id, &ctx).await?;
+    Ok(())
+}
+
+/// List attachments of a Car
+///
+/// Files attached to the car, oldest first.
+#[utoipa::path(
+    get,
+    path = "/{car_id}/attachments",
+    params(("car_id" = i32, Path, description="Car Id")),
+    responses(
+        (status = OK, body = [AttachmentSummary]),
+        (status = 404, description = "No such car")
+    ),
+    tag = CARS_TAG
+)]
+pub async fn attachments(
     Path(car_id): Path<i32>,
     Extension(repo): CarRepoExt,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(cache): CacheExt,
+) -> Result<AppJson<Vec<AttachmentSummary>>, AppError> {
+    services::cars::view(repo, cache, car_id, false).await?;
+    let owner = AttachmentOwner::Car(car_id);
+    let attachments = services::attachments::find_all(attachments, owner).await?;
+    Ok(AppJson(attachments))
+}
+
+/// Download an attachment of a Car
+///
+/// Sends the attached file like `/api/files/{id}` does, to anyone who can see the car.
+#[utoipa::path(
+    get,
+    path = "/{car_id}/attachments/{upload_id}",
+    params(
+        ("car_id" = i32, Path, description="Car Id"),
+        ("upload_id" = Uuid, Path, description="File Id"),
+        ("Range" = Option<String>, Header, description = "e.g. `bytes=0-1023`"),
+    ),
+    responses(
+        (status = 200, description = "The whole file", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream"),
+        (status = 404, description = "No such car, or the file is not attached to it"),
+        (status = 416, description = "The range lies outside the file"),
+    ),
+    tag = CARS_TAG
+)]
+pub async fn attachment(
+    Path((car_id, upload_id)): Path<(i32, Uuid)>,
+    Extension(repo): CarRepoExt,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(cache): CacheExt,
+    Extension(storage): StorageExt,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    services::cars::view(repo, cache, car_id, false).await?;
+    let owner = AttachmentOwner::Car(car_id);
+    let upload = services::attachments::find_upload(attachments, owner, upload_id).await?;
+    files::send(storage.as_ref(), &upload, &headers).await
+}
+
+/// Attach a file to a Car
+///
+/// Attaches one of the caller's uploads, see `/api/files/upload`. A file can be attached to a
+/// single car or part, and counts as a change to the car: its version is bumped.
+#[utoipa::path(
+    post,
+    path = "/{car_id}/attachments",
+    params(("car_id" = i32, Path, description="Car Id")),
+    security(
+        ("bearerAuth" = [])
+    ),
+    request_body(content=NewAttachment, content_type="application/json", description="File to attach"),
+    responses(
+        (status = 201, description = "File attached successfully", body = AttachmentSummary),
+        (status = 404, description = "No such car, or the file is not the caller's"),
+        (status = 409, description = "The file is already attached")
+    ),
+    tag = CARS_TAG
+)]
+pub async fn attach(
+    claims: Claims,
+    ctx: AuditContext,
+    Path(car_id): Path<i32>,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(uploads): UploadRepoExt,
+    Extension(cache): CacheExt,
+    Json(new_attachment): Json<NewAttachment>,
+) -> Result<(StatusCode, AppJson<AttachmentSummary>), AppError> {
+    let upload = services::uploads::view(
+        uploads,
+        new_attachment.upload_id,
+        &claims.sub,
+        claims.is_admin(),
+    )
+    .await?;
+    let owner = AttachmentOwner::Car(car_id);
+    let attachment =
+        services::attachments::attach(attachments, cache, owner, &upload, &ctx).await?;
+    Ok((StatusCode::CREATED, AppJson(attachment)))
+}
+
+/// Detach a file from a Car
+///
+/// The file itself is kept. Only whoever uploaded it, or an admin, can detach it.
+#[utoipa::path(
+    delete,
+    path = "/{car_id}/attachments/{upload_id}",
+    params(
+        ("car_id" = i32, Path, description="Car Id"),
+        ("upload_id" = Uuid, Path, description="File Id"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = 200, description = "File detached successfully"),
+        (status = 404, description = "The file is not attached to the car, or not the caller's")
+    ),
+    tag = CARS_TAG
+)]
+pub async fn detach(
+    claims: Claims,
+    ctx: AuditContext,
+    Path((car_id, upload_id)): Path<(i32, Uuid)>,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(uploads): UploadRepoExt,
     Extension(cache): CacheExt,
 ) -> Result<(), AppError> {
-    services::cars::delete(repo.clone(), cache, car_id).await?;
+    services::uploads::view(uploads, upload_id, &claims.sub, claims.is_admin()).await?;
+    let owner = AttachmentOwner::Car(car_id);
+    services::attachments::detach(attachments, cache, owner, upload_id, &ctx).await?;
     Ok(())
 }
 
@@ -141,16 +366,30 @@ pub async fn delete(
 #[cfg(test)]
 mod tests {
     use crate::config::Config;
-    use crate::controllers::cars;
+    use crate::controllers::conditional::CacheControl;
+    use crate::controllers::{Pagination, cars};
+    use crate::models::attachment::AttachmentOwner;
+    use crate::models::audit::{AuditContext, AuditQuery};
     use crate::models::car::{CarList, NewCar};
+    use crate::models::upload::NewUpload;
+    use crate::repositories::attachment::AttachmentRepository;
+    use crate::repositories::audit::AuditRepository;
     use crate::repositories::car::CarRepository;
-    use crate::repositories::{clear_database, create_car_repository, run_migrations};
+    use crate::repositories::upload::UploadRepository;
+    use crate::repositories::{
+        clear_database, create_attachment_repository, create_audit_repository,
+        create_car_repository, create_upload_repository, run_migrations,
+    };
+    use crate::services;
+    use crate::tests::fixture::cache::cache_fixture;
     use axum::http::Request;
     use axum::routing::get;
     use axum::{Extension, Router, body::Body, http::StatusCode};
     use once_cell::sync::Lazy;
+    use serde_json::json;
     use std::sync::Arc;
     use tower::ServiceExt;
+    use uuid::Uuid;
 
     static INIT: Lazy<()> = Lazy::new(|| {
         dotenv::from_filename(".env.test").ok();
@@ -172,12 +411,17 @@ mod tests {
             color: Some("Red".to_string()),
             year: Some(2020),
         };
-        real_repo.create(&car).await.unwrap();
+        real_repo
+            .create(&car, &AuditContext::system())
+            .await
+            .unwrap();
 
         // Create an Axum router with the mock repository as an extension
         let app = Router::new()
             .route("/cars", get(cars::list))
-            .layer(Extension(Arc::new(real_repo)));
+            .layer(Extension(Arc::new(real_repo)))
+            .layer(Extension(cache_fixture()))
+            .layer(Extension(CacheControl::from(&config)));
 
         // Build a request to simulate a GET /cars
         let request = Request::builder()
@@ -207,4 +451,163 @@ mod tests {
         assert_eq!(cars.data[0].color, Some("Red".to_string()));
         assert_eq!(cars.data[0].year, Some(2020));
     }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_update_matches_any_listed_version() {
+        Lazy::force(&INIT);
+        let config = Config::init();
+        let _ = run_migrations(&config).await;
+        let _ = clear_database(&config).await;
+        let repo = Arc::new(create_car_repository(&config).await);
+        let ctx = AuditContext::system();
+
+        // given a car at version 3
+        let car = NewCar {
+            name: "Tesla".to_string(),
+            color: None,
+            year: None,
+        };
+        let mut car = repo.create(&car, &ctx).await.unwrap();
+        for version in [1, 2] {
+            car = repo.update(&car, &[version], &ctx).await.unwrap().unwrap();
+        }
+        assert_eq!(car.version, 3);
+
+        // when `If-Match: "1", "3"`
+        let updated = services::cars::update(repo.clone(), cache_fixture(), &car, &[1, 3], &ctx)
+            .await
+            .unwrap();
+
+        // then
+        assert_eq!(updated.version, 4);
+        let stale = services::cars::update(repo, cache_fixture(), &car, &[1, 3], &ctx).await;
+        assert!(stale.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_attachments_are_audited_on_their_car() {
+        Lazy::force(&INIT);
+        let config = Config::init();
+        let _ = run_migrations(&config).await;
+        let _ = clear_database(&config).await;
+        let cars = create_car_repository(&config).await;
+        let uploads = create_upload_repository(&config).await;
+        let attachments = create_attachment_repository(&config).await;
+        let audit = create_audit_repository(&config).await;
+        let ctx = AuditContext::system();
+
+        // given
+        let car = NewCar {
+            name: "Tesla".to_string(),
+            color: None,
+            year: None,
+        };
+        let car = cars.create(&car, &ctx).await.unwrap();
+        let upload = NewUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            original_name: "tesla.txt".to_string(),
+            size: 5,
+            content_type: "text/plain".to_string(),
+            sha256: Uuid::new_v4().to_string(),
+            description: None,
+        };
+        let upload = uploads.create(&upload, None).await.unwrap();
+        let owner = AttachmentOwner::Car(car.id);
+
+        // when
+        attachments.attach(owner, upload.id, &ctx).await.unwrap();
+        attachments.detach(owner, upload.id, &ctx).await.unwrap();
+
+        // then
+        let query = AuditQuery {
+            entity: Some("car".to_string()),
+            entity_id: Some(car.id),
+            action: Some("update".to_string()),
+            ..Default::default()
+        };
+        let pagination = Pagination {
+            page: None,
+            per_page: None,
+            field: None,
+            order: None,
+        };
+        let entries = audit.find_all(&query, &pagination).await.unwrap().data;
+        let id = upload.id.to_string();
+        // newest first
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].changes["attachments"]["from"], json!([id]));
+        assert_eq!(entries[0].changes["attachments"]["to"], json!([]));
+        assert_eq!(entries[1].changes["attachments"]["from"], json!([]));
+        assert_eq!(entries[1].changes["attachments"]["to"], json!([id]));
+        assert_eq!(entries[1].changes["version"]["to"], json!(2));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_thumbnails_are_audited_updates_of_their_car() {
+        Lazy::force(&INIT);
+        let config = Config::init();
+        let _ = run_migrations(&config).await;
+        let _ = clear_database(&config).await;
+        let cars = create_car_repository(&config).await;
+        let uploads = create_upload_repository(&config).await;
+        let attachments = create_attachment_repository(&config).await;
+        let audit = create_audit_repository(&config).await;
+        let ctx = AuditContext::system();
+
+        // given an image attached to a car
+        let car = NewCar {
+            name: "Tesla".to_string(),
+            color: None,
+            year: None,
+        };
+        let car = cars.create(&car, &ctx).await.unwrap();
+        let upload = NewUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            original_name: "tesla.png".to_string(),
+            size: 5,
+            content_type: "image/png".to_string(),
+            sha256: Uuid::new_v4().to_string(),
+            description: None,
+        };
+        let upload = uploads.create(&upload, None).await.unwrap();
+        let owner = AttachmentOwner::Car(car.id);
+        attachments.attach(owner, upload.id, &ctx).await.unwrap();
+
+        // when
+        let set = uploads.set_thumbnails(upload.id, &[100]).await.unwrap();
+
+        // then
+        assert_eq!(set, Some(Some(owner)));
+        let after = cars.find_by_id(car.id, false).await.unwrap();
+        assert_eq!(after.version, 3);
+        assert!(after.updated_at > car.updated_at);
+        let query = AuditQuery {
+            entity: Some("car".to_string()),
+            entity_id: Some(car.id),
+            actor: Some("system".to_string()),
+            action: Some("update".to_string()),
+            ..Default::default()
+        };
+        let pagination = Pagination {
+            page: None,
+            per_page: None,
+            field: None,
+            order: None,
+        };
+        let entries = audit.find_all(&query, &pagination).await.unwrap().data;
+        // newest first, after the one for attaching
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].changes["version"]["from"], json!(2));
+        assert_eq!(entries[0].changes["version"]["to"], json!(3));
+        let gone = uploads
+            .set_thumbnails(Uuid::new_v4(), &[100])
+            .await
+            .unwrap();
+        assert_eq!(gone, None);
+    }
 }
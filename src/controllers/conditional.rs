@@ -0,0 +1,222 @@
+use crate::config::Config;
+use crate::error::{ApiError, AppJson};
+use axum::Extension;
+use axum::extract::FromRequestParts;
+use axum::extract::rejection::ExtensionRejection;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+
+pub type CacheControlExt = Extension<CacheControl>;
+
+// `Cache-Control` sent with cacheable GET responses, see `Config::http_cache_control`
+#[derive(Debug, Clone)]
+pub struct CacheControl(HeaderValue);
+
+impl From<&Config> for CacheControl {
+    fn from(config: &Config) -> Self {
+        let value = HeaderValue::from_str(&config.http_cache_control)
+            .unwrap_or_else(|e| panic!("HTTP_CACHE_CONTROL is invalid: {e:?}"));
+        Self(value)
+    }
+}
+
+// Versioned rows use their version number as a strong entity tag, e.g. `"3"`.
+pub fn etag(version: i32) -> [(HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", version))]
+}
+
+// What a client's cached copy of a GET response is checked against
+pub struct Validators {
+    etag: ETag,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    // A versioned row: its version is the ETag (the same one `If-Match` takes), and every write
+    // bumps `updated_at`
+    pub fn row(version: i32, updated_at: DateTime<Utc>) -> Self {
+        let etag = format!("\"{}\"", version)
+            .parse()
+            .expect("a version is a valid ETag");
+        Self {
+            etag,
+            last_modified: Some(updated_at),
+        }
+    }
+
+    // A list is tagged with a hash of its content. It has no trustworthy modification time: a
+    // row dropping out of the list does not make any of the others newer.
+    pub fn content(body: &impl Serialize) -> anyhow::Result<Self> {
+        let digest = Sha256::digest(serde_json::to_vec(body)?);
+        let etag = format!("\"{:x}\"", digest).parse()?;
+        Ok(Self {
+            etag,
+            last_modified: None,
+        })
+    }
+
+    // `If-None-Match` takes precedence, `If-Modified-Since` is only looked at without it
+    // (RFC 9110, 13.2.2). It is accurate to the second only.
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+            return !if_none_match.precondition_passes(&self.etag);
+        }
+        match (headers.typed_get::<IfModifiedSince>(), self.last_modified) {
+            (Some(since), Some(modified)) => !since.is_modified(SystemTime::from(modified)),
+            _ => false,
+        }
+    }
+}
+
+// Extractor for cacheable GET handlers: the request's conditional headers together with the
+// configured `Cache-Control`.
+pub struct ConditionalGet {
+    headers: HeaderMap,
+    cache_control: CacheControl,
+}
+
+impl<S> FromRequestParts<S> for ConditionalGet
+where
+    S: Send + Sync,
+{
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(cache_control) = CacheControlExt::from_request_parts(parts, state).await?;
+        Ok(Self {
+            headers: parts.headers.clone(),
+            cache_control,
+        })
+    }
+}
+
+impl ConditionalGet {
+    // 304 with no body when the client's copy is still current, otherwise the body. Either way
+    // the response carries the validators and the configured `Cache-Control`.
+    pub fn respond<T: Serialize>(self, validators: Validators, body: T) -> Response {
+        let mut response = if validators.not_modified(&self.headers) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            AppJson(body).into_response()
+        };
+        let headers = response.headers_mut();
+        headers.typed_insert(validators.etag);
+        if let Some(modified) = validators.last_modified {
+            headers.typed_insert(LastModified::from(SystemTime::from(modified)));
+        }
+        headers.insert(header::CACHE_CONTROL, self.cache_control.0);
+        response
+    }
+}
+
+// Extracts the versions the client expects to modify from `If-Match`; the write goes ahead if
+// the row is at any of them.
+//
+// Writes to versioned rows must be conditional, so a missing header is rejected with 428. A
+// header that names no strong version of ours (weak tags, `*`, garbage) can never match and is
+// rejected with 412.
+pub fn if_match_versions(headers: &HeaderMap) -> Result<Vec<i32>, ApiError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .ok_or(ApiError::PreconditionRequired)?
+        .to_str()
+        .map_err(|_| ApiError::PreconditionFailed)?;
+
+    let versions: Vec<i32> = value
+        .split(',')
+        .map(str::trim)
+        .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+        .collect();
+    if versions.is_empty() {
+        return Err(ApiError::PreconditionFailed);
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(if_match: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, HeaderValue::from_static(if_match));
+        headers
+    }
+
+    fn get(name: HeaderName, value: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        let conditional = ConditionalGet {
+            headers,
+            cache_control: CacheControl(HeaderValue::from_static("private, no-cache")),
+        };
+        let updated_at = DateTime::parse_from_rfc3339("2025-01-02T03:04:05.678Z").unwrap();
+        conditional.respond(Validators::row(3, updated_at.into()), "car")
+    }
+
+    #[test]
+    fn test_if_none_match_matching_the_version_is_not_modified() {
+        for value in ["\"3\"", "W/\"3\"", "\"1\", \"3\"", "*"] {
+            let response = get(header::IF_NONE_MATCH, value);
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{value}");
+        }
+        let response = get(header::IF_NONE_MATCH, "\"2\"");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"3\"");
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "private, no-cache"
+        );
+        assert_eq!(
+            response.headers()[header::LAST_MODIFIED],
+            "Thu, 02 Jan 2025 03:04:05 GMT"
+        );
+    }
+
+    #[test]
+    fn test_if_modified_since_compares_to_the_second() {
+        let response = get(header::IF_MODIFIED_SINCE, "Thu, 02 Jan 2025 03:04:05 GMT");
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get(header::IF_MODIFIED_SINCE, "Thu, 02 Jan 2025 03:04:04 GMT");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_lists_are_tagged_by_content() {
+        let etag = |body: &[i32]| Validators::content(&body).unwrap().etag;
+        assert_eq!(etag(&[1, 2]), etag(&[1, 2]));
+        assert_ne!(etag(&[1, 2]), etag(&[1]));
+        assert!(Validators::content(&[1]).unwrap().last_modified.is_none());
+    }
+
+    #[test]
+    fn test_if_match_parses_strong_versions() {
+        assert_eq!(if_match_versions(&headers("\"3\"")).unwrap(), [3]);
+        assert_eq!(if_match_versions(&headers("\"x\", \"4\"")).unwrap(), [4]);
+        assert_eq!(
+            if_match_versions(&headers("\"1\", W/\"2\", \"3\"")).unwrap(),
+            [1, 3]
+        );
+    }
+
+    #[test]
+    fn test_if_match_rejects_missing_and_unusable_tags() {
+        assert!(matches!(
+            if_match_versions(&HeaderMap::new()),
+            Err(ApiError::PreconditionRequired)
+        ));
+        for value in ["W/\"3\"", "*", "3"] {
+            assert!(matches!(
+                if_match_versions(&headers(value)),
+                Err(ApiError::PreconditionFailed)
+            ));
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use crate::cache::CacheExt;
+use crate::controllers::Pagination;
+use crate::controllers::auth::Claims;
+use crate::error::{ApiError, AppError, AppJson};
+use crate::models::upload::{DownloadLink, FileMeta, Upload, UploadForm, UploadList};
+use crate::repositories::UploadRepoExt;
+use crate::repositories::upload::UploadRepository;
+use crate::router::FILES_TAG;
+use crate::services;
+use crate::services::uploads::{UploadSettings, UploadSettingsExt};
+use crate::storage::{Storage, StorageExt};
+use anyhow::{Result, bail};
+use axum::{
+    body::Body,
+    extract::{
+        Extension, Path, Query,
+        multipart::{Field, Multipart, MultipartError},
+    },
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+    },
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{AcceptRanges, ContentLength, ContentRange, HeaderMapExt, Range};
+use serde::Deserialize;
+use std::ops::Bound;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const MAX_DESCRIPTION_BYTES: usize = 1000;
+
+/// Upload files
+///
+/// Accepts several files in one `multipart/form-data` request and streams each of them to
+/// storage. A `description` field applies to the files that come after it. Either every file
+/// is stored or none is. The type of each file is told from its content and has to be one of
+/// `UPLOAD_ALLOWED_TYPES`.
+#[utoipa::path(
+    post,
+    path = "/upload",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    request_body(content = UploadForm, content_type = "multipart/form-data", description = "Files and their description"),
+    responses(
+        (status = 201, description = "Files uploaded successfully", body = [Upload]),
+        (status = 400, description = "Malformed form, unknown field, no files or too many"),
+        (status = 413, description = "A file is larger than the upload limit, or the files do not fit in the caller's storage quota"),
+        (status = 415, description = "A file is not of its declared type, or of a type not accepted"),
+    )
+)]
+pub async fn upload(
+    claims: Claims,
+    Extension(repo): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+    Extension(storage): StorageExt,
+    Extension(cache): CacheExt,
+    multipart: Multipart,
+) -> Result<(StatusCode, AppJson<Vec<Upload>>), AppError> {
+    let mut uploads = vec![];
+    let read = read_form(
+        repo.clone(),
+        storage.as_ref(),
+        &settings,
+        &claims.sub,
+        multipart,
+        &mut uploads,
+    )
+    .await;
+    if let Err(err) = read {
+        services::uploads::discard(repo, storage.as_ref(), &uploads).await?;
+        return Err(err.into());
+    }
+    for upload in &uploads {
+        let sizes = settings.thumbnail_sizes.clone();
+        services::thumbnails::spawn(
+            repo.clone(),
+            storage.clone(),
+            cache.clone(),
+            sizes,
+            upload.clone(),
+        );
+    }
+    Ok((StatusCode::CREATED, AppJson(uploads)))
+}
+
+/// List own files
+///
+/// The caller's uploads, newest first.
+#[utoipa::path(
+    get,
+    path = "/list",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("page" = Option<usize>, Query, description = "Page number, starting from 1"),
+        ("perPage" = Option<usize>, Query, description = "Files per page"),
+    ),
+    responses(
+        (status = OK, body = UploadList)
+    )
+)]
+pub async fn list(
+    claims: Claims,
+    Query(pagination): Query<Pagination>,
+    Extension(repo): UploadRepoExt,
+) -> Result<AppJson<UploadList>, AppError> {
+    let uploads = services::uploads::find_all(repo, &claims.sub, &pagination).await?;
+    Ok(AppJson(uploads))
+}
+
+/// Download a file
+///
+/// Sends the file with its stored content type, as an attachment named after the uploaded
+/// file. A single `Range` is answered with 206 and only those bytes; several ranges get the
+/// whole file. Owners and admins only.
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "File id"),
+        ("Range" = Option<String>, Header, description = "e.g. `bytes=0-1023`"),
+    ),
+    responses(
+        (status = 200, description = "The whole file", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream",
+            headers(("Content-Range" = String, description = "Which bytes were sent"))),
+        (status = 404, description = "No such file, or not the caller's"),
+        (status = 416, description = "The range lies outside the file"),
+    )
+)]
+pub async fn download(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): UploadRepoExt,
+    Extension(storage): StorageExt,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let upload = services::uploads::view(repo, id, &claims.sub, claims.is_admin()).await?;
+    send(storage.as_ref(), &upload, &headers).await
+}
+
+/// Download a thumbnail
+///
+/// A JPEG of an uploaded image whose longest edge is at most `size` pixels, for the sizes in
+/// the `thumbnails` of the file. They are made in the background after the upload, so they
+/// appear a moment later. Owners and admins can see them, and any logged in user once the
+/// image is attached to a car or part.
+#[utoipa::path(
+    get,
+    path = "/{id}/thumb/{size}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "File id"),
+        ("size" = u32, Path, description = "One of the file's `thumbnails`"),
+    ),
+    responses(
+        (status = 200, description = "The thumbnail", content_type = "image/jpeg"),
+        (status = 404, description = "No such file or thumbnail, or not visible to the caller"),
+    )
+)]
+pub async fn thumbnail(
+    claims: Claims,
+    Path((id, size)): Path<(Uuid, u32)>,
+    Extension(repo): UploadRepoExt,
+    Extension(storage): StorageExt,
+) -> Result<Response, AppError> {
+    let upload = services::thumbnails::view(repo, id, &claims.sub, claims.is_admin()).await?;
+    let thumbnail = services::thumbnails::read(storage.as_ref(), &upload, size).await?;
+    let headers = [
+        (CONTENT_TYPE, "image/jpeg"),
+        // a thumbnail never changes once it is made
+        (CACHE_CONTROL, "private, max-age=86400"),
+    ];
+    Ok((headers, thumbnail).into_response())
+}
+
+/// Create a download link
+///
+/// A link to the file that works without logging in until it expires, for handing to a
+/// browser or another service. Owners and admins only.
+#[utoipa::path(
+    get,
+    path = "/{id}/link",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "File id"),
+    ),
+    responses(
+        (status = 200, body = DownloadLink),
+        (status = 404, description = "No such file, or not the caller's"),
+    )
+)]
+pub async fn link(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+) -> Result<AppJson<DownloadLink>, AppError> {
+    let upload = services::uploads::view(repo, id, &claims.sub, claims.is_admin()).await?;
+    let (expires_at, signature) = services::uploads::sign_link(&settings, upload.id);
+    let url = format!(
+        "/api/files/download/{}?expires={}&signature={}",
+        upload.id,
+        expires_at.timestamp(),
+        signature
+    );
+    Ok(AppJson(DownloadLink { url, expires_at }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedQuery {
+    expires: i64,
+    signature: String,
+}
+
+/// Download a file through a link
+///
+/// Like downloading a file, with the query of a link from `/{id}/link` instead of a token.
+#[utoipa::path(
+    get,
+    path = "/download/{id}",
+    tag = FILES_TAG,
+    params(
+        ("id" = Uuid, Path, description = "File id"),
+        ("expires" = i64, Query, description = "From the link"),
+        ("signature" = String, Query, description = "From the link"),
+        ("Range" = Option<String>, Header, description = "e.g. `bytes=0-1023`"),
+    ),
+    responses(
+        (status = 200, description = "The whole file", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream",
+            headers(("Content-Range" = String, description = "Which bytes were sent"))),
+        (status = 403, description = "The link is expired or was not signed for this file"),
+        (status = 404, description = "The file was deleted"),
+        (status = 416, description = "The range lies outside the file"),
+    )
+)]
+pub async fn download_signed(
+    Path(id): Path<Uuid>,
+    Query(query): Query<SignedQuery>,
+    Extension(repo): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+    Extension(storage): StorageExt,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let upload =
+        services::uploads::view_signed(repo, &settings, id, query.expires, &query.signature)
+            .await?;
+    send(storage.as_ref(), &upload, &headers).await
+}
+
+/// Delete a file
+///
+/// Removes the record and the stored bytes. Owners and admins only. Files attached to a car or
+/// part have to be detached first.
+#[utoipa::path(
+    delete,
+    path = "/delete/{id}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "File id"),
+    ),
+    responses(
+        (status = 200, description = "File deleted"),
+        (status = 404, description = "No such file, or not the caller's"),
+        (status = 409, description = "The file is attached to a car or part"),
+    )
+)]
+pub async fn delete(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): UploadRepoExt,
+    Extension(storage): StorageExt,
+) -> Result<(), AppError> {
+    services::uploads::delete(repo, storage.as_ref(), id, &claims.sub, claims.is_admin()).await?;
+    Ok(())
+}
+
+// The file, or the part of it `headers` ask for with `Range`
+pub async fn send(
+    storage: &dyn Storage,
+    upload: &Upload,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let size = upload.size as u64;
+    let (status, start, end) = match byte_range(headers.typed_get::<Range>(), size) {
+        ByteRange::Full => (StatusCode::OK, 0, size),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end + 1),
+        ByteRange::Unsatisfiable => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            response
+                .headers_mut()
+                .typed_insert(ContentRange::unsatisfied_bytes(size));
+            return Ok(response);
+        }
+    };
+    let stream = services::uploads::read(storage, upload, start, end - start).await?;
+
+    let mut response = Response::new(Body::from_stream(stream));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.typed_insert(ContentLength(end - start));
+    headers.typed_insert(AcceptRanges::bytes());
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers.typed_insert(ContentRange::bytes(start..end, size)?);
+    }
+    let content_type = HeaderValue::from_str(&upload.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    headers.insert(CONTENT_TYPE, content_type);
+    headers.insert(
+        CONTENT_DISPOSITION,
+        content_disposition(&upload.original_name),
+    );
+    // The stored type was checked against the first bytes only, and text is taken as whatever
+    // textual type was declared. A browser sniffing the rest could still find markup in a file
+    // stored as plain text or an image, and run it as a page of ours.
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+// Stores the files of the form one by one as they arrive, adding each to `uploads`, so the
+// caller can discard them when a later part fails
+async fn read_form<R: UploadRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    settings: &UploadSettings,
+    owner: &str,
+    mut multipart: Multipart,
+    uploads: &mut Vec<Upload>,
+) -> Result<()> {
+    let mut description = None;
+    while let Some(field) = multipart.next_field().await.map_err(bad_form)? {
+        let name = field.name().unwrap_or_default().to_string();
+        // any part with a file name is a file, whatever the field is called
+        if let Some(file_name) = field.file_name() {
+            if uploads.len() == settings.max_files {
+                bail!(ApiError::BadRequest(format!(
+                    "At most {} files can be uploaded at once",
+                    settings.max_files
+                )));
+            }
+            let meta = FileMeta {
+                name: file_name.to_string(),
+                content_type: field
+                    .content_type()
+                    .unwrap_or("application/octet-stream")
+                    .to_string(),
+                description: description.clone(),
+            };
+            let upload = services::uploads::upload(
+                repo.clone(),
+                storage,
+                settings,
+                &settings.form_types,
+                owner,
+                &meta,
+                field,
+            );
+            uploads.push(upload.await?);
+        } else if name == "description" {
+            description = Some(read_text(field).await?).filter(|text| !text.is_empty());
+        } else {
+            bail!(ApiError::BadRequest(format!("Unknown form field {name:?}")));
+        }
+    }
+    if uploads.is_empty() {
+        bail!(ApiError::BadRequest("The form has no files".to_string()));
+    }
+    Ok(())
+}
+
+async fn read_text(mut field: Field<'_>) -> Result<String> {
+    let mut text = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(bad_form)? {
+        text.extend_from_slice(&chunk);
+        if text.len() > MAX_DESCRIPTION_BYTES {
+            bail!(ApiError::BadRequest(format!(
+                "A description is at most {} bytes",
+                MAX_DESCRIPTION_BYTES
+            )));
+        }
+    }
+    String::from_utf8(text)
+        .map_err(|_| ApiError::BadRequest("A description must be UTF-8".to_string()).into())
+}
+
+fn bad_form(err: MultipartError) -> ApiError {
+    ApiError::BadRequest(err.body_text())
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    // first and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only a single range is served partially; anything the header cannot be made sense of is
+// answered with the whole file, as if there were no `Range`
+fn byte_range(range: Option<Range>, size: u64) -> ByteRange {
+    let Some(range) = range else {
+        return ByteRange::Full;
+    };
+    let ranges: Vec<_> = range.satisfiable_ranges(size).collect();
+    let [(start, end)] = ranges[..] else {
+        return match ranges.len() {
+            0 => ByteRange::Unsatisfiable,
+            _ => ByteRange::Full,
+        };
+    };
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => end.min(size.saturating_sub(1)),
+        Bound::Excluded(end) => end.min(size).saturating_sub(1),
+        Bound::Unbounded => size.saturating_sub(1),
+    };
+    if start >= size || start > end {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+// `attachment` with a plain ASCII `filename` for old clients and the exact name in `filename*`
+fn content_disposition(name: &str) -> HeaderValue {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    ))
+    .expect("only visible ASCII is left")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::upload::MockUploadRepository;
+    use crate::services::file_types::AllowedTypes;
+    use crate::storage::MemoryStorage;
+    use crate::tests::fixture::upload::upload_fixture;
+    use axum::extract::{FromRequest, Request};
+    use std::time::Duration;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn settings(max_files: usize) -> UploadSettings {
+        UploadSettings {
+            max_bytes: 100,
+            max_files,
+            quota: None,
+            form_types: AllowedTypes::default(),
+            body_types: AllowedTypes::default(),
+            resumable_ttl: Duration::from_secs(60),
+            thumbnail_sizes: vec![],
+            link_secret: "secret".to_string(),
+            link_ttl: Duration::from_secs(60),
+        }
+    }
+
+    // `parts` are (field name, file name, content)
+    async fn multipart(parts: &[(&str, Option<&str>, &str)]) -> Multipart {
+        let mut body = String::new();
+        for (name, file_name, content) in parts {
+            body += &format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"");
+            if let Some(file_name) = file_name {
+                body += &format!("; filename=\"{file_name}\"\r\nContent-Type: text/plain");
+            }
+            body += &format!("\r\n\r\n{content}\r\n");
+        }
+        body += &format!("--{BOUNDARY}--\r\n");
+        let request = Request::post("/upload")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn recording_repo() -> MockUploadRepository {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_reserve_blob().returning(|_| Ok(()));
+        mock_repo_impl
+            .expect_create()
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+        mock_repo_impl
+    }
+
+    #[tokio::test]
+    async fn test_stores_every_file_with_the_description_before_it() {
+        let form = multipart(&[
+            ("files", Some("a.txt"), "first"),
+            ("description", None, "manuals"),
+            ("files", Some("b.txt"), "second"),
+        ])
+        .await;
+        let mut uploads = vec![];
+        let storage = MemoryStorage::default();
+        let repo = Arc::new(recording_repo());
+        read_form(repo, &storage, &settings(10), "alice", form, &mut uploads)
+            .await
+            .unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].original_name, "a.txt");
+        assert_eq!(uploads[0].description, None);
+        assert_eq!(uploads[1].size, 6);
+        assert_eq!(uploads[1].description.as_deref(), Some("manuals"));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_more_files_than_allowed() {
+        let form = multipart(&[
+            ("files", Some("a.txt"), "first"),
+            ("files", Some("b.txt"), "second"),
+        ])
+        .await;
+        let mut uploads = vec![];
+        let storage = MemoryStorage::default();
+        let repo = Arc::new(recording_repo());
+        let result = read_form(repo, &storage, &settings(1), "alice", form, &mut uploads).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::BadRequest(_))
+        ));
+        // the caller discards what was already stored
+        assert_eq!(uploads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_unknown_fields_and_empty_forms() {
+        let storage = MemoryStorage::default();
+        let repo = Arc::new(recording_repo());
+        let form = multipart(&[("owner", None, "bob")]).await;
+        let result = read_form(
+            repo.clone(),
+            &storage,
+            &settings(10),
+            "alice",
+            form,
+            &mut vec![],
+        )
+        .await;
+        assert!(result.is_err());
+
+        let form = multipart(&[("description", None, "nothing")]).await;
+        let result = read_form(repo, &storage, &settings(10), "alice", form, &mut vec![]).await;
+        assert!(result.is_err());
+    }
+
+    fn range(value: &'static str) -> Option<Range> {
+        let mut headers = HeaderMap::new();
+        headers.insert("range", HeaderValue::from_static(value));
+        headers.typed_get()
+    }
+
+    #[test]
+    fn test_byte_ranges() {
+        assert_eq!(byte_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            byte_range(range("bytes=0-9"), 100),
+            ByteRange::Partial(0, 9)
+        );
+        assert_eq!(
+            byte_range(range("bytes=90-"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            byte_range(range("bytes=-10"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            byte_range(range("bytes=50-500"), 100),
+            ByteRange::Partial(50, 99)
+        );
+        assert_eq!(byte_range(range("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(
+            byte_range(range("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(byte_range(range("bytes=0-0"), 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_content_disposition_keeps_the_name_safe() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("\"zażółć\".txt"),
+            "attachment; filename=\"_za_____.txt\"; filename*=UTF-8''%22za%C5%BC%C3%B3%C5%82%C4%87%22.txt"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use crate::cache::{CacheExt, CacheImpl};
+use crate::config::Config;
+use crate::controllers::auth::Claims;
+use crate::error::{ApiError, AppError};
+use anyhow::Result;
+use axum::Extension;
+use axum::body::{Body, Bytes, HttpBody, to_bytes};
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body_util::LengthLimitError;
+use redis::{AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+// How long a key stays claimed by a request that is still being handled. Only matters when the
+// instance handling it dies, the key is freed after this.
+const IN_PROGRESS_TTL: u64 = 60;
+
+// Largest request body hashed and response body kept, the same as axum's default body limit
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+// Claims a key unless it is taken, in which case its record is returned instead
+// KEYS: the key
+// ARGV: the in-progress record, its expiry in seconds
+static CLAIM: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then
+            return false
+        end
+        return redis.call('GET', KEYS[1])
+        ",
+    )
+});
+
+pub type IdempotencyExt = Extension<IdempotencyWindow>;
+
+// How long the first response to an `Idempotency-Key` is replayed, see
+// `Config::idempotency_ttl_secs`
+#[derive(Debug, Clone, Copy)]
+pub struct IdempotencyWindow(Duration);
+
+impl From<&Config> for IdempotencyWindow {
+    fn from(config: &Config) -> Self {
+        Self(Duration::from_secs(config.idempotency_ttl_secs))
+    }
+}
+
+// What Redis holds for a key
+#[derive(Serialize, Deserialize)]
+struct Record {
+    // hash of the body the key was first sent with
+    fingerprint: String,
+    // `None` while that request is still being handled
+    response: Option<StoredResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl StoredResponse {
+    fn new(status: StatusCode, headers: &HeaderMap, body: &Bytes) -> Option<Self> {
+        Some(Self {
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.into())))
+                .collect(),
+            body: String::from_utf8(body.to_vec()).ok()?,
+        })
+    }
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut response = (status, self.body).into_response();
+        let headers = response.headers_mut();
+        headers.clear();
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::try_from(value))
+            {
+                headers.append(name, value);
+            }
+        }
+        headers.insert(REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+enum Claim {
+    Claimed,
+    Taken(Record),
+    // Redis is unavailable: the request is handled without deduplication
+    Unavailable,
+}
+
+// Route layer for create endpoints. The first request with an `Idempotency-Key` is handled as
+// usual and its response kept; retries with the same key and body get that response back
+// instead of creating another row. Keys are per user and route, reusing one for a different
+// body is refused with 422, and one whose first request is still running with 409.
+//
+// Server errors are not kept, so those requests can be retried for real, and neither are
+// responses over `MAX_BODY_BYTES`. Larger request bodies are refused with 413.
+pub async fn idempotent(
+    claims: Option<Claims>,
+    Extension(cache): CacheExt,
+    Extension(IdempotencyWindow(ttl)): IdempotencyExt,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(next.run(request).await);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= 255)
+        .ok_or_else(|| {
+            ApiError::BadRequest("Idempotency-Key must be 1 to 255 visible characters".into())
+        })?;
+    let owner = claims.map_or_else(|| "anonymous".to_string(), |claims| claims.sub);
+    let redis_key = format!(
+        "idempotency:{}:{}:{:x}",
+        owner,
+        request.uri().path(),
+        Sha256::digest(key)
+    );
+
+    let (parts, body) = request.into_parts();
+    let body = read_request(body).await?;
+    let fingerprint = format!("{:x}", Sha256::digest(&body));
+    let request = Request::from_parts(parts, Body::from(body));
+
+    match claim(&cache, &redis_key, &fingerprint).await? {
+        Claim::Claimed => {}
+        Claim::Taken(record) if record.fingerprint != fingerprint => {
+            return Err(ApiError::IdempotencyKeyReused.into());
+        }
+        Claim::Taken(Record {
+            response: Some(stored),
+            ..
+        }) => return Ok(stored.into_response()),
+        Claim::Taken(_) => return Err(ApiError::IdempotencyKeyInProgress.into()),
+        Claim::Unavailable => return Ok(next.run(request).await),
+    }
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    // a response that may not fit is passed on as is rather than buffered, and not kept
+    if body
+        .size_hint()
+        .upper()
+        .is_none_or(|size| size > MAX_BODY_BYTES as u64)
+    {
+        cache
+            .call(async |conn| conn.del::<_, ()>(&redis_key).await)
+            .await;
+        return Ok(Response::from_parts(parts, body));
+    }
+    let body = to_bytes(body, MAX_BODY_BYTES).await?;
+    let stored = (!parts.status.is_server_error())
+        .then(|| StoredResponse::new(parts.status, &parts.headers, &body))
+        .flatten();
+    match stored {
+        Some(response) => {
+            let record = Record {
+                fingerprint,
+                response: Some(response),
+            };
+            let json = serde_json::to_string(&record)?;
+            cache
+                .call(async |conn| {
+                    conn.set_ex::<_, _, ()>(&redis_key, json, ttl.as_secs())
+                        .await
+                })
+                .await;
+        }
+        None => {
+            cache
+                .call(async |conn| conn.del::<_, ()>(&redis_key).await)
+                .await;
+        }
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+async fn read_request(body: Body) -> Result<Bytes> {
+    to_bytes(body, MAX_BODY_BYTES).await.map_err(|err| {
+        let source = std::error::Error::source(&err);
+        if source.is_some_and(|source| source.is::<LengthLimitError>()) {
+            ApiError::PayloadTooLarge(MAX_BODY_BYTES as u64).into()
+        } else {
+            err.into()
+        }
+    })
+}
+
+async fn claim(cache: &CacheImpl, key: &str, fingerprint: &str) -> Result<Claim> {
+    let placeholder = serde_json::to_string(&Record {
+        fingerprint: fingerprint.to_string(),
+        response: None,
+    })?;
+    let existing: Option<Option<String>> = cache
+        .call(async |conn| {
+            CLAIM
+                .key(key)
+                .arg(placeholder)
+                .arg(IN_PROGRESS_TTL)
+                .invoke_async(conn)
+                .await
+        })
+        .await;
+    Ok(match existing {
+        None => Claim::Unavailable,
+        Some(None) => Claim::Claimed,
+        Some(Some(json)) => Claim::Taken(serde_json::from_str(&json)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixture::cache::cache_fixture;
+    use axum::routing::post;
+    use axum::{Router, middleware};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    fn app(cache: Arc<CacheImpl>, handled: Arc<AtomicUsize>) -> Router {
+        Router::new()
+            .route(
+                "/create",
+                post(async move || {
+                    let count = handled.fetch_add(1, Ordering::SeqCst) + 1;
+                    (StatusCode::CREATED, [("x-count", count.to_string())])
+                }),
+            )
+            .route_layer(middleware::from_fn(idempotent))
+            .layer(Extension(cache))
+            .layer(Extension(IdempotencyWindow(Duration::from_secs(60))))
+    }
+
+    fn create(key: &str, body: &'static str) -> Request {
+        Request::post("/create")
+            .header(IDEMPOTENCY_KEY, key)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replays_status_headers_and_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let body = Bytes::from_static(b"{\"id\":1}");
+        let stored = StoredResponse::new(StatusCode::CREATED, &headers, &body).unwrap();
+        let response = stored.into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(response.headers()[REPLAYED], "true");
+    }
+
+    #[tokio::test]
+    async fn test_handles_every_request_without_redis() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let app = app(cache_fixture(), handled.clone());
+        for _ in 0..2 {
+            let response = app.clone().oneshot(create("abc", "{}")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bodies_over_the_limit() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let app = app(cache_fixture(), handled.clone());
+        let request = Request::post("/create")
+            .header(IDEMPOTENCY_KEY, "abc")
+            .body(Body::from(vec![b' '; MAX_BODY_BYTES + 1]))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(handled.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_an_empty_key() {
+        let app = app(cache_fixture(), Arc::new(AtomicUsize::new(0)));
+        let response = app.oneshot(create("", "{}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // needs Redis, see CACHE_URL
+    #[tokio::test]
+    #[ignore]
+    async fn test_replays_retries_and_refuses_other_payloads() {
+        dotenv::dotenv().ok();
+        let cache = Arc::new(crate::cache::create_cache(&Config::init()).await);
+        let handled = Arc::new(AtomicUsize::new(0));
+        let app = app(cache, handled.clone());
+        let key = format!("test-{}", rand::random::<u64>());
+
+        let first = app.clone().oneshot(create(&key, "{}")).await.unwrap();
+        let retry = app.clone().oneshot(create(&key, "{}")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()["x-count"], first.headers()["x-count"]);
+        assert_eq!(retry.headers()[REPLAYED], "true");
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+
+        let other = app.oneshot(create(&key, "{\"a\":1}")).await.unwrap();
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
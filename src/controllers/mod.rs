@@ -1,8 +1,17 @@
+use crate::error::ApiError;
+use auth::Claims;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize};
 
+pub mod audit;
 pub mod auth;
+pub mod cache;
 pub mod cars;
+pub mod conditional;
+pub mod files;
+pub mod idempotency;
 pub mod parts;
+pub mod resumable;
 pub mod users;
 pub mod utils;
 
@@ -41,10 +50,59 @@ pub struct Pagination {
 }
 
 #[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct CommonQuery {
     #[serde(deserialize_with = "deserialize_string_to_array")]
     #[serde(default = "default_ids")]
     pub ids: Vec<i32>,
+    // only rows modified at or after this instant (RFC 3339)
+    pub updated_after: Option<DateTime<Utc>>,
+    // only rows modified strictly before this instant (RFC 3339)
+    pub updated_before: Option<DateTime<Utc>>,
+    // also return soft-deleted rows, admins only
+    #[serde(default, rename = "include_deleted")]
+    pub include_deleted: bool,
+}
+
+impl Pagination {
+    // Fills in the defaults the repositories apply, so that requests for the same page compare
+    // equal
+    pub fn normalized(&self) -> Self {
+        Self {
+            page: Some(self.page.unwrap_or(1)),
+            per_page: Some(self.per_page.unwrap_or(100)),
+            field: Some(self.field.clone().unwrap_or_else(|| "id".to_string())),
+            order: Some(self.order.as_deref().unwrap_or("ASC").to_uppercase()),
+        }
+    }
+}
+
+impl CommonQuery {
+    // `ids` is a set, its order and duplicates do not change the result
+    pub fn normalized(&self) -> Self {
+        let mut ids = self.ids.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        Self {
+            ids,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct ViewQuery {
+    // also return a soft-deleted row, admins only
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+// soft-deleted rows are only visible to admins
+fn authorize_include_deleted(include_deleted: bool, claims: Option<&Claims>) -> anyhow::Result<()> {
+    if include_deleted {
+        claims.ok_or(ApiError::Forbidden)?.require_admin()?;
+    }
+    Ok(())
 }
 
 fn deserialize_string_to_array<'de, D>(deserializer: D) -> Result<Vec<i32>, D::Error>
@@ -61,6 +119,7 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::router;
     use crate::tests::request;
     use axum::{body::Body, http::StatusCode};
@@ -71,4 +130,31 @@ mod tests {
         let response = request(app, "/api/healthcheck", Body::empty()).await;
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[test]
+    fn test_equivalent_queries_normalize_the_same() {
+        let query = CommonQuery {
+            ids: vec![3, 1, 3],
+            ..Default::default()
+        };
+        let same = CommonQuery {
+            ids: vec![1, 3],
+            ..Default::default()
+        };
+        assert_eq!(query.normalized(), same.normalized());
+
+        let pagination = Pagination {
+            page: None,
+            per_page: Some(100),
+            field: None,
+            order: Some("asc".to_string()),
+        };
+        let same = Pagination {
+            page: Some(1),
+            per_page: None,
+            field: Some("id".to_string()),
+            order: None,
+        };
+        assert_eq!(pagination.normalized(), same.normalized());
+    }
 }
@@ -1,17 +1,25 @@
 use crate::cache::CacheExt;
 use crate::error::{AppError, AppJson};
-use crate::models::part::{NewPart, Part, PartList, PartQuery};
-use crate::repositories::PartRepoExt;
+use crate::models::attachment::{AttachmentOwner, AttachmentSummary, NewAttachment};
+use crate::models::audit::AuditContext;
+use crate::models::part::{NewPart, Part, PartDetails, PartList, PartQuery};
+use crate::repositories::{AttachmentRepoExt, PartRepoExt, UploadRepoExt};
 use crate::router::PARTS_TAG;
 use crate::services;
+use crate::storage::StorageExt;
 use axum::{
     Json,
     extract::{Extension, Path},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use axum_extra::extract::Query;
+use uuid::Uuid;
 
 use super::auth::Claims;
-use super::{CommonQuery, Pagination};
+use super::conditional::{ConditionalGet, Validators, etag, if_match_versions};
+use super::files;
+use super::{CommonQuery, Pagination, ViewQuery, authorize_include_deleted};
 
 /// List Parts
 ///
@@ -22,25 +30,40 @@ use super::{CommonQuery, Pagination};
     params(
         ("name" = inline(Option<String>), Query, description="Part Name"),
         ("ids" = inline(Option<String>), Query, description="ids"),
+        ("updatedAfter" = inline(Option<String>), Query, description="Modified at or after (RFC 3339)"),
+        ("updatedBefore" = inline(Option<String>), Query, description="Modified before (RFC 3339)"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
         ("page" = inline(Option<usize>), Query, description="Page"),
         ("perPage" = inline(Option<usize>), Query, description="PerPage"),
         ("field" = inline(Option<String>), Query, description="Field"),
         ("order" = inline(Option<String>), Query, description="Order")
     ) ,
-    responses((status = OK, body = PartList)),
+    responses(
+        (status = OK, body = PartList, headers(
+            ("ETag" = String, description = "Hash of the page"),
+            ("Cache-Control" = String, description = "As configured")
+        )),
+        (status = 304, description = "Matches the If-None-Match ETag")
+    ),
     tag = PARTS_TAG
 )]
 pub async fn list(
+    claims: Option<Claims>,
     Query(conditions): Query<PartQuery>,
     Query(query): Query<CommonQuery>,
     Query(pagination): Query<Pagination>,
     Extension(repo): PartRepoExt,
-) -> Result<AppJson<PartList>, AppError> {
+    Extension(cache): CacheExt,
+    conditional: ConditionalGet,
+) -> Result<Response, AppError> {
     println!("list params: {:?}", pagination);
     println!("conditions: {:?}", conditions);
     println!("ids: {:?}", query);
-    let parts = services::parts::find_all(repo.clone(), &conditions, &query, &pagination).await?;
-    Ok(AppJson(parts))
+    authorize_include_deleted(query.include_deleted, claims.as_ref())?;
+    let parts =
+        services::parts::find_all(repo.clone(), cache, &conditions, &query, &pagination).await?;
+    let validators = Validators::content(&parts)?;
+    Ok(conditional.respond(validators, parts))
 }
 
 /// Get single Part by id
@@ -49,18 +72,41 @@ pub async fn list(
 #[utoipa::path(
     get,
     path = "/{part_id}",
-    params(("part_id" = i32, Path, description="Part Id")),
-    responses((status = OK, body = Part)),
+    params(
+        ("part_id" = i32, Path, description="Part Id"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
+    ),
+    responses(
+        (status = OK, body = PartDetails, headers(
+            ("ETag" = String, description = "Current version of the part"),
+            ("Last-Modified" = String, description = "When the part last changed"),
+            ("Cache-Control" = String, description = "As configured")
+        )),
+        (status = 304, description = "Matches If-None-Match, or unchanged since If-Modified-Since")
+    ),
     tag = PARTS_TAG
 )]
 pub async fn view(
-    _claims: Claims,
+    claims: Claims,
     Path(part_id): Path<i32>,
+    Query(view_query): Query<ViewQuery>,
     Extension(repo): PartRepoExt,
+    Extension(attachments): AttachmentRepoExt,
     Extension(cache): CacheExt,
-) -> Result<AppJson<Part>, AppError> {
-    let part = services::parts::view(repo.clone(), cache.clone(), part_id).await?;
-    Ok(AppJson(part))
+    conditional: ConditionalGet,
+) -> Result<impl IntoResponse, AppError> {
+    authorize_include_deleted(view_query.include_deleted, Some(&claims))?;
+    let part = services::parts::view(
+        repo.clone(),
+        cache.clone(),
+        part_id,
+        view_query.include_deleted,
+    )
+    .await?;
+    let owner = AttachmentOwner::Part(part.id);
+    let attachments = services::attachments::find_all(attachments, owner).await?;
+    let validators = Validators::row(part.version, part.updated_at);
+    Ok(conditional.respond(validators, PartDetails { part, attachments }))
 }
 
 /// Create new Part
@@ -73,17 +119,21 @@ pub async fn view(
         security(
             ("bearerAuth" = [])
         ),
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key and body get the first response back instead of creating another part")),
         request_body(content=NewPart, content_type="application/json", description="New Part Information"),
         responses(
-            (status = 201, description = "Part item created successfully", body = Part)
+            (status = 201, description = "Part item created successfully", body = Part),
+            (status = 409, description = "A request with this Idempotency-Key is still being processed"),
+            (status = 422, description = "The Idempotency-Key was used with a different body")
         )
 )]
 pub async fn create(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): PartRepoExt,
+    Extension(cache): CacheExt,
     Json(new_part): Json<NewPart>,
 ) -> Result<AppJson<Part>, AppError> {
-    let part = services::parts::create(repo.clone(), &new_part).await?;
+    let part = services::parts::create(repo.clone(), cache, &new_part, &ctx).await?;
     Ok(AppJson(part))
 }
 
@@ -97,19 +147,25 @@ pub async fn create(
         security(
             ("bearerAuth" = [])
         ),
+        params(("If-Match" = String, Header, description = "ETag of the part being updated")),
         request_body(content=Part, content_type="application/json", description="Part To Update"),
         responses(
-            (status = 200, description = "Part item updated successfully", body = Part)
+            (status = 200, description = "Part item updated successfully", body = Part,
+                headers(("ETag" = String, description = "New version of the part"))),
+            (status = 412, description = "Part was modified since the given ETag"),
+            (status = 428, description = "If-Match header missing")
         )
 )]
 pub async fn update(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): PartRepoExt,
     Extension(cache): CacheExt,
+    headers: HeaderMap,
     Json(part): Json<Part>,
-) -> Result<AppJson<Part>, AppError> {
-    let part = services::parts::update(repo.clone(), cache, &part).await?;
-    Ok(AppJson(part))
+) -> Result<impl IntoResponse, AppError> {
+    let versions = if_match_versions(&headers)?;
+    let part = services::parts::update(repo.clone(), cache, &part, &versions, &ctx).await?;
+    Ok((etag(part.version), AppJson(part)))
 }
 
 /// Delete existing Part
@@ -118,21 +174,199 @@ pub async fn update(
 #[utoipa::path(
         delete,
         path = "/delete/{part_id}",
-        params(("part_id" = i32, Path, description="Part Id")),
+        params(
+            ("part_id" = i32, Path, description="Part Id"),
+            ("If-Match" = String, Header, description = "ETag of the part being deleted")
+        ),
         tag = PARTS_TAG,
         security(
             ("bearerAuth" = [])
         ),
         responses(
-            (status = 200, description = "Part item deleted successfully", body = String)
+            (status = 200, description = "Part item deleted successfully", body = String),
+            (status = 412, description = "Part was modified since the given ETag"),
+            (status = 428, description = "If-Match header missing")
         )
 )]
 pub async fn delete(
+    ctx: AuditContext,
+    Path(part_id): Path<i32>,
+    Extension(repo): PartRepoExt,
+    Extension(cache): CacheExt,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    let versions = if_match_versions(&headers)?;
+    services::parts::delete(repo.clone(), cache, part_id, &versions, &ctx).await?;
+    Ok(())
+}
+
+/// Restore deleted Part
+///
+/// Tries to bring back a soft-deleted Part. Admins only.
+#[utoipa::path(
+        post,
+        path = "/restore/{part_id}",
+        params(("part_id" = i32, Path, description="Part Id")),
+        security(
+            ("bearerAuth" = [])
+        ),
+        tag = PARTS_TAG,
+        responses(
+            (status = 200, description = "Part item restored successfully", body = String),
+            (status = 403, description = "Not an admin"),
+            (status = 404, description = "No deleted part with this id")
+        )
+)]
+pub async fn restore(
+    claims: Claims,
+    ctx: AuditContext,
+    Path(part_id): Path<i32>,
+    Extension(repo): PartRepoExt,
+    Extension(cache): CacheExt,
+) -> Result<(), AppError> {
+    claims.require_admin()?;
+    services::parts::restore(repo.clone(), cache, part_id, &ctx).await?;
+    Ok(())
+}
+
+/// List attachments of a Part
+///
+/// Files attached to the part, oldest first.
+#[utoipa::path(
+    get,
+    path = "/{part_id}/attachments",
+    params(("part_id" = i32, Path, description="Part Id")),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = OK, body = [AttachmentSummary]),
+        (status = 404, description = "No such part")
+    ),
+    tag = PARTS_TAG
+)]
+pub async fn attachments(
+    // parts are only shown to logged in users, like in `view`
     _claims: Claims,
     Path(part_id): Path<i32>,
     Extension(repo): PartRepoExt,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(cache): CacheExt,
+) -> Result<AppJson<Vec<AttachmentSummary>>, AppError> {
+    services::parts::view(repo, cache, part_id, false).await?;
+    let owner = AttachmentOwner::Part(part_id);
+    let attachments = services::attachments::find_all(attachments, owner).await?;
+    Ok(AppJson(attachments))
+}
+
+/// Download an attachment of a Part
+///
+/// Sends the attached file like `/api/files/{id}` does, to anyone who can see the part.
+#[utoipa::path(
+    get,
+    path = "/{part_id}/attachments/{upload_id}",
+    params(
+        ("part_id" = i32, Path, description="Part Id"),
+        ("upload_id" = Uuid, Path, description="File Id"),
+        ("Range" = Option<String>, Header, description = "e.g. `bytes=0-1023`"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = 200, description = "The whole file", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream"),
+        (status = 404, description = "No such part, or the file is not attached to it"),
+        (status = 416, description = "The range lies outside the file"),
+    ),
+    tag = PARTS_TAG
+)]
+pub async fn attachment(
+    // parts are only shown to logged in users, like in `view`
+    _claims: Claims,
+    Path((part_id, upload_id)): Path<(i32, Uuid)>,
+    Extension(repo): PartRepoExt,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(cache): CacheExt,
+    Extension(storage): StorageExt,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    services::parts::view(repo, cache, part_id, false).await?;
+    let owner = AttachmentOwner::Part(part_id);
+    let upload = services::attachments::find_upload(attachments, owner, upload_id).await?;
+    files::send(storage.as_ref(), &upload, &headers).await
+}
+
+/// Attach a file to a Part
+///
+/// Attaches one of the caller's uploads, see `/api/files/upload`. A file can be attached to a
+/// single car or part, and counts as a change to the part: its version is bumped.
+#[utoipa::path(
+    post,
+    path = "/{part_id}/attachments",
+    params(("part_id" = i32, Path, description="Part Id")),
+    security(
+        ("bearerAuth" = [])
+    ),
+    request_body(content=NewAttachment, content_type="application/json", description="File to attach"),
+    responses(
+        (status = 201, description = "File attached successfully", body = AttachmentSummary),
+        (status = 404, description = "No such part, or the file is not the caller's"),
+        (status = 409, description = "The file is already attached")
+    ),
+    tag = PARTS_TAG
+)]
+pub async fn attach(
+    claims: Claims,
+    ctx: AuditContext,
+    Path(part_id): Path<i32>,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(uploads): UploadRepoExt,
+    Extension(cache): CacheExt,
+    Json(new_attachment): Json<NewAttachment>,
+) -> Result<(StatusCode, AppJson<AttachmentSummary>), AppError> {
+    let upload = services::uploads::view(
+        uploads,
+        new_attachment.upload_id,
+        &claims.sub,
+        claims.is_admin(),
+    )
+    .await?;
+    let owner = AttachmentOwner::Part(part_id);
+    let attachment =
+        services::attachments::attach(attachments, cache, owner, &upload, &ctx).await?;
+    Ok((StatusCode::CREATED, AppJson(attachment)))
+}
+
+/// Detach a file from a Part
+///
+/// The file itself is kept. Only whoever uploaded it, or an admin, can detach it.
+#[utoipa::path(
+    delete,
+    path = "/{part_id}/attachments/{upload_id}",
+    params(
+        ("part_id" = i32, Path, description="Part Id"),
+        ("upload_id" = Uuid, Path, description="File Id"),
+    ),
+    security(
+        ("bearerAuth" = [])
+    ),
+    responses(
+        (status = 200, description = "File detached successfully"),
+        (status = 404, description = "The file is not attached to the part, or not the caller's")
+    ),
+    tag = PARTS_TAG
+)]
+pub async fn detach(
+    claims: Claims,
+    ctx: AuditContext,
+    Path((part_id, upload_id)): Path<(i32, Uuid)>,
+    Extension(attachments): AttachmentRepoExt,
+    Extension(uploads): UploadRepoExt,
     Extension(cache): CacheExt,
 ) -> Result<(), AppError> {
-    services::parts::delete(repo.clone(), cache, part_id).await?;
+    services::uploads::view(uploads, upload_id, &claims.sub, claims.is_admin()).await?;
+    let owner = AttachmentOwner::Part(part_id);
+    services::attachments::detach(attachments, cache, owner, upload_id, &ctx).await?;
     Ok(())
 }
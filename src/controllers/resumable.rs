@@ -0,0 +1,356 @@
+use crate::cache::CacheExt;
+use crate::controllers::auth::Claims;
+use crate::error::{ApiError, AppError, AppJson};
+use crate::models::resumable::{Appended, Chunk};
+use crate::models::upload::{FileMeta, Upload};
+use crate::repositories::{ResumableRepoExt, UploadRepoExt};
+use crate::router::FILES_TAG;
+use crate::services;
+use crate::services::uploads::UploadSettingsExt;
+use crate::storage::StorageExt;
+use anyhow::Result;
+use axum::{
+    extract::{Extension, Path, Request},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION},
+    },
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// tus 1.0.0 (https://tus.io/protocols/resumable-upload) with the creation, expiration, checksum
+// and termination extensions. OPTIONS requests are all answered by the CORS layer, so the
+// capabilities are documented on `create` instead of being discoverable.
+const TUS_VERSION: &str = "1.0.0";
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+pub const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+pub const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+pub const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_CHECKSUM: HeaderName = HeaderName::from_static("upload-checksum");
+
+/// Start a resumable upload
+///
+/// Announces a file of `Upload-Length` bytes that is then sent in chunks with PATCH requests
+/// to the returned `Location`, so that a broken connection only loses the chunk in flight.
+/// `Upload-Metadata` holds comma separated `key base64(value)` pairs, of which `filename`,
+/// `filetype` and `description` are used. An upload that receives no chunk for
+/// `RESUMABLE_UPLOAD_TTL_SECS` expires. Follows tus 1.0.0 with the creation, expiration,
+/// checksum (sha256) and termination extensions.
+#[utoipa::path(
+    post,
+    path = "/resumable",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("Upload-Length" = u64, Header, description = "Size of the whole file in bytes"),
+        ("Upload-Metadata" = Option<String>, Header, description = "e.g. `filename bm90ZXMudHh0,filetype dGV4dC9wbGFpbg==`"),
+    ),
+    responses(
+        (status = 201, description = "Upload started",
+            headers(
+                ("Location" = String, description = "Where to send the chunks"),
+                ("Upload-Expires" = String),
+            )),
+        (status = 400, description = "Missing or malformed Upload-Length or Upload-Metadata"),
+        (status = 413, description = "The file is larger than the upload limit or what is left of the caller's storage quota"),
+        (status = 415, description = "Files of the declared type are not accepted"),
+        (status = 503, description = "Uploads cannot be tracked right now"),
+    )
+)]
+pub async fn create(
+    claims: Claims,
+    Extension(repo): ResumableRepoExt,
+    Extension(uploads): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let length = number(&headers, &UPLOAD_LENGTH)?;
+    let metadata = headers
+        .get(&UPLOAD_METADATA)
+        .map(|value| value.to_str().map_err(|_| bad_header(&UPLOAD_METADATA)))
+        .transpose()?;
+    let meta = file_meta(metadata.unwrap_or_default())?;
+    let upload =
+        services::resumable::create(repo, uploads, &settings, &claims.sub, length, meta).await?;
+    let headers = [
+        (TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION)),
+        (
+            LOCATION,
+            header_value(format!("/api/files/resumable/{}", upload.id)),
+        ),
+        (UPLOAD_EXPIRES, header_value(http_date(upload.expires_at))),
+    ];
+    Ok((StatusCode::CREATED, headers).into_response())
+}
+
+/// Resumable upload status
+///
+/// How many bytes of the upload were received, i.e. the offset to send the next chunk from.
+/// Only the caller who started it can see it.
+#[utoipa::path(
+    head,
+    path = "/resumable/{id}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "Upload id, from `Location`"),
+    ),
+    responses(
+        (status = 200, description = "The upload's progress",
+            headers(
+                ("Upload-Offset" = u64),
+                ("Upload-Length" = u64),
+                ("Upload-Expires" = String),
+            )),
+        (status = 404, description = "No such upload, expired, completed or not the caller's"),
+        (status = 503, description = "Uploads cannot be tracked right now"),
+    )
+)]
+pub async fn status(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): ResumableRepoExt,
+) -> Result<Response, AppError> {
+    let upload = services::resumable::view(repo, id, &claims.sub).await?;
+    let headers = [
+        (TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION)),
+        (UPLOAD_OFFSET, HeaderValue::from(upload.offset)),
+        (UPLOAD_LENGTH, HeaderValue::from(upload.length)),
+        (UPLOAD_EXPIRES, header_value(http_date(upload.expires_at))),
+        (CACHE_CONTROL, HeaderValue::from_static("no-store")),
+    ];
+    Ok(headers.into_response())
+}
+
+/// Send a chunk of a resumable upload
+///
+/// Appends the body at `Upload-Offset`, which has to be the upload's current offset. With
+/// `Upload-Checksum: sha256 <base64 digest>` a chunk that arrives damaged is dropped with 460.
+/// A chunk that breaks off is dropped as well: ask for the offset with HEAD and resend from
+/// there. The last chunk completes the upload, which is then checked like any other file and
+/// returned. Should completing it fail with 503, resend an empty chunk at the end.
+#[utoipa::path(
+    patch,
+    path = "/resumable/{id}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "Upload id, from `Location`"),
+        ("Upload-Offset" = u64, Header, description = "Where the chunk starts"),
+        ("Upload-Checksum" = Option<String>, Header, description = "e.g. `sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=`"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream", description = "The chunk"),
+    responses(
+        (status = 200, description = "The last chunk, the file is stored", body = Upload),
+        (status = 204, description = "Chunk stored", headers(("Upload-Offset" = u64))),
+        (status = 400, description = "Missing or malformed headers, or the chunk goes past Upload-Length"),
+        (status = 404, description = "No such upload, expired or not the caller's"),
+        (status = 409, description = "Upload-Offset is not the upload's offset"),
+        (status = 413, description = "The file is larger than the upload limit or what is left of the caller's storage quota"),
+        (status = 415, description = "Not `application/offset+octet-stream`, or the completed file is not of an accepted type"),
+        (status = 460, description = "The chunk does not match Upload-Checksum"),
+        (status = 503, description = "Uploads cannot be tracked right now"),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn append(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): ResumableRepoExt,
+    Extension(uploads): UploadRepoExt,
+    Extension(storage): StorageExt,
+    Extension(cache): CacheExt,
+    Extension(settings): UploadSettingsExt,
+    request: Request,
+) -> Result<Response, AppError> {
+    let headers = request.headers();
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some(CHUNK_CONTENT_TYPE) {
+        return Err(ApiError::UnsupportedMediaType(format!(
+            "Chunks have to be sent as {CHUNK_CONTENT_TYPE}"
+        ))
+        .into());
+    }
+    let chunk = Chunk {
+        offset: number(headers, &UPLOAD_OFFSET)?,
+        checksum: headers
+            .get(&UPLOAD_CHECKSUM)
+            .map(|value| checksum(value.to_str().unwrap_or_default()))
+            .transpose()?,
+    };
+    let upload = services::resumable::view(repo.clone(), id, &claims.sub).await?;
+    let appended = services::resumable::append(
+        repo,
+        uploads.clone(),
+        storage.as_ref(),
+        &settings,
+        upload,
+        &chunk,
+        request.into_body().into_data_stream(),
+    )
+    .await?;
+    let tus_resumable = (TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    match appended {
+        Appended::Partial(upload) => {
+            let headers = [
+                tus_resumable,
+                (UPLOAD_OFFSET, HeaderValue::from(upload.offset)),
+                (UPLOAD_EXPIRES, header_value(http_date(upload.expires_at))),
+            ];
+            Ok((StatusCode::NO_CONTENT, headers).into_response())
+        }
+        Appended::Complete(upload) => {
+            let headers = [
+                tus_resumable,
+                (UPLOAD_OFFSET, HeaderValue::from(upload.size)),
+            ];
+            let sizes = settings.thumbnail_sizes.clone();
+            services::thumbnails::spawn(uploads, storage, cache, sizes, upload.clone());
+            Ok((headers, AppJson(upload)).into_response())
+        }
+    }
+}
+
+/// Cancel a resumable upload
+///
+/// Discards the upload and the chunks received so far.
+#[utoipa::path(
+    delete,
+    path = "/resumable/{id}",
+    tag = FILES_TAG,
+    security(
+        ("bearerAuth" = [])
+    ),
+    params(
+        ("id" = Uuid, Path, description = "Upload id, from `Location`"),
+    ),
+    responses(
+        (status = 204, description = "Upload cancelled"),
+        (status = 404, description = "No such upload, expired, completed or not the caller's"),
+        (status = 503, description = "Uploads cannot be tracked right now"),
+    )
+)]
+pub async fn terminate(
+    claims: Claims,
+    Path(id): Path<Uuid>,
+    Extension(repo): ResumableRepoExt,
+    Extension(storage): StorageExt,
+) -> Result<Response, AppError> {
+    let upload = services::resumable::view(repo.clone(), id, &claims.sub).await?;
+    services::resumable::terminate(repo, storage.as_ref(), &upload).await?;
+    let headers = [(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION))];
+    Ok((StatusCode::NO_CONTENT, headers).into_response())
+}
+
+fn number(headers: &HeaderMap, name: &HeaderName) -> Result<u64, ApiError> {
+    let value = headers
+        .get(name)
+        .ok_or_else(|| ApiError::BadRequest(format!("{name} is missing")))?;
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| bad_header(name))
+}
+
+// `Upload-Metadata`: comma separated pairs of a key and its base64 encoded value, which tus
+// allows to be left out
+fn file_meta(metadata: &str) -> Result<FileMeta, ApiError> {
+    let mut pairs = HashMap::new();
+    for pair in metadata
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = STANDARD
+            .decode(value.trim())
+            .ok()
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or_else(|| bad_header(&UPLOAD_METADATA))?;
+        pairs.insert(key, value);
+    }
+    Ok(FileMeta {
+        name: pairs.remove("filename").unwrap_or_default(),
+        content_type: pairs
+            .remove("filetype")
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+        description: pairs
+            .remove("description")
+            .filter(|value| !value.is_empty()),
+    })
+}
+
+// `Upload-Checksum`: the algorithm and the base64 encoded digest
+fn checksum(value: &str) -> Result<Vec<u8>, ApiError> {
+    match value.split_once(' ') {
+        Some(("sha256", digest)) => STANDARD
+            .decode(digest.trim())
+            .ok()
+            .filter(|digest| digest.len() == 32)
+            .ok_or_else(|| bad_header(&UPLOAD_CHECKSUM)),
+        Some((algorithm, _)) => Err(ApiError::BadRequest(format!(
+            "Checksums with {algorithm} are not supported, use sha256"
+        ))),
+        None => Err(bad_header(&UPLOAD_CHECKSUM)),
+    }
+}
+
+fn bad_header(name: &HeaderName) -> ApiError {
+    ApiError::BadRequest(format!("{name} is malformed"))
+}
+
+fn header_value(value: String) -> HeaderValue {
+    HeaderValue::try_from(value).expect("valid header value")
+}
+
+fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_meta() {
+        let meta = file_meta("filename bm90ZXMudHh0, filetype dGV4dC9wbGFpbg==,flag").unwrap();
+        assert_eq!(meta.name, "notes.txt");
+        assert_eq!(meta.content_type, "text/plain");
+        assert_eq!(meta.description, None);
+        let meta = file_meta("").unwrap();
+        assert_eq!(meta.content_type, "application/octet-stream");
+        assert!(file_meta("filename not base64!").is_err());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let digest = checksum("sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=").unwrap();
+        assert_eq!(digest.len(), 32);
+        assert!(checksum("sha256 aGVsbG8=").is_err());
+        assert!(checksum("md5 XUFAKrxLKna5cZ2REBfFkg==").is_err());
+        assert!(checksum("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=").is_err());
+    }
+
+    #[test]
+    fn test_http_date() {
+        let at = DateTime::from_timestamp(1735787045, 0).unwrap();
+        assert_eq!(http_date(at), "Thu, 02 Jan 2025 03:04:05 GMT");
+    }
+}
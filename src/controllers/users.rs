@@ -1,4 +1,6 @@
+use crate::cache::CacheExt;
 use crate::error::{AppError, AppJson};
+use crate::models::audit::AuditContext;
 use crate::models::user::{User, UserAuth, UserList, UserQuery};
 use crate::repositories::UserRepoExt;
 use crate::router::USERS_TAG;
@@ -10,7 +12,7 @@ use axum::{
 use axum_extra::extract::Query;
 
 use super::auth::Claims;
-use super::{CommonQuery, Pagination};
+use super::{CommonQuery, Pagination, ViewQuery, authorize_include_deleted};
 
 /// List Users
 ///
@@ -21,6 +23,9 @@ use super::{CommonQuery, Pagination};
     params(
         ("name" = inline(Option<String>), Query, description="User Name"),
         ("ids" = inline(Option<String>), Query, description="ids"),
+        ("updatedAfter" = inline(Option<String>), Query, description="Modified at or after (RFC 3339)"),
+        ("updatedBefore" = inline(Option<String>), Query, description="Modified before (RFC 3339)"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
         ("page" = inline(Option<usize>), Query, description="Page"),
         ("perPage" = inline(Option<usize>), Query, description="PerPage"),
         ("field" = inline(Option<String>), Query, description="Field"),
@@ -30,6 +35,7 @@ use super::{CommonQuery, Pagination};
     tag = USERS_TAG
 )]
 pub async fn list(
+    claims: Option<Claims>,
     Query(conditions): Query<UserQuery>,
     Query(query): Query<CommonQuery>,
     Query(pagination): Query<Pagination>,
@@ -38,6 +44,7 @@ pub async fn list(
     println!("list params: {:?}", pagination);
     println!("conditions: {:?}", conditions);
     println!("ids: {:?}", query);
+    authorize_include_deleted(query.include_deleted, claims.as_ref())?;
     let users = services::users::find_all(repo.clone(), &conditions, &query, &pagination).await?;
     Ok(AppJson(users))
 }
@@ -48,7 +55,10 @@ pub async fn list(
 #[utoipa::path(
     get,
     path = "/{username}",
-    params(("username" =&str, Path, description="User Id")),
+    params(
+        ("username" =&str, Path, description="User Id"),
+        ("include_deleted" = inline(Option<bool>), Query, description="Include soft-deleted rows (admins only)"),
+    ),
     responses((status = OK, body = User)),
     security(
         ("bearerAuth" = [])
@@ -56,11 +66,15 @@ pub async fn list(
     tag = USERS_TAG
 )]
 pub async fn view(
-    _claims: Claims,
+    claims: Claims,
     Path(username): Path<String>,
+    Query(view_query): Query<ViewQuery>,
     Extension(repo): UserRepoExt,
+    Extension(cache): CacheExt,
 ) -> Result<AppJson<User>, AppError> {
-    let user = services::users::view(repo.clone(), &username).await?;
+    authorize_include_deleted(view_query.include_deleted, Some(&claims))?;
+    let user =
+        services::users::view(repo.clone(), cache, &username, view_query.include_deleted).await?;
     Ok(AppJson(user))
 }
 
@@ -80,11 +94,11 @@ pub async fn view(
         )
 )]
 pub async fn create(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): UserRepoExt,
     Json(new_user): Json<UserAuth>,
 ) -> Result<AppJson<User>, AppError> {
-    let user = services::users::create(repo.clone(), &new_user).await?;
+    let user = services::users::create(repo.clone(), &new_user, &ctx).await?;
     Ok(AppJson(user))
 }
 
@@ -104,11 +118,12 @@ pub async fn create(
         )
 )]
 pub async fn update(
-    _claims: Claims,
+    ctx: AuditContext,
     Extension(repo): UserRepoExt,
+    Extension(cache): CacheExt,
     Json(user): Json<UserAuth>,
 ) -> Result<AppJson<User>, AppError> {
-    let user = services::users::update(repo.clone(), &user).await?;
+    let user = services::users::update(repo.clone(), cache, &user, &ctx).await?;
     Ok(AppJson(user))
 }
 
@@ -128,11 +143,40 @@ pub async fn update(
         )
 )]
 pub async fn delete(
-    _claims: Claims,
+    ctx: AuditContext,
     Path(username): Path<String>,
     Extension(repo): UserRepoExt,
+    Extension(cache): CacheExt,
 ) -> Result<(), AppError> {
-    services::users::delete(repo.clone(), &username).await?;
+    services::users::delete(repo.clone(), cache, &username, &ctx).await?;
+    Ok(())
+}
+
+/// Restore deleted User
+///
+/// Tries to bring back a soft-deleted User. Admins only.
+#[utoipa::path(
+        post,
+        path = "/restore/{username}",
+        params(("username" = String, Path, description="User Id")),
+        security(
+            ("bearerAuth" = [])
+        ),
+        tag = USERS_TAG,
+        responses(
+            (status = 200, description = "User item restored successfully", body = String),
+            (status = 403, description = "Not an admin"),
+            (status = 404, description = "No deleted user with this username")
+        )
+)]
+pub async fn restore(
+    claims: Claims,
+    ctx: AuditContext,
+    Path(username): Path<String>,
+    Extension(repo): UserRepoExt,
+) -> Result<(), AppError> {
+    claims.require_admin()?;
+    services::users::restore(repo.clone(), &username, &ctx).await?;
     Ok(())
 }
 
@@ -143,6 +187,7 @@ pub async fn delete(
 mod tests {
     use crate::config::Config;
     use crate::controllers::users;
+    use crate::models::audit::AuditContext;
     use crate::models::user::{UserAuth, UserList};
     use crate::repositories::user::UserRepository;
     use crate::repositories::{clear_database, create_user_repository, run_migrations};
@@ -172,7 +217,10 @@ mod tests {
             username: "Tesla".to_string(),
             password: "Red".to_string(),
         };
-        real_repo.create(&user).await.unwrap();
+        real_repo
+            .create(&user, &AuditContext::system())
+            .await
+            .unwrap();
 
         // Create an Axum router with the mock repository as an extension
         let app = Router::new()
@@ -204,6 +252,6 @@ mod tests {
         let users: UserList =
             serde_json::from_slice(&response_body).expect("Failed to deserialize response");
         assert_eq!(users.data[0].username, "Tesla");
-        assert_eq!(users.data[0].password_hash, "Red".to_string());
+        assert!(users.data[0].password_hash.is_empty());
     }
 }
@@ -1,14 +1,18 @@
+use crate::cache::CacheExt;
+use crate::controllers::auth::Claims;
 use crate::error::{AppError, AppJson};
+use crate::models::upload::{FileMeta, Upload};
+use crate::repositories::UploadRepoExt;
+use crate::services;
+use crate::services::uploads::UploadSettingsExt;
+use crate::storage::StorageExt;
 use axum::{
-    BoxError,
-    body::Bytes,
-    extract::{Path, Request},
-    http::StatusCode,
+    extract::{Extension, Path, Request},
+    http::{
+        StatusCode,
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+    },
 };
-use futures::{Stream, TryStreamExt};
-use std::io;
-use tokio::{fs::File, io::BufWriter};
-use tokio_util::io::StreamReader;
 
 /// Healthcheck endpoint
 ///
@@ -22,70 +26,63 @@ use tokio_util::io::StreamReader;
 pub async fn healthcheck() -> Result<AppJson<String>, AppError> {
     Ok(AppJson("ok".into()))
 }
-/// Handler that streams the request body to a file.
+/// Upload a file
 ///
-/// POST'ing to `/upload/foo.txt` will create a file called `foo.txt`.
-/// For example: curl -i -X POST http://localhost:3000/api/upload/README.md --data-binary "@README.md"
+/// Streams the request body to storage under a generated id and records it for the caller.
+/// `file_name` is only kept, sanitized, as the file's display name. The type is told from the
+/// content and has to be one of `UPLOAD_BODY_ALLOWED_TYPES`.
+/// For example: curl -i -X POST http://localhost:3000/api/upload/README.md -H "Authorization: Bearer $TOKEN" -H "Content-Type: text/markdown" --data-binary "@README.md"
 #[utoipa::path(
     post,
     path = "/upload/{file_name}",
     tag = "Utils",
+    security(
+        ("bearerAuth" = [])
+    ),
     params(
-        ("file_name" = String, Path, description = "file name to uploaded")
+        ("file_name" = String, Path, description = "name of the uploaded file")
     ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "file content"),
     responses(
-        (status = 201, description = "pdf upload successfully.", body = String),
-        (status = 400, description = "pdf upload error", body = String),
+        (status = 201, description = "File uploaded successfully", body = Upload),
+        (status = 413, description = "File larger than the upload limit or what is left of the caller's storage quota"),
+        (status = 415, description = "File not of its declared type, or of a type not accepted"),
     )
 )]
 pub async fn save_request_body(
+    claims: Claims,
+    Extension(repo): UploadRepoExt,
+    Extension(settings): UploadSettingsExt,
+    Extension(storage): StorageExt,
+    Extension(cache): CacheExt,
     Path(file_name): Path<String>,
     request: Request,
-) -> Result<(), (StatusCode, String)> {
-    stream_to_file(&file_name, request.into_body().into_data_stream()).await
-}
-
-// Save a `Stream` to a file
-async fn stream_to_file<S, E>(path: &str, stream: S) -> Result<(), (StatusCode, String)>
-where
-    S: Stream<Item = Result<Bytes, E>>,
-    E: Into<BoxError>,
-{
-    if !path_is_valid(path) {
-        return Err((StatusCode::BAD_REQUEST, "Invalid path".to_owned()));
-    }
-
-    async {
-        // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = stream.map_err(|err| io::Error::other(err));
-        let body_reader = StreamReader::new(body_with_io_error);
-        futures::pin_mut!(body_reader);
-
-        let upload = "uploads".to_string();
-        // Create the file. `File` implements `AsyncWrite`.
-        let path = std::path::Path::new(&upload).join(path);
-        let mut file = BufWriter::new(File::create(path).await?);
-
-        // Copy the body into the file.
-        tokio::io::copy(&mut body_reader, &mut file).await?;
-
-        Ok::<_, io::Error>(())
-    }
-    .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
-}
-
-// to prevent directory traversal attacks we ensure the path consists of exactly one normal
-// component
-fn path_is_valid(path: &str) -> bool {
-    let path = std::path::Path::new(path);
-    let mut components = path.components().peekable();
-
-    if let Some(first) = components.peek() {
-        if !matches!(first, std::path::Component::Normal(_)) {
-            return false;
-        }
-    }
-
-    components.count() == 1
+) -> Result<(StatusCode, AppJson<Upload>), AppError> {
+    let headers = request.headers();
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    settings.check_length(content_length)?;
+    let meta = FileMeta {
+        name: file_name,
+        content_type: headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        description: None,
+    };
+    let upload = services::uploads::upload(
+        repo.clone(),
+        storage.as_ref(),
+        &settings,
+        &settings.body_types,
+        &claims.sub,
+        &meta,
+        request.into_body().into_data_stream(),
+    )
+    .await?;
+    let sizes = settings.thumbnail_sizes.clone();
+    services::thumbnails::spawn(repo, storage, cache, sizes, upload.clone());
+    Ok((StatusCode::CREATED, AppJson(upload)))
 }
@@ -2,12 +2,19 @@ use crate::config::Config;
 use bb8_redis::RedisConnectionManager;
 use bb8_redis::bb8::Pool;
 use dotenv::dotenv;
+use redis::RedisResult;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub type Redis = Arc<Pool<RedisConnectionManager>>;
 
-pub async fn redis_connect(config: &Config) -> Pool<RedisConnectionManager> {
+// Only fails on an invalid url. Connections are opened on first use, so a pool is returned even
+// while the server is unreachable.
+pub async fn redis_connect(config: &Config) -> RedisResult<Pool<RedisConnectionManager>> {
     dotenv().ok();
-    let manager = RedisConnectionManager::new(config.cache_url.as_str()).unwrap();
-    Pool::builder().build(manager).await.unwrap()
+    let manager = RedisConnectionManager::new(config.cache_url.as_str())?;
+    let pool = Pool::builder()
+        .connection_timeout(Duration::from_millis(config.cache_timeout_ms))
+        .build_unchecked(manager);
+    Ok(pool)
 }
@@ -27,6 +27,71 @@ where
 // Make our own error that wraps `anyhow::Error`.
 pub struct AppError(anyhow::Error);
 
+// Errors that are the client's fault. Services return them through `anyhow` like any other
+// error and `AppError` picks them back out to choose the status code.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("Not found")]
+    NotFound,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("{0}")]
+    Conflict(String),
+    #[error("The resource was modified, fetch it again and retry")]
+    PreconditionFailed,
+    #[error("If-Match header is required")]
+    PreconditionRequired,
+    #[error("Payloads are limited to {0} bytes")]
+    PayloadTooLarge(u64),
+    #[error("Uploads would exceed the storage quota of {0} bytes")]
+    QuotaExceeded(u64),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("The content does not match Upload-Checksum")]
+    ChecksumMismatch,
+    #[error("Too many requests, retry later")]
+    TooManyRequests,
+    #[error("Idempotency-Key was already used with a different request")]
+    IdempotencyKeyReused,
+    #[error("A request with this Idempotency-Key is still being processed")]
+    IdempotencyKeyInProgress,
+    #[error("Temporarily unavailable, retry later")]
+    ServiceUnavailable,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ApiError::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            // tus' "Checksum Mismatch"
+            ApiError::ChecksumMismatch => StatusCode::from_u16(460).expect("valid status code"),
+            ApiError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::IdempotencyKeyInProgress => StatusCode::CONFLICT,
+            ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+// Missing rows surface either as `ApiError::NotFound` or straight from sqlx
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ApiError>(), Some(ApiError::NotFound))
+        || matches!(
+            err.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::RowNotFound)
+        )
+}
+
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
@@ -36,7 +101,11 @@ impl IntoResponse for AppError {
             message: String,
         }
         let err = self;
-        let (status, message) = {
+        let (status, message) = if let Some(api_err) = err.0.downcast_ref::<ApiError>() {
+            (api_err.status(), api_err.to_string())
+        } else if let Some(sqlx::Error::RowNotFound) = err.0.downcast_ref::<sqlx::Error>() {
+            (StatusCode::NOT_FOUND, ApiError::NotFound.to_string())
+        } else {
             // Because `TraceLayer` wraps each request in a span that contains the request
             // method, uri, etc we don't need to include those details here
             tracing::error!(%err);
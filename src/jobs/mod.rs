@@ -0,0 +1,106 @@
+use crate::cache::CacheImpl;
+use crate::config::Config;
+use crate::models::audit::AuditContext;
+use crate::repositories::{
+    attachment::AttachmentRepositoryImpl, car::CarRepositoryImpl, part::PartRepositoryImpl,
+    resumable::ResumableRepositoryImpl, upload::UploadRepositoryImpl, user::UserRepositoryImpl,
+};
+use crate::services;
+use crate::storage::Storage;
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+// Hard-deletes rows, and the files attached to them, that have been soft-deleted for longer than
+// the configured retention period.
+pub fn spawn_purge(
+    config: &Config,
+    user_repository: Arc<UserRepositoryImpl>,
+    car_repository: Arc<CarRepositoryImpl>,
+    part_repository: Arc<PartRepositoryImpl>,
+    attachment_repository: Arc<AttachmentRepositoryImpl>,
+    storage: Arc<dyn Storage>,
+    cache: Arc<CacheImpl>,
+) {
+    let retention = chrono::Duration::days(config.soft_delete_retention_days);
+    let period = Duration::from_secs(config.purge_interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - retention;
+            let ctx = AuditContext::system();
+
+            // files go first, the attachments pointing at them vanish with their owners
+            if let Err(err) = services::attachments::purge(
+                attachment_repository.clone(),
+                storage.as_ref(),
+                cutoff,
+            )
+            .await
+            {
+                error!(%err, "failed to purge files of deleted cars and parts");
+            }
+            if let Err(err) = services::parts::purge(part_repository.clone(), cutoff, &ctx).await {
+                error!(%err, "failed to purge deleted parts");
+            }
+            if let Err(err) =
+                services::cars::purge(car_repository.clone(), cache.clone(), cutoff, &ctx).await
+            {
+                error!(%err, "failed to purge deleted cars");
+            }
+            if let Err(err) = services::users::purge(user_repository.clone(), cutoff, &ctx).await {
+                error!(%err, "failed to purge deleted users");
+            }
+        }
+    });
+}
+
+// Removes resumable uploads that stopped receiving chunks, along with the chunks they got
+pub fn spawn_resumable_expiry(
+    config: &Config,
+    resumable_repository: Arc<ResumableRepositoryImpl>,
+    storage: Arc<dyn Storage>,
+) {
+    let period = Duration::from_secs(config.purge_interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(err) = services::resumable::expire(
+                resumable_repository.clone(),
+                storage.as_ref(),
+                Utc::now(),
+            )
+            .await
+            {
+                error!(%err, "failed to expire abandoned resumable uploads");
+            }
+        }
+    });
+}
+
+// Removes the content of uploads that no file points at anymore, see `uploads::collect_garbage`
+pub fn spawn_blob_collection(
+    config: &Config,
+    upload_repository: Arc<UploadRepositoryImpl>,
+    storage: Arc<dyn Storage>,
+) {
+    let period = Duration::from_secs(config.purge_interval_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(err) =
+                services::uploads::collect_garbage(upload_repository.clone(), storage.as_ref())
+                    .await
+            {
+                error!(%err, "failed to remove unreferenced blobs");
+            }
+        }
+    });
+}
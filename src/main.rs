@@ -11,11 +11,14 @@ mod config;
 mod controllers;
 mod db;
 mod error;
+mod jobs;
 mod models;
 mod password;
+mod rate_limit;
 mod repositories;
 mod router;
 mod services;
+mod storage;
 #[cfg(test)]
 mod tests;
 
@@ -39,7 +42,11 @@ async fn main() {
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     debug!("listening on {addr}");
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    // the peer address is what anonymous clients are rate limited by
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -0,0 +1,49 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// What an upload can be attached to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentOwner {
+    Car(i32),
+    Part(i32),
+}
+
+impl AttachmentOwner {
+    pub fn car_id(&self) -> Option<i32> {
+        match self {
+            AttachmentOwner::Car(id) => Some(*id),
+            AttachmentOwner::Part(_) => None,
+        }
+    }
+
+    pub fn part_id(&self) -> Option<i32> {
+        match self {
+            AttachmentOwner::Part(id) => Some(*id),
+            AttachmentOwner::Car(_) => None,
+        }
+    }
+}
+
+// An attached file as shown with its car or part
+#[derive(Serialize, Deserialize, FromRow, Debug, ToSchema, Clone, PartialEq)]
+pub struct AttachmentSummary {
+    // of the upload
+    pub id: Uuid,
+    pub original_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub description: Option<String>,
+    // sizes to ask `/api/files/{id}/thumb/{size}` for
+    pub thumbnails: Vec<i32>,
+    pub attached_by: String,
+    pub attached_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct NewAttachment {
+    // an upload of the caller's that is not attached yet
+    pub upload_id: Uuid,
+}
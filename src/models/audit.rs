@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+// Fields whose values must never be copied into the audit log.
+const REDACTED_FIELDS: &[&str] = &["password_hash"];
+
+// Who is making a change, and in which request. Every mutation takes one so that it can be
+// written to the audit log together with the change itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditContext {
+    pub actor: String,
+    pub request_id: Option<String>,
+}
+
+impl AuditContext {
+    // Changes made by background jobs rather than by a user.
+    pub fn system() -> Self {
+        Self {
+            actor: "system".to_string(),
+            request_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+            AuditAction::Restore => "restore",
+            AuditAction::Purge => "purge",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug, ToSchema, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub actor: String,
+    pub entity: String,
+    pub entity_id: i32,
+    pub action: String,
+    // `{"field": {"from": .., "to": ..}}` for every field the action changed
+    pub changes: Value,
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditQuery {
+    pub entity: Option<String>,
+    pub entity_id: Option<i32>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub request_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct AuditList {
+    pub data: Vec<AuditEntry>,
+    pub total: i64,
+}
+
+// Field-by-field difference between two snapshots of a row. A missing snapshot (before a
+// create, after a purge) counts as every field being null.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Value {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+
+    let mut changes = Map::new();
+    for field in before.keys().chain(after.keys()) {
+        let from = before.get(field).unwrap_or(&Value::Null);
+        let to = after.get(field).unwrap_or(&Value::Null);
+        if from == to || changes.contains_key(field) {
+            continue;
+        }
+        let change = if REDACTED_FIELDS.contains(&field.as_str()) {
+            json!({ "from": "[redacted]", "to": "[redacted]" })
+        } else {
+            json!({ "from": from, "to": to })
+        };
+        changes.insert(field.clone(), change);
+    }
+    Value::Object(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_keeps_only_changed_fields() {
+        let before = json!({ "id": 1, "name": "Ferrari", "color": "red" });
+        let after = json!({ "id": 1, "name": "Ferrari", "color": "black" });
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            json!({ "color": { "from": "red", "to": "black" } })
+        );
+    }
+
+    #[test]
+    fn test_diff_of_create_lists_every_field() {
+        let after = json!({ "id": 1, "color": null });
+        assert_eq!(
+            diff(None, Some(&after)),
+            json!({ "id": { "from": null, "to": 1 } })
+        );
+    }
+
+    #[test]
+    fn test_diff_redacts_secrets() {
+        let before = json!({ "password_hash": "old" });
+        let after = json!({ "password_hash": "new" });
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            json!({ "password_hash": { "from": "[redacted]", "to": "[redacted]" } })
+        );
+    }
+}
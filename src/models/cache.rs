@@ -0,0 +1,79 @@
+use crate::cache::Entity;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TierStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    // whether a Redis url is configured at all
+    pub redis_configured: bool,
+    // keys in the Redis database, `None` when it could not be asked
+    pub redis_keys: Option<u64>,
+    pub breaker_open: bool,
+    // Redis calls that failed or timed out, and calls skipped while the breaker was open
+    pub failures: u64,
+    pub bypassed: u64,
+    pub local: TierStats,
+    pub local_entries: usize,
+    pub local_capacity: usize,
+    pub redis: TierStats,
+}
+
+// What both tiers hold for one key
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheEntry {
+    pub key: String,
+    pub local: bool,
+    pub redis: Option<RedisEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RedisEntry {
+    pub value: Value,
+    // served as stale after this, until `expires_in_ms` runs out
+    pub fresh_until: Option<DateTime<Utc>>,
+    pub expires_in_ms: i64,
+}
+
+// Either `prefix`, or `entity` with an optional `id`: without one every entry of the entity goes
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EvictRequest {
+    pub entity: Option<Entity>,
+    pub id: Option<String>,
+    pub prefix: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Evicted {
+    // entries removed from Redis, or from the local tier when running without Redis
+    pub keys: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmRequest {
+    pub entity: Entity,
+    // car and part ids, or usernames
+    pub ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmResult {
+    pub warmed: Vec<String>,
+    // ids with no such (non-deleted) record
+    pub missing: Vec<String>,
+}
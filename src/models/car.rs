@@ -1,3 +1,5 @@
+use crate::models::attachment::AttachmentSummary;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use utoipa::ToSchema;
@@ -10,6 +12,22 @@ pub struct Car {
     pub name: String,
     pub color: Option<String>,
     pub year: Option<i16>,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub updated_at: DateTime<Utc>,
+    #[schema(read_only)]
+    pub created_by: Option<String>,
+    #[schema(read_only)]
+    pub updated_by: Option<String>,
+    #[schema(read_only)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // bumped on every update, sent back as the `ETag` of the row
+    #[serde(default)]
+    #[schema(read_only)]
+    pub version: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema, Validate)]
@@ -30,3 +48,11 @@ pub struct CarList {
     pub data: Vec<Car>,
     pub total: i64,
 }
+
+// A car together with its attachments, as `cars::view` returns it
+#[derive(Serialize, Deserialize, Debug, ToSchema, Clone)]
+pub struct CarDetails {
+    #[serde(flatten)]
+    pub car: Car,
+    pub attachments: Vec<AttachmentSummary>,
+}
@@ -1,3 +1,8 @@
+pub mod attachment;
+pub mod audit;
+pub mod cache;
 pub mod car;
 pub mod part;
+pub mod resumable;
+pub mod upload;
 pub mod user;
@@ -1,3 +1,5 @@
+use crate::models::attachment::AttachmentSummary;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use utoipa::ToSchema;
@@ -9,6 +11,22 @@ pub struct Part {
     pub id: i32,
     pub car_id: Option<i32>,
     pub name: String,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub updated_at: DateTime<Utc>,
+    #[schema(read_only)]
+    pub created_by: Option<String>,
+    #[schema(read_only)]
+    pub updated_by: Option<String>,
+    #[schema(read_only)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // bumped on every update, sent back as the `ETag` of the row
+    #[serde(default)]
+    #[schema(read_only)]
+    pub version: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema, Validate)]
@@ -28,3 +46,11 @@ pub struct PartList {
     pub data: Vec<Part>,
     pub total: i64,
 }
+
+// A part together with its attachments, as `parts::view` returns it
+#[derive(Serialize, Deserialize, Debug, ToSchema, Clone)]
+pub struct PartDetails {
+    #[serde(flatten)]
+    pub part: Part,
+    pub attachments: Vec<AttachmentSummary>,
+}
@@ -0,0 +1,36 @@
+use crate::models::upload::{FileMeta, Upload};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// An upload sent in chunks over several requests, see `services::resumable`. It is kept in Redis
+// until it is complete, then becomes an `Upload`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumableUpload {
+    pub id: Uuid,
+    pub owner: String,
+    // bytes announced when it was created
+    pub length: u64,
+    // bytes received so far
+    pub offset: u64,
+    pub meta: FileMeta,
+    // storage keys of the chunks received, in order
+    pub parts: Vec<String>,
+    // pushed back by every chunk
+    pub expires_at: DateTime<Utc>,
+}
+
+// A chunk as announced by its request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    // where it starts, has to be where the upload is at
+    pub offset: u64,
+    // SHA-256 digest the content has to match
+    pub checksum: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum Appended {
+    // more chunks to come
+    Partial(ResumableUpload),
+    Complete(Upload),
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+// A stored file. The bytes live under `blob`, shared with identical files, the name the client
+// sent is only kept for display and downloads.
+#[derive(Serialize, Deserialize, FromRow, Debug, ToSchema, Clone)]
+pub struct Upload {
+    pub id: Uuid,
+    pub owner: String,
+    pub original_name: String,
+    pub size: i64,
+    pub content_type: String,
+    // hex digest of the content
+    pub sha256: String,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // edges in pixels of the thumbnails made so far, only ever filled for images
+    pub thumbnails: Vec<i32>,
+    // storage key of the content
+    #[serde(skip)]
+    pub blob: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewUpload {
+    pub id: Uuid,
+    pub owner: String,
+    pub original_name: String,
+    pub size: i64,
+    pub content_type: String,
+    pub sha256: String,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct UploadList {
+    pub data: Vec<Upload>,
+    pub total: i64,
+}
+
+// Lets whoever holds it download a file without logging in, until it expires
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct DownloadLink {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// Bytes of uploads a user has stored, and how many they may
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    // none when there is no limit
+    pub quota_bytes: Option<u64>,
+}
+
+impl StorageUsage {
+    // what may still be uploaded, none when there is no limit
+    pub fn left(&self) -> Option<u64> {
+        self.quota_bytes
+            .map(|quota| quota.saturating_sub(self.used_bytes))
+    }
+}
+
+// What the client tells about a file it uploads
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub name: String,
+    pub content_type: String,
+    pub description: Option<String>,
+}
+
+// Form of `files::upload`, documentation only: the handler reads the parts as they stream in
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    // applies to the files that come after it in the form
+    description: Option<String>,
+    #[schema(value_type = Vec<String>, format = Binary)]
+    files: Vec<Vec<u8>>,
+}
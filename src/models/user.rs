@@ -1,3 +1,5 @@
+use crate::models::upload::StorageUsage;
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -12,7 +14,34 @@ static USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[0-9A-Za-z_]+$")
 pub struct User {
     pub id: i32,
     pub username: String,
+    // Only ever read from the database, to check passwords. Never serialized, so it is neither
+    // cached nor sent to clients.
+    #[serde(skip_serializing, default)]
+    #[schema(write_only)]
     pub password_hash: String,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub updated_at: DateTime<Utc>,
+    #[schema(read_only)]
+    pub created_by: Option<String>,
+    #[schema(read_only)]
+    pub updated_by: Option<String>,
+    #[schema(read_only)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[schema(read_only)]
+    pub is_admin: bool,
+}
+
+// What `auth::profile` returns: the user and how much of their storage quota is used
+#[derive(Serialize, Deserialize, Debug, ToSchema, Clone)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub user: User,
+    pub storage: StorageUsage,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema, Validate)]
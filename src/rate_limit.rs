@@ -0,0 +1,479 @@
+use crate::cache::CacheImpl;
+use crate::config::Config;
+use crate::controllers::auth::Claims;
+use crate::error::{ApiError, AppError};
+use anyhow::{Context, Result, anyhow, bail};
+use axum::extract::{ConnectInfo, FromRequestParts, Request};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, Method};
+use axum::response::{IntoResponse, Response};
+use futures::future::BoxFuture;
+use redis::Script;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+
+pub const API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+const LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+// Token bucket: holds up to `limit` tokens and refills `limit` of them per window, every request
+// takes one. The clock is Redis', so all instances agree on it.
+// KEYS: the bucket
+// ARGV: limit, window in ms
+// Returns whether the request is allowed, the tokens left, and the ms until the bucket is full
+// again and until the next token
+static TAKE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local limit = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local rate = limit / window
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+        local tokens = tonumber(bucket[1]) or limit
+        local at = tonumber(bucket[2]) or now
+        tokens = math.min(limit, tokens + math.max(0, now - at) * rate)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tokens, 'at', now)
+        redis.call('PEXPIRE', KEYS[1], window)
+        local retry = 0
+        if allowed == 0 then
+            retry = math.ceil((1 - tokens) / rate)
+        end
+        return {allowed, math.floor(tokens), math.ceil((limit - tokens) / rate), retry}
+        ",
+    )
+});
+
+// One entry of `Config::rate_limit_policies`, written `[METHOD ]path-prefix=requests/seconds`,
+// or `*=requests/seconds` for every route without a policy of its own. The most specific policy
+// wins: the longest prefix, then one naming the method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    // as written in the config, also names the bucket
+    route: String,
+    method: Option<Method>,
+    prefix: String,
+    limit: u32,
+    window: Duration,
+}
+
+impl Policy {
+    fn parse(entry: &str) -> Result<Self> {
+        let (route, spec) = entry
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("{entry}: expected route=requests/seconds"))?;
+        let route = route.trim();
+        let (limit, window) = spec
+            .split_once('/')
+            .ok_or_else(|| anyhow!("{entry}: expected requests/seconds"))?;
+        let limit: u32 = limit.trim().parse().context(entry.to_string())?;
+        let window: u64 = window.trim().parse().context(entry.to_string())?;
+        if limit == 0 || window == 0 {
+            bail!("{entry}: requests and seconds must be positive");
+        }
+        let (method, prefix) = match route.split_once(' ') {
+            Some((method, prefix)) => (Some(method.parse()?), prefix.trim()),
+            None if route == "*" => (None, ""),
+            None => (None, route),
+        };
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            bail!("{entry}: routes are paths starting with /");
+        }
+        Ok(Self {
+            route: route.to_string(),
+            method,
+            prefix: prefix.to_string(),
+            limit,
+            window: Duration::from_secs(window),
+        })
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method) && path.starts_with(&self.prefix)
+    }
+}
+
+pub fn parse_policies(policies: &str) -> Result<Vec<Policy>> {
+    policies
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(Policy::parse)
+        .collect()
+}
+
+// The outcome of taking a token, sent back as `RateLimit-*` headers
+#[derive(Debug)]
+struct Quota {
+    allowed: bool,
+    limit: u32,
+    window: Duration,
+    remaining: u64,
+    reset: Duration,
+    retry_after: Duration,
+}
+
+impl Quota {
+    fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(LIMIT, self.limit.into());
+        headers.insert(REMAINING, self.remaining.into());
+        headers.insert(RESET, seconds(self.reset).into());
+        if let Ok(policy) = format!("{};w={}", self.limit, self.window.as_secs()).parse() {
+            headers.insert(POLICY, policy);
+        }
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, seconds(self.retry_after).max(1).into());
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
+}
+
+fn digest(key: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(key))
+}
+
+pub struct RateLimiter {
+    cache: Arc<CacheImpl>,
+    policies: Vec<Policy>,
+    // digests of the API keys given buckets of their own
+    api_keys: HashSet<String>,
+    trust_proxy: bool,
+}
+
+impl RateLimiter {
+    pub fn new(
+        cache: Arc<CacheImpl>,
+        policies: Vec<Policy>,
+        api_keys: &str,
+        trust_proxy: bool,
+    ) -> Self {
+        let api_keys = api_keys
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| digest(key.as_bytes()))
+            .collect();
+        Self {
+            cache,
+            policies,
+            api_keys,
+            trust_proxy,
+        }
+    }
+
+    fn policy(&self, method: &Method, path: &str) -> Option<&Policy> {
+        self.policies
+            .iter()
+            .filter(|policy| policy.matches(method, path))
+            .max_by_key(|policy| (policy.prefix.len(), policy.method.is_some()))
+    }
+
+    // `None` when the route is not limited or Redis is unavailable: requests are let through
+    // rather than failed because the limiter cannot count them.
+    async fn check(&self, parts: &mut Parts) -> Option<Quota> {
+        let policy = self.policy(&parts.method, parts.uri.path())?;
+        let key = format!("ratelimit:{}:{}", policy.route, self.client(parts).await);
+        let window = policy.window.as_millis() as u64;
+        let (allowed, remaining, reset, retry_after): (u8, u64, u64, u64) = self
+            .cache
+            .call(async |conn| {
+                TAKE.key(&key)
+                    .arg(policy.limit)
+                    .arg(window)
+                    .invoke_async(conn)
+                    .await
+            })
+            .await?;
+        Some(Quota {
+            allowed: allowed == 1,
+            limit: policy.limit,
+            window: policy.window,
+            remaining,
+            reset: Duration::from_millis(reset),
+            retry_after: Duration::from_millis(retry_after),
+        })
+    }
+
+    // Who the request is counted against: a configured API key, else the signed-in user, else
+    // the client address. Unknown keys are ignored, or any client could get a fresh bucket per
+    // request by making one up. API keys are hashed so they are not stored in Redis as is.
+    async fn client(&self, parts: &mut Parts) -> String {
+        if let Some(key) = parts.headers.get(API_KEY) {
+            let digest = digest(key.as_bytes());
+            if self.api_keys.contains(&digest) {
+                return format!("key:{digest}");
+            }
+        }
+        if parts.headers.contains_key(AUTHORIZATION)
+            && let Ok(claims) = Claims::from_request_parts(parts, &()).await
+        {
+            return format!("sub:{}", claims.sub);
+        }
+        match self.client_ip(parts) {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".to_string(),
+        }
+    }
+
+    // With a trusted proxy its address is the peer, and the last `X-Forwarded-For` entry is the
+    // one it added; earlier entries come from the client and could be anything.
+    fn client_ip(&self, parts: &Parts) -> Option<IpAddr> {
+        if self.trust_proxy
+            && let Some(ip) = parts
+                .headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|ip| ip.trim().parse().ok())
+                .next_back()
+        {
+            return Some(ip);
+        }
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+}
+
+// Limits requests per client and route, see `Policy`. Allowed responses carry the `RateLimit-*`
+// headers, the others are answered with 429 and `Retry-After` without reaching the router.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    // Panics on invalid policies, like any other invalid setting
+    pub fn new(config: &Config, cache: Arc<CacheImpl>) -> Self {
+        let policies = parse_policies(&config.rate_limit_policies)
+            .unwrap_or_else(|e| panic!("RATE_LIMIT_POLICIES is invalid: {e:?}"));
+        let limiter = RateLimiter::new(
+            cache,
+            policies,
+            &config.rate_limit_api_keys,
+            config.rate_limit_trust_proxy,
+        );
+        Self {
+            limiter: Arc::new(limiter),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // the clone may not be ready, keep the one that was polled
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let quota = limiter.check(&mut parts).await;
+            let request = Request::from_parts(parts, body);
+            let Some(quota) = quota else {
+                return inner.call(request).await;
+            };
+            let mut response = if quota.allowed {
+                inner.call(request).await?
+            } else {
+                AppError::from(ApiError::TooManyRequests).into_response()
+            };
+            quota.set_headers(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixture::cache::cache_fixture;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    fn limiter(policies: &str, trust_proxy: bool) -> RateLimiter {
+        RateLimiter::new(
+            cache_fixture(),
+            parse_policies(policies).unwrap(),
+            "secret",
+            trust_proxy,
+        )
+    }
+
+    fn parts(request: Request<()>) -> Parts {
+        request.into_parts().0
+    }
+
+    #[test]
+    fn test_parses_policies() {
+        let policies = parse_policies("*=600/60, POST /api/auth/authorize=10/60,").unwrap();
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies[0].prefix, "");
+        assert_eq!(policies[1].method, Some(Method::POST));
+        assert_eq!(policies[1].prefix, "/api/auth/authorize");
+        assert_eq!(policies[1].limit, 10);
+        assert_eq!(policies[1].window, Duration::from_secs(60));
+        assert!(parse_policies("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_invalid_policies() {
+        assert!(parse_policies("/api=10").is_err());
+        assert!(parse_policies("/api=0/60").is_err());
+        assert!(parse_policies("/api=ten/60").is_err());
+        assert!(parse_policies("api=10/60").is_err());
+    }
+
+    #[test]
+    fn test_picks_the_most_specific_policy() {
+        let all = limiter("*=600/60,/api/cars=100/60,POST /api/cars=20/60", false);
+        let route = |method, path| all.policy(&method, path).map(|p| p.route.as_str());
+        assert_eq!(
+            route(Method::POST, "/api/cars/create"),
+            Some("POST /api/cars")
+        );
+        assert_eq!(route(Method::GET, "/api/cars/list"), Some("/api/cars"));
+        assert_eq!(route(Method::GET, "/api/parts/list"), Some("*"));
+
+        let cars = limiter("/api/cars=100/60", false);
+        assert_eq!(cars.policy(&Method::GET, "/api/parts/list"), None);
+    }
+
+    #[tokio::test]
+    async fn test_counts_known_api_keys_before_addresses() {
+        let limiter = limiter("*=10/60", false);
+        let request = |key: Option<&str>| {
+            let mut request = Request::builder();
+            if let Some(key) = key {
+                request = request.header(API_KEY, key);
+            }
+            let mut request = request.body(()).unwrap();
+            let addr = SocketAddr::from(([10, 0, 0, 1], 1234));
+            request.extensions_mut().insert(ConnectInfo(addr));
+            parts(request)
+        };
+        let client = limiter.client(&mut request(Some("secret"))).await;
+        assert!(client.starts_with("key:"));
+        assert!(!client.contains("secret"));
+
+        assert_eq!(limiter.client(&mut request(None)).await, "ip:10.0.0.1");
+        assert_eq!(
+            limiter.client(&mut request(Some("made-up"))).await,
+            "ip:10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_only_trusts_forwarded_addresses_behind_a_proxy() {
+        let request = || {
+            let mut request = Request::builder()
+                .header("x-forwarded-for", "1.1.1.1, 2.2.2.2")
+                .body(())
+                .unwrap();
+            let addr = SocketAddr::from(([10, 0, 0, 1], 1234));
+            request.extensions_mut().insert(ConnectInfo(addr));
+            parts(request)
+        };
+        let ip = |trust_proxy| limiter("*=10/60", trust_proxy).client_ip(&request());
+        assert_eq!(ip(false), Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(ip(true), Some(IpAddr::from([2, 2, 2, 2])));
+    }
+
+    #[tokio::test]
+    async fn test_lets_requests_through_without_redis() {
+        let layer = RateLimitLayer {
+            limiter: Arc::new(limiter("*=1/60", false)),
+        };
+        let service = layer.layer(tower::service_fn(|_: Request| async {
+            Ok::<_, std::convert::Infallible>(StatusCode::OK.into_response())
+        }));
+        for _ in 0..3 {
+            let request = Request::builder().uri("/api/cars/list").body(Body::empty());
+            let response = service.clone().oneshot(request.unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response.headers().contains_key(LIMIT));
+        }
+    }
+
+    // needs Redis, see CACHE_URL
+    #[tokio::test]
+    #[ignore]
+    async fn test_answers_429_once_the_bucket_is_empty() {
+        dotenv::dotenv().ok();
+        let cache = Arc::new(crate::cache::create_cache(&Config::init()).await);
+        let policies = parse_policies("*=2/60").unwrap();
+        let key = format!("test-{}", rand::random::<u64>());
+        let layer = RateLimitLayer {
+            limiter: Arc::new(RateLimiter::new(cache, policies, &key, false)),
+        };
+        let service = layer.layer(tower::service_fn(|_: Request| async {
+            Ok::<_, std::convert::Infallible>(StatusCode::OK.into_response())
+        }));
+        let mut statuses = vec![];
+        for _ in 0..3 {
+            let request = Request::builder()
+                .uri("/api/cars/list")
+                .header(API_KEY, &key)
+                .body(Body::empty());
+            let response = service.clone().oneshot(request.unwrap()).await.unwrap();
+            statuses.push(response.status());
+            assert_eq!(response.headers()[LIMIT], "2");
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::db::postgres::Db;
+use crate::error::ApiError;
+use crate::models::attachment::{AttachmentOwner, AttachmentSummary};
+use crate::models::audit::{AuditAction, AuditContext};
+use crate::models::car::Car;
+use crate::models::part::Part;
+use crate::models::upload::Upload;
+use crate::repositories::audit;
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use serde_json::Value;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+pub struct AttachmentRepositoryImpl {
+    pool: Db,
+}
+impl AttachmentRepositoryImpl {
+    pub fn new(pool: Db) -> Self {
+        Self { pool }
+    }
+}
+
+#[automock]
+#[async_trait]
+pub trait AttachmentRepository {
+    // oldest first
+    async fn find_by_owner(&self, owner: AttachmentOwner) -> Result<Vec<AttachmentSummary>>;
+    async fn find_upload(&self, owner: AttachmentOwner, upload_id: Uuid) -> Result<Upload>;
+    // `None` when the owner does not exist or is deleted
+    async fn attach(
+        &self,
+        owner: AttachmentOwner,
+        upload_id: Uuid,
+        ctx: &AuditContext,
+    ) -> Result<Option<AttachmentSummary>>;
+    async fn detach(
+        &self,
+        owner: AttachmentOwner,
+        upload_id: Uuid,
+        ctx: &AuditContext,
+    ) -> Result<u64>;
+    // Deletes the uploads attached to cars and parts deleted before the cutoff, returning
+    // them so their thumbnails can go too
+    async fn purge(&self, deleted_before: DateTime<Utc>) -> Result<Vec<Upload>>;
+}
+
+#[async_trait]
+impl AttachmentRepository for AttachmentRepositoryImpl {
+    async fn find_by_owner(&self, owner: AttachmentOwner) -> Result<Vec<AttachmentSummary>> {
+        let attachments = sqlx::query_as!(
+            AttachmentSummary,
+            r#"
+            SELECT u.id, u.original_name, u.content_type, u.size, u.description, u.thumbnails,
+                   a.created_by AS attached_by, a.created_at AS attached_at
+            FROM attachments a
+            JOIN uploads u ON u.id = a.upload_id
+            WHERE a.car_id = $1 OR a.part_id = $2
+            ORDER BY a.created_at, u.id
+            "#,
+            owner.car_id(),
+            owner.part_id(),
+        )
+        .fetch_all(self.pool.reader())
+        .await?;
+        Ok(attachments)
+    }
+
+    async fn find_upload(&self, owner: AttachmentOwner, upload_id: Uuid) -> Result<Upload> {
+        let upload = sqlx::query_as!(
+            Upload,
+            r#"
+            SELECT u.* FROM uploads u
+            JOIN attachments a ON a.upload_id = u.id
+            WHERE u.id = $1 AND (a.car_id = $2 OR a.part_id = $3)
+            "#,
+            upload_id,
+            owner.car_id(),
+            owner.part_id(),
+        )
+        .fetch_one(self.pool.reader())
+        .await?;
+        Ok(upload)
+    }
+
+    async fn attach(
+        &self,
+        owner: AttachmentOwner,
+        upload_id: Uuid,
+        ctx: &AuditContext,
+    ) -> Result<Option<AttachmentSummary>> {
+        let mut tx = self.pool.writer().begin().await?;
+        let Some(before) = lock_owner(&mut tx, owner).await? else {
+            return Ok(None);
+        };
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO attachments (upload_id, car_id, part_id, created_by)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (upload_id) DO NOTHING
+            "#,
+            upload_id,
+            owner.car_id(),
+            owner.part_id(),
+            ctx.actor,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            bail!(ApiError::Conflict(
+                "The file is already attached to a car or part".to_string()
+            ));
+        }
+        touch(&mut tx, owner, ctx, &before).await?;
+        let attachment = sqlx::query_as!(
+            AttachmentSummary,
+            r#"
+            SELECT u.id, u.original_name, u.content_type, u.size, u.description, u.thumbnails,
+                   a.created_by AS attached_by, a.created_at AS attached_at
+            FROM attachments a
+            JOIN uploads u ON u.id = a.upload_id
+            WHERE a.upload_id = $1
+            "#,
+            upload_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(attachment))
+    }
+
+    async fn detach(
+        &self,
+        owner: AttachmentOwner,
+        upload_id: Uuid,
+        ctx: &AuditContext,
+    ) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_owner(&mut tx, owner).await?;
+        let affected_rows = sqlx::query!(
+            "DELETE FROM attachments WHERE upload_id = $1 AND (car_id = $2 OR part_id = $3)",
+            upload_id,
+            owner.car_id(),
+            owner.part_id(),
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if let Some(before) = before.filter(|_| affected_rows > 0) {
+            touch(&mut tx, owner, ctx, &before).await?;
+        }
+        tx.commit().await?;
+        Ok(affected_rows)
+    }
+
+    async fn purge(&self, deleted_before: DateTime<Utc>) -> Result<Vec<Upload>> {
+        let uploads = sqlx::query_as!(
+            Upload,
+            r#"
+            WITH deleted AS (
+                DELETE FROM uploads
+                WHERE id IN (
+                    SELECT a.upload_id FROM attachments a
+                    LEFT JOIN cars c ON c.id = a.car_id
+                    LEFT JOIN parts p ON p.id = a.part_id
+                    WHERE c.deleted_at < $1 OR p.deleted_at < $1
+                )
+                RETURNING *
+            ),
+            usage AS (
+                UPDATE storage_usage s SET bytes = s.bytes - d.bytes
+                FROM (SELECT owner, SUM(size) AS bytes FROM deleted GROUP BY owner) d
+                WHERE s.owner = d.owner
+            ),
+            blobs AS (
+                UPDATE blobs b SET refs = b.refs - d.refs
+                FROM (SELECT blob, COUNT(*) AS refs FROM deleted GROUP BY blob) d
+                WHERE b.key = d.blob
+            )
+            SELECT * FROM deleted
+            "#,
+            deleted_before,
+        )
+        .fetch_all(self.pool.writer())
+        .await?;
+        Ok(uploads)
+    }
+}
+
+// The owner as the audit log shows it: its row and the ids of the uploads attached to it,
+// oldest first. Locks the row until the transaction ends so nothing changes in between, `None`
+// when the owner does not exist or is deleted.
+pub async fn lock_owner(conn: &mut PgConnection, owner: AttachmentOwner) -> Result<Option<Value>> {
+    let row = match owner {
+        AttachmentOwner::Car(id) => sqlx::query_as::<_, Car>(
+            "SELECT * FROM cars WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(serde_json::to_value),
+        AttachmentOwner::Part(id) => sqlx::query_as::<_, Part>(
+            "SELECT * FROM parts WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(serde_json::to_value),
+    };
+    let Some(mut row) = row.transpose()? else {
+        return Ok(None);
+    };
+    let attachments = sqlx::query_scalar!(
+        r#"
+        SELECT upload_id FROM attachments
+        WHERE car_id = $1 OR part_id = $2
+        ORDER BY created_at, upload_id
+        "#,
+        owner.car_id(),
+        owner.part_id(),
+    )
+    .fetch_all(conn)
+    .await?;
+    row["attachments"] = serde_json::to_value(attachments)?;
+    Ok(Some(row))
+}
+
+// Attachments are part of what a car or part looks like, so changing them bumps its version
+// and `updated_at` like any other write, and is audited as an update of the owner. That keeps
+// ETags, `Last-Modified` and `If-Match` right. `before` is what `lock_owner` returned ahead of
+// the change.
+pub async fn touch(
+    conn: &mut PgConnection,
+    owner: AttachmentOwner,
+    ctx: &AuditContext,
+    before: &Value,
+) -> Result<()> {
+    let (entity, id) = match owner {
+        AttachmentOwner::Car(id) => {
+            sqlx::query!(
+                r#"
+                UPDATE cars SET updated_at = now(), updated_by = $2, version = version + 1
+                WHERE id = $1
+                "#,
+                id,
+                ctx.actor,
+            )
+            .execute(&mut *conn)
+            .await?;
+            ("car", id)
+        }
+        AttachmentOwner::Part(id) => {
+            sqlx::query!(
+                r#"
+                UPDATE parts SET updated_at = now(), updated_by = $2, version = version + 1
+                WHERE id = $1
+                "#,
+                id,
+                ctx.actor,
+            )
+            .execute(&mut *conn)
+            .await?;
+            ("part", id)
+        }
+    };
+    let after = lock_owner(conn, owner).await?;
+    audit::record(
+        conn,
+        ctx,
+        entity,
+        id,
+        AuditAction::Update,
+        Some(before),
+        after.as_ref(),
+    )
+    .await
+}
@@ -0,0 +1,119 @@
+use crate::controllers::Pagination;
+use crate::db::postgres::Db;
+use crate::models::audit::{AuditAction, AuditContext, AuditEntry, AuditList, AuditQuery, diff};
+use anyhow::Result;
+use async_trait::async_trait;
+use mockall::automock;
+use serde::Serialize;
+use sqlx::PgConnection;
+
+pub struct AuditRepositoryImpl {
+    pool: Db,
+}
+impl AuditRepositoryImpl {
+    pub fn new(pool: Db) -> Self {
+        Self { pool }
+    }
+}
+
+#[automock]
+#[async_trait]
+pub trait AuditRepository {
+    async fn find_all(&self, conditions: &AuditQuery, pagination: &Pagination)
+    -> Result<AuditList>;
+}
+
+#[async_trait]
+impl AuditRepository for AuditRepositoryImpl {
+    async fn find_all(
+        &self,
+        conditions: &AuditQuery,
+        pagination: &Pagination,
+    ) -> Result<AuditList> {
+        let limit = pagination.per_page.unwrap_or(100);
+        let offset = (pagination.page.unwrap_or(1) - 1) * limit;
+
+        let data = sqlx::query_as!(
+            AuditEntry,
+            r#"
+            SELECT * FROM audit_log
+            WHERE ($1::text IS NULL OR entity = $1)
+              AND ($2::integer IS NULL OR entity_id = $2)
+              AND ($3::text IS NULL OR actor = $3)
+              AND ($4::text IS NULL OR action = $4)
+              AND ($5::text IS NULL OR request_id = $5)
+              AND ($6::timestamptz IS NULL OR occurred_at >= $6)
+              AND ($7::timestamptz IS NULL OR occurred_at < $7)
+            ORDER BY id DESC
+            LIMIT $8 OFFSET $9
+            "#,
+            conditions.entity,
+            conditions.entity_id,
+            conditions.actor,
+            conditions.action,
+            conditions.request_id,
+            conditions.from,
+            conditions.to,
+            limit as i32,
+            offset as i32,
+        )
+        .fetch_all(self.pool.reader())
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) FROM audit_log
+            WHERE ($1::text IS NULL OR entity = $1)
+              AND ($2::integer IS NULL OR entity_id = $2)
+              AND ($3::text IS NULL OR actor = $3)
+              AND ($4::text IS NULL OR action = $4)
+              AND ($5::text IS NULL OR request_id = $5)
+              AND ($6::timestamptz IS NULL OR occurred_at >= $6)
+              AND ($7::timestamptz IS NULL OR occurred_at < $7)
+            "#,
+            conditions.entity,
+            conditions.entity_id,
+            conditions.actor,
+            conditions.action,
+            conditions.request_id,
+            conditions.from,
+            conditions.to,
+        )
+        .fetch_one(self.pool.reader())
+        .await?
+        .unwrap();
+
+        Ok(AuditList { data, total })
+    }
+}
+
+// Appends an audit entry for a change to `entity`. Pass the transaction that made the change so
+// the entry commits, or rolls back, together with it.
+pub async fn record<T: Serialize>(
+    conn: &mut PgConnection,
+    ctx: &AuditContext,
+    entity: &str,
+    entity_id: i32,
+    action: AuditAction,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<()> {
+    let before = before.map(serde_json::to_value).transpose()?;
+    let after = after.map(serde_json::to_value).transpose()?;
+    let changes = diff(before.as_ref(), after.as_ref());
+
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_log (actor, entity, entity_id, action, changes, request_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        ctx.actor,
+        entity,
+        entity_id,
+        action.as_str(),
+        changes,
+        ctx.request_id,
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
@@ -1,9 +1,15 @@
 use crate::controllers::{CommonQuery, Pagination};
 use crate::db::postgres::Db;
+use crate::models::audit::{AuditAction, AuditContext};
 use crate::models::car::{Car, CarList, CarQuery, NewCar};
+use crate::repositories::audit;
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use mockall::automock;
+use sqlx::PgConnection;
+
+const AUDIT_ENTITY: &str = "car";
 
 pub struct CarRepositoryImpl {
     pool: Db,
@@ -23,10 +29,17 @@ pub trait CarRepository {
         query: &CommonQuery,
         pagination: &Pagination,
     ) -> Result<CarList>;
-    async fn create(&self, car_data: &NewCar) -> Result<Car>;
-    async fn update(&self, car_data: &Car) -> Result<Car>;
-    async fn delete(&self, car_id: i32) -> Result<u64>;
-    async fn find_by_id(&self, car_id: i32) -> Result<Car>;
+    async fn create(&self, car_data: &NewCar, ctx: &AuditContext) -> Result<Car>;
+    async fn update(
+        &self,
+        car_data: &Car,
+        versions: &[i32],
+        ctx: &AuditContext,
+    ) -> Result<Option<Car>>;
+    async fn delete(&self, car_id: i32, versions: &[i32], ctx: &AuditContext) -> Result<u64>;
+    async fn restore(&self, car_id: i32, ctx: &AuditContext) -> Result<u64>;
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64>;
+    async fn find_by_id(&self, car_id: i32, include_deleted: bool) -> Result<Car>;
 }
 
 #[async_trait]
@@ -43,109 +56,295 @@ impl CarRepository for CarRepositoryImpl {
         let data = if let Some(name) = &conditions.name {
             sqlx::query_as!(
                 Car,
-                "SELECT * FROM cars WHERE NAME LIKE $1 LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM cars
+                WHERE NAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 format!("%{}%", name),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else if !query.ids.is_empty() {
             sqlx::query_as!(
                 Car,
-                "SELECT * FROM cars WHERE id IN (SELECT unnest($1::integer[])) LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM cars
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else {
             sqlx::query_as!(
                 Car,
-                "SELECT * FROM cars LIMIT $1 OFFSET $2",
+                r#"
+                SELECT * FROM cars
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                LIMIT $4 OFFSET $5
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         };
         let total = if let Some(name) = &conditions.name {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM cars WHERE NAME LIKE $1",
+                r#"
+                SELECT COUNT(*) FROM cars
+                WHERE NAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 format!("%{}%", name),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else if !query.ids.is_empty() {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM cars WHERE id IN (SELECT unnest($1::integer[]))",
+                r#"
+                SELECT COUNT(*) FROM cars
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else {
-            sqlx::query_scalar!("SELECT COUNT(*) FROM cars")
-                .fetch_one(&*self.pool)
-                .await?
-                .unwrap()
+            sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) FROM cars
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
+            )
+            .fetch_one(self.pool.reader())
+            .await?
+            .unwrap()
         };
 
         Ok(CarList { data, total })
     }
 
-    async fn create(&self, car_data: &NewCar) -> Result<Car> {
+    async fn create(&self, car_data: &NewCar, ctx: &AuditContext) -> Result<Car> {
+        let mut tx = self.pool.writer().begin().await?;
         let created_car = sqlx::query_as::<_, Car>(
             r#"
-            INSERT INTO cars (name, color, year)
-            VALUES ($1, $2, $3)
-            RETURNING id, name, color, year
+            INSERT INTO cars (name, color, year, created_by, updated_by)
+            VALUES ($1, $2, $3, $4, $4)
+            RETURNING *
             "#,
         )
         .bind(&car_data.name)
         .bind(&car_data.color)
         .bind(car_data.year)
-        .fetch_one(&*self.pool)
+        .bind(&ctx.actor)
+        .fetch_one(&mut *tx)
+        .await?;
+        audit::record(
+            &mut tx,
+            ctx,
+            AUDIT_ENTITY,
+            created_car.id,
+            AuditAction::Create,
+            None,
+            Some(&created_car),
+        )
         .await?;
+        tx.commit().await?;
         Ok(created_car)
     }
 
-    async fn update(&self, car_data: &Car) -> Result<Car> {
+    async fn update(
+        &self,
+        car_data: &Car,
+        versions: &[i32],
+        ctx: &AuditContext,
+    ) -> Result<Option<Car>> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, car_data.id).await?;
         let updated_car = sqlx::query_as::<_, Car>(
             r#"
             UPDATE cars
-            SET name = $2, color = $3, year = $4
-            WHERE id = $1
-            RETURNING id, name, color, year
+            SET name = $2, color = $3, year = $4, updated_at = now(), updated_by = $5,
+                version = version + 1
+            WHERE id = $1 AND version = ANY($6) AND deleted_at IS NULL
+            RETURNING *
             "#,
         )
         .bind(car_data.id)
         .bind(&car_data.name)
         .bind(&car_data.color)
         .bind(car_data.year)
-        .fetch_one(&*self.pool)
+        .bind(&ctx.actor)
+        .bind(versions)
+        .fetch_optional(&mut *tx)
         .await?;
+        if let Some(updated) = &updated_car {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                updated.id,
+                AuditAction::Update,
+                before.as_ref(),
+                Some(updated),
+            )
+            .await?;
+        }
+        tx.commit().await?;
         Ok(updated_car)
     }
 
-    async fn delete(&self, car_id: i32) -> Result<u64> {
-        let query = sqlx::query("DELETE FROM cars WHERE id = $1")
-            .bind(car_id)
-            .execute(&*self.pool)
+    async fn delete(&self, car_id: i32, versions: &[i32], ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, car_id).await?;
+        let deleted_car = sqlx::query_as::<_, Car>(
+            r#"
+            UPDATE cars
+            SET deleted_at = now(), updated_at = now(), updated_by = $2, version = version + 1
+            WHERE id = $1 AND version = ANY($3) AND deleted_at IS NULL
+            RETURNING *
+            "#,
+        )
+        .bind(car_id)
+        .bind(&ctx.actor)
+        .bind(versions)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(deleted) = &deleted_car {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                car_id,
+                AuditAction::Delete,
+                before.as_ref(),
+                Some(deleted),
+            )
             .await?;
-        Ok(query.rows_affected())
+        }
+        tx.commit().await?;
+        Ok(u64::from(deleted_car.is_some()))
     }
 
-    async fn find_by_id(&self, car_id: i32) -> Result<Car> {
-        let row = sqlx::query_as!(Car, "SELECT * FROM cars WHERE id = $1", car_id)
-            .fetch_one(&*self.pool)
+    async fn restore(&self, car_id: i32, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, car_id).await?;
+        let restored_car = sqlx::query_as::<_, Car>(
+            r#"
+            UPDATE cars
+            SET deleted_at = NULL, updated_at = now(), updated_by = $2, version = version + 1
+            WHERE id = $1 AND deleted_at IS NOT NULL
+            RETURNING *
+            "#,
+        )
+        .bind(car_id)
+        .bind(&ctx.actor)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(restored) = &restored_car {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                car_id,
+                AuditAction::Restore,
+                before.as_ref(),
+                Some(restored),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(u64::from(restored_car.is_some()))
+    }
+
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let purged_cars =
+            sqlx::query_as::<_, Car>("DELETE FROM cars WHERE deleted_at < $1 RETURNING *")
+                .bind(deleted_before)
+                .fetch_all(&mut *tx)
+                .await?;
+        for purged in &purged_cars {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                purged.id,
+                AuditAction::Purge,
+                Some(purged),
+                None,
+            )
             .await?;
+        }
+        tx.commit().await?;
+        Ok(purged_cars.len() as u64)
+    }
+
+    async fn find_by_id(&self, car_id: i32, include_deleted: bool) -> Result<Car> {
+        let row = sqlx::query_as!(
+            Car,
+            "SELECT * FROM cars WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+            car_id,
+            include_deleted,
+        )
+        .fetch_one(self.pool.reader())
+        .await?;
         Ok(row)
     }
 }
 
+// Reads the current row and locks it until the transaction ends, so the audit entry's "before"
+// is exactly what the following write replaces.
+async fn lock_by_id(conn: &mut PgConnection, car_id: i32) -> Result<Option<Car>> {
+    let row = sqlx::query_as::<_, Car>("SELECT * FROM cars WHERE id = $1 FOR UPDATE")
+        .bind(car_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,7 +355,10 @@ mod tests {
         let conditions = CarQuery {
             name: Some("Tesla".to_string()),
         };
-        let query = CommonQuery { ids: [].to_vec() };
+        let query = CommonQuery {
+            ids: [].to_vec(),
+            ..Default::default()
+        };
         let pagination = Pagination {
             page: None,
             per_page: None,
@@ -170,12 +372,24 @@ mod tests {
                     name: "Tesla Model S".to_string(),
                     color: None,
                     year: None,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                    created_by: None,
+                    updated_by: None,
+                    deleted_at: None,
+                    version: 1,
                 },
                 Car {
                     id: 2,
                     name: "Tesla Model 3".to_string(),
                     color: None,
                     year: None,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                    created_by: None,
+                    updated_by: None,
+                    deleted_at: None,
+                    version: 1,
                 },
             ],
             total: 99,
@@ -1,22 +1,32 @@
 use crate::config::Config;
 use crate::db::postgres;
 use crate::repositories::{
-    car::CarRepositoryImpl, part::PartRepositoryImpl, user::UserRepositoryImpl,
+    attachment::AttachmentRepositoryImpl, audit::AuditRepositoryImpl, car::CarRepositoryImpl,
+    part::PartRepositoryImpl, resumable::ResumableRepositoryImpl, upload::UploadRepositoryImpl,
+    user::UserRepositoryImpl,
 };
 use axum::extract::Extension;
 use std::sync::Arc;
 
+pub mod attachment;
+pub mod audit;
 pub mod car;
 pub mod part;
+pub mod resumable;
+pub mod upload;
 pub mod user;
 
 pub type UserRepoExt = Extension<Arc<UserRepositoryImpl>>;
 pub type CarRepoExt = Extension<Arc<CarRepositoryImpl>>;
 pub type PartRepoExt = Extension<Arc<PartRepositoryImpl>>;
+pub type AuditRepoExt = Extension<Arc<AuditRepositoryImpl>>;
+pub type UploadRepoExt = Extension<Arc<UploadRepositoryImpl>>;
+pub type AttachmentRepoExt = Extension<Arc<AttachmentRepositoryImpl>>;
+pub type ResumableRepoExt = Extension<Arc<ResumableRepositoryImpl>>;
 
 pub async fn run_migrations(config: &Config) {
     let db_pool = Arc::new(postgres::db_connect(config).await);
-    if let Err(e) = sqlx::migrate!().run(&*db_pool).await {
+    if let Err(e) = sqlx::migrate!().run(db_pool.writer()).await {
         panic!("Failed to run database migrations: {:?}", e);
     }
 }
@@ -36,11 +46,26 @@ pub async fn create_part_repository(config: &Config) -> PartRepositoryImpl {
     PartRepositoryImpl::new(db_pool.clone())
 }
 
+pub async fn create_audit_repository(config: &Config) -> AuditRepositoryImpl {
+    let db_pool = Arc::new(postgres::db_connect(config).await);
+    AuditRepositoryImpl::new(db_pool.clone())
+}
+
+pub async fn create_upload_repository(config: &Config) -> UploadRepositoryImpl {
+    let db_pool = Arc::new(postgres::db_connect(config).await);
+    UploadRepositoryImpl::new(db_pool.clone())
+}
+
+pub async fn create_attachment_repository(config: &Config) -> AttachmentRepositoryImpl {
+    let db_pool = Arc::new(postgres::db_connect(config).await);
+    AttachmentRepositoryImpl::new(db_pool.clone())
+}
+
 #[cfg(test)]
 pub async fn clear_database(config: &Config) {
     let db_pool = Arc::new(postgres::db_connect(config).await);
-    sqlx::query("TRUNCATE TABLE parts, cars, users CASCADE")
-        .execute(&*db_pool)
+    sqlx::query("TRUNCATE TABLE audit_log, attachments, uploads, parts, cars, users CASCADE")
+        .execute(db_pool.writer())
         .await
         .expect("Failed to clear database tables");
 }
@@ -1,9 +1,15 @@
 use crate::controllers::{CommonQuery, Pagination};
 use crate::db::postgres::Db;
+use crate::models::audit::{AuditAction, AuditContext};
 use crate::models::part::{NewPart, Part, PartList, PartQuery};
+use crate::repositories::audit;
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use mockall::automock;
+use sqlx::PgConnection;
+
+const AUDIT_ENTITY: &str = "part";
 
 pub struct PartRepositoryImpl {
     pool: Db,
@@ -23,10 +29,17 @@ pub trait PartRepository {
         query: &CommonQuery,
         pagination: &Pagination,
     ) -> Result<PartList>;
-    async fn create(&self, part_data: &NewPart) -> Result<Part>;
-    async fn update(&self, part_data: &Part) -> Result<Part>;
-    async fn delete(&self, part_id: i32) -> Result<u64>;
-    async fn find_by_id(&self, part_id: i32) -> Result<Part>;
+    async fn create(&self, part_data: &NewPart, ctx: &AuditContext) -> Result<Part>;
+    async fn update(
+        &self,
+        part_data: &Part,
+        versions: &[i32],
+        ctx: &AuditContext,
+    ) -> Result<Option<Part>>;
+    async fn delete(&self, part_id: i32, versions: &[i32], ctx: &AuditContext) -> Result<u64>;
+    async fn restore(&self, part_id: i32, ctx: &AuditContext) -> Result<u64>;
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64>;
+    async fn find_by_id(&self, part_id: i32, include_deleted: bool) -> Result<Part>;
 }
 
 #[async_trait]
@@ -42,105 +55,295 @@ impl PartRepository for PartRepositoryImpl {
         let data = if let Some(name) = &conditions.name {
             sqlx::query_as!(
                 Part,
-                "SELECT * FROM parts WHERE NAME LIKE $1 LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM parts
+                WHERE NAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 format!("%{}%", name),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else if !query.ids.is_empty() {
             sqlx::query_as!(
                 Part,
-                "SELECT * FROM parts WHERE id IN (SELECT unnest($1::integer[])) LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM parts
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else {
             sqlx::query_as!(
                 Part,
-                "SELECT * FROM parts LIMIT $1 OFFSET $2",
+                r#"
+                SELECT * FROM parts
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                LIMIT $4 OFFSET $5
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         };
         let total = if let Some(name) = &conditions.name {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM parts WHERE NAME LIKE $1",
+                r#"
+                SELECT COUNT(*) FROM parts
+                WHERE NAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 format!("%{}%", name),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else if !query.ids.is_empty() {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM parts WHERE id IN (SELECT unnest($1::integer[]))",
+                r#"
+                SELECT COUNT(*) FROM parts
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else {
-            sqlx::query_scalar!("SELECT COUNT(*) FROM parts")
-                .fetch_one(&*self.pool)
-                .await?
-                .unwrap()
+            sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) FROM parts
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
+            )
+            .fetch_one(self.pool.reader())
+            .await?
+            .unwrap()
         };
 
         Ok(PartList { data, total })
     }
 
-    async fn create(&self, part_data: &NewPart) -> Result<Part> {
+    async fn create(&self, part_data: &NewPart, ctx: &AuditContext) -> Result<Part> {
+        let mut tx = self.pool.writer().begin().await?;
         let created_part = sqlx::query_as!(
             Part,
             r#"
-            INSERT INTO parts (name, car_id)
-            VALUES ($1, $2)
-            RETURNING id, name, car_id
+            INSERT INTO parts (name, car_id, created_by, updated_by)
+            VALUES ($1, $2, $3, $3)
+            RETURNING *
             "#,
             &part_data.name,
             part_data.car_id,
+            ctx.actor,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        audit::record(
+            &mut tx,
+            ctx,
+            AUDIT_ENTITY,
+            created_part.id,
+            AuditAction::Create,
+            None,
+            Some(&created_part),
         )
-        .fetch_one(&*self.pool)
         .await?;
+        tx.commit().await?;
         Ok(created_part)
     }
 
-    async fn update(&self, part_data: &Part) -> Result<Part> {
+    async fn update(
+        &self,
+        part_data: &Part,
+        versions: &[i32],
+        ctx: &AuditContext,
+    ) -> Result<Option<Part>> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, part_data.id).await?;
         let updated_part = sqlx::query_as!(
             Part,
             r#"
             UPDATE parts
-            SET name = $2, car_id = $3
-            WHERE id = $1
-            RETURNING id, name, car_id
+            SET name = $2, car_id = $3, updated_at = now(), updated_by = $4,
+                version = version + 1
+            WHERE id = $1 AND version = ANY($5) AND deleted_at IS NULL
+            RETURNING *
             "#,
             part_data.id,
             &part_data.name,
             part_data.car_id,
+            ctx.actor,
+            versions,
         )
-        .fetch_one(&*self.pool)
+        .fetch_optional(&mut *tx)
         .await?;
+        if let Some(updated) = &updated_part {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                updated.id,
+                AuditAction::Update,
+                before.as_ref(),
+                Some(updated),
+            )
+            .await?;
+        }
+        tx.commit().await?;
         Ok(updated_part)
     }
 
-    async fn delete(&self, part_id: i32) -> Result<u64> {
-        let query = sqlx::query("DELETE FROM parts WHERE id = $1")
-            .bind(part_id)
-            .execute(&*self.pool)
+    async fn delete(&self, part_id: i32, versions: &[i32], ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, part_id).await?;
+        let deleted_part = sqlx::query_as!(
+            Part,
+            r#"
+            UPDATE parts
+            SET deleted_at = now(), updated_at = now(), updated_by = $2, version = version + 1
+            WHERE id = $1 AND version = ANY($3) AND deleted_at IS NULL
+            RETURNING *
+            "#,
+            part_id,
+            ctx.actor,
+            versions,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(deleted) = &deleted_part {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                part_id,
+                AuditAction::Delete,
+                before.as_ref(),
+                Some(deleted),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(u64::from(deleted_part.is_some()))
+    }
+
+    async fn restore(&self, part_id: i32, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_id(&mut tx, part_id).await?;
+        let restored_part = sqlx::query_as!(
+            Part,
+            r#"
+            UPDATE parts
+            SET deleted_at = NULL, updated_at = now(), updated_by = $2, version = version + 1
+            WHERE id = $1 AND deleted_at IS NOT NULL
+            RETURNING *
+            "#,
+            part_id,
+            ctx.actor,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(restored) = &restored_part {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                part_id,
+                AuditAction::Restore,
+                before.as_ref(),
+                Some(restored),
+            )
             .await?;
-        Ok(query.rows_affected())
+        }
+        tx.commit().await?;
+        Ok(u64::from(restored_part.is_some()))
     }
 
-    async fn find_by_id(&self, part_id: i32) -> Result<Part> {
-        let row = sqlx::query_as!(Part, "SELECT * FROM parts WHERE id = $1", part_id,)
-            .fetch_one(&*self.pool)
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let purged_parts = sqlx::query_as!(
+            Part,
+            "DELETE FROM parts WHERE deleted_at < $1 RETURNING *",
+            deleted_before,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for purged in &purged_parts {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                purged.id,
+                AuditAction::Purge,
+                Some(purged),
+                None,
+            )
             .await?;
+        }
+        tx.commit().await?;
+        Ok(purged_parts.len() as u64)
+    }
+
+    async fn find_by_id(&self, part_id: i32, include_deleted: bool) -> Result<Part> {
+        let row = sqlx::query_as!(
+            Part,
+            "SELECT * FROM parts WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+            part_id,
+            include_deleted,
+        )
+        .fetch_one(self.pool.reader())
+        .await?;
         Ok(row)
     }
 }
+
+// Reads the current row and locks it until the transaction ends, so the audit entry's "before"
+// is exactly what the following write replaces.
+async fn lock_by_id(conn: &mut PgConnection, part_id: i32) -> Result<Option<Part>> {
+    let row = sqlx::query_as::<_, Part>("SELECT * FROM parts WHERE id = $1 FOR UPDATE")
+        .bind(part_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row)
+}
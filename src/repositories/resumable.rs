@@ -0,0 +1,313 @@
+use crate::cache::CacheImpl;
+use crate::error::ApiError;
+use crate::models::resumable::ResumableUpload;
+use crate::models::upload::FileMeta;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use redis::{AsyncCommands, Script};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use uuid::Uuid;
+
+// Sorted set of the ids of resumable uploads, scored by when they expire
+const EXPIRY_KEY: &str = "resumable:expiry";
+
+// How long Redis keeps an upload past its expiry, in case the expiry job does not get to it
+const GRACE_SECS: i64 = 86400;
+
+// Moves an upload's offset forward by one chunk, unless another request moved it first
+// KEYS: the upload, the expiry set
+// ARGV: expected offset, new offset, the chunk's storage key, new expiry, when Redis drops the
+//       upload, the upload's id
+static ADVANCE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], 'offset') ~= ARGV[1] then
+            return 0
+        end
+        local parts = redis.call('HGET', KEYS[1], 'parts') or ''
+        redis.call('HSET', KEYS[1], 'offset', ARGV[2], 'parts', parts .. ARGV[3] .. ' ',
+            'expires_at', ARGV[4])
+        redis.call('EXPIREAT', KEYS[1], ARGV[5])
+        redis.call('ZADD', KEYS[2], ARGV[4], ARGV[6])
+        return 1
+        ",
+    )
+});
+
+// Removes an upload that has expired, unless a chunk moved its expiry since, returning what its
+// chunks are stored under. An upload that is gone only leaves the expiry set.
+// KEYS: the upload, the expiry set
+// ARGV: now, the upload's id
+static EXPIRE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local expires_at = redis.call('HGET', KEYS[1], 'expires_at')
+        if expires_at and tonumber(expires_at) > tonumber(ARGV[1]) then
+            return false
+        end
+        local parts = redis.call('HGET', KEYS[1], 'parts')
+        redis.call('DEL', KEYS[1])
+        redis.call('ZREM', KEYS[2], ARGV[2])
+        return parts
+        ",
+    )
+});
+
+// Keeps resumable uploads in Redis as hashes, through the cache's pool, timeout and breaker.
+// When Redis cannot be reached every call fails with `ApiError::ServiceUnavailable`: unlike
+// cached reads there is nothing to fall back to.
+pub struct ResumableRepositoryImpl {
+    cache: Arc<CacheImpl>,
+}
+impl ResumableRepositoryImpl {
+    pub fn new(cache: Arc<CacheImpl>) -> Self {
+        Self { cache }
+    }
+}
+
+#[automock]
+#[async_trait]
+pub trait ResumableRepository {
+    async fn create(&self, upload: &ResumableUpload) -> Result<()>;
+    async fn find(&self, id: Uuid) -> Result<ResumableUpload>;
+    // Records a chunk of `to - from` bytes stored under `part`. False when the upload is no
+    // longer at `from`, or gone.
+    async fn advance(
+        &self,
+        id: Uuid,
+        from: u64,
+        to: u64,
+        part: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool>;
+    // false when it was already gone
+    async fn delete(&self, id: Uuid) -> Result<bool>;
+    async fn find_expired(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>>;
+    // Deletes the upload if it expired by `now`, returning the storage keys of its chunks. None
+    // when it has not expired after all, or was gone.
+    async fn expire(&self, id: Uuid, now: DateTime<Utc>) -> Result<Option<Vec<String>>>;
+}
+
+#[async_trait]
+impl ResumableRepository for ResumableRepositoryImpl {
+    async fn create(&self, upload: &ResumableUpload) -> Result<()> {
+        let key = upload_key(upload.id);
+        let mut fields = vec![
+            ("owner", upload.owner.clone()),
+            ("length", upload.length.to_string()),
+            ("offset", upload.offset.to_string()),
+            ("name", upload.meta.name.clone()),
+            ("content_type", upload.meta.content_type.clone()),
+            (
+                "parts",
+                upload.parts.iter().map(|part| format!("{part} ")).collect(),
+            ),
+            ("expires_at", upload.expires_at.timestamp().to_string()),
+        ];
+        if let Some(description) = &upload.meta.description {
+            fields.push(("description", description.clone()));
+        }
+        let expires_at = upload.expires_at.timestamp();
+        let id = upload.id.to_string();
+        self.cache
+            .call(async |conn| {
+                redis::pipe()
+                    .atomic()
+                    .hset_multiple(&key, &fields)
+                    .ignore()
+                    .expire_at(&key, expires_at + GRACE_SECS)
+                    .ignore()
+                    .zadd(EXPIRY_KEY, &id, expires_at)
+                    .ignore()
+                    .query_async::<()>(conn)
+                    .await
+            })
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        Ok(())
+    }
+
+    async fn find(&self, id: Uuid) -> Result<ResumableUpload> {
+        let key = upload_key(id);
+        let fields: HashMap<String, String> = self
+            .cache
+            .call(async |conn| conn.hgetall(&key).await)
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        if fields.is_empty() {
+            return Err(ApiError::NotFound.into());
+        }
+        decode(id, fields).with_context(|| format!("Resumable upload {id} is malformed"))
+    }
+
+    async fn advance(
+        &self,
+        id: Uuid,
+        from: u64,
+        to: u64,
+        part: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool> {
+        let key = upload_key(id);
+        let advanced: i64 = self
+            .cache
+            .call(async |conn| {
+                ADVANCE
+                    .key(&key)
+                    .key(EXPIRY_KEY)
+                    .arg(from)
+                    .arg(to)
+                    .arg(part)
+                    .arg(expires_at.timestamp())
+                    .arg(expires_at.timestamp() + GRACE_SECS)
+                    .arg(id.to_string())
+                    .invoke_async(conn)
+                    .await
+            })
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        Ok(advanced == 1)
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<bool> {
+        let key = upload_key(id);
+        let (deleted,): (u64,) = self
+            .cache
+            .call(async |conn| {
+                redis::pipe()
+                    .atomic()
+                    .del(&key)
+                    .zrem(EXPIRY_KEY, id.to_string())
+                    .ignore()
+                    .query_async(conn)
+                    .await
+            })
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        Ok(deleted > 0)
+    }
+
+    async fn find_expired(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let ids: Vec<String> = self
+            .cache
+            .call(async |conn| {
+                conn.zrangebyscore(EXPIRY_KEY, "-inf", now.timestamp())
+                    .await
+            })
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        Ok(ids.iter().filter_map(|id| id.parse().ok()).collect())
+    }
+
+    async fn expire(&self, id: Uuid, now: DateTime<Utc>) -> Result<Option<Vec<String>>> {
+        let key = upload_key(id);
+        let parts: Option<String> = self
+            .cache
+            .call(async |conn| {
+                EXPIRE
+                    .key(&key)
+                    .key(EXPIRY_KEY)
+                    .arg(now.timestamp())
+                    .arg(id.to_string())
+                    .invoke_async(conn)
+                    .await
+            })
+            .await
+            .ok_or(ApiError::ServiceUnavailable)?;
+        Ok(parts.map(|parts| parts.split_whitespace().map(str::to_string).collect()))
+    }
+}
+
+fn upload_key(id: Uuid) -> String {
+    format!("resumable:{id}")
+}
+
+fn decode(id: Uuid, mut fields: HashMap<String, String>) -> Result<ResumableUpload> {
+    let mut take = |name: &str| fields.remove(name).context(format!("no {name}"));
+    let owner = take("owner")?;
+    let length = take("length")?.parse()?;
+    let offset = take("offset")?.parse()?;
+    let name = take("name")?;
+    let content_type = take("content_type")?;
+    let parts = take("parts")?;
+    let expires_at = take("expires_at")?.parse()?;
+    let description = fields.remove("description");
+    Ok(ResumableUpload {
+        id,
+        owner,
+        length,
+        offset,
+        meta: FileMeta {
+            name,
+            content_type,
+            description,
+        },
+        parts: parts.split_whitespace().map(str::to_string).collect(),
+        expires_at: DateTime::from_timestamp(expires_at, 0).context("expires_at out of range")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use chrono::Duration;
+
+    // needs Redis, see CACHE_URL
+    #[tokio::test]
+    #[ignore]
+    async fn test_chunks_advance_the_offset_once() {
+        dotenv::dotenv().ok();
+        let cache = Arc::new(crate::cache::create_cache(&Config::init()).await);
+        let repo = ResumableRepositoryImpl::new(cache);
+        let expires_at = DateTime::from_timestamp(Utc::now().timestamp() + 60, 0).unwrap();
+        let upload = ResumableUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            length: 10,
+            offset: 0,
+            meta: FileMeta {
+                name: "notes.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                description: Some("chunked".to_string()),
+            },
+            parts: vec![],
+            expires_at,
+        };
+        repo.create(&upload).await.unwrap();
+        assert_eq!(repo.find(upload.id).await.unwrap(), upload);
+
+        let later = expires_at + Duration::seconds(60);
+        assert!(repo.advance(upload.id, 0, 4, "a", later).await.unwrap());
+        assert!(!repo.advance(upload.id, 0, 4, "b", later).await.unwrap());
+        let found = repo.find(upload.id).await.unwrap();
+        assert_eq!(
+            (found.offset, found.parts, found.expires_at),
+            (4, vec!["a".to_string()], later)
+        );
+
+        assert!(
+            !repo
+                .find_expired(expires_at)
+                .await
+                .unwrap()
+                .contains(&upload.id)
+        );
+        assert!(repo.find_expired(later).await.unwrap().contains(&upload.id));
+        assert_eq!(repo.expire(upload.id, expires_at).await.unwrap(), None);
+        assert_eq!(
+            repo.expire(upload.id, later).await.unwrap(),
+            Some(vec!["a".to_string()])
+        );
+        assert!(!repo.find_expired(later).await.unwrap().contains(&upload.id));
+        assert_eq!(repo.expire(upload.id, later).await.unwrap(), None);
+
+        repo.create(&upload).await.unwrap();
+        assert!(repo.delete(upload.id).await.unwrap());
+        assert!(!repo.delete(upload.id).await.unwrap());
+        assert!(!repo.advance(upload.id, 4, 8, "c", later).await.unwrap());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::controllers::Pagination;
+use crate::db::postgres::Db;
+use crate::error::ApiError;
+use crate::models::attachment::AttachmentOwner;
+use crate::models::audit::AuditContext;
+use crate::models::upload::{NewUpload, Upload, UploadList};
+use crate::repositories::attachment;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mockall::automock;
+use uuid::Uuid;
+
+pub struct UploadRepositoryImpl {
+    pool: Db,
+}
+impl UploadRepositoryImpl {
+    pub fn new(pool: Db) -> Self {
+        Self { pool }
+    }
+}
+
+#[automock]
+#[async_trait]
+pub trait UploadRepository {
+    // Records that content is about to be stored under `key`, so it can be collected should the
+    // upload never be recorded
+    async fn reserve_blob(&self, key: &str) -> Result<()>;
+    // Records an upload whose content was stored under its id. When identical content is
+    // stored already the upload points at that instead, see `Upload::blob`, and what was stored
+    // under the id is no longer needed. Counts the upload towards its owner's usage, failing
+    // with `ApiError::QuotaExceeded` instead when that would go over `quota`.
+    async fn create(&self, upload: &NewUpload, quota: Option<u64>) -> Result<Upload>;
+    async fn find_by_id(&self, id: Uuid) -> Result<Upload>;
+    // newest first
+    async fn find_by_owner(&self, owner: &str, pagination: &Pagination) -> Result<UploadList>;
+    async fn delete(&self, id: Uuid) -> Result<()>;
+    // bytes stored by `owner`, identical files counted each time
+    async fn usage(&self, owner: &str) -> Result<u64>;
+    // Forgets the blobs no upload points at anymore, returning their storage keys. Those reserved
+    // for an upload still being stored are kept unless they were reserved before `pending_before`.
+    async fn collect_blobs(&self, pending_before: DateTime<Utc>) -> Result<Vec<String>>;
+    // to a car or part
+    async fn is_attached(&self, id: Uuid) -> Result<bool>;
+    // Records which thumbnails an upload has. They show in its car's or part's attachments, so
+    // that gets a new version too, audited as an update by the system like any other. `None`
+    // when the upload is gone, otherwise what it is attached to, if anything.
+    async fn set_thumbnails(
+        &self,
+        id: Uuid,
+        sizes: &[i32],
+    ) -> Result<Option<Option<AttachmentOwner>>>;
+}
+
+#[async_trait]
+impl UploadRepository for UploadRepositoryImpl {
+    async fn reserve_blob(&self, key: &str) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO blobs (key, size, refs, pending_since) VALUES ($1, 0, 0, now())",
+            key,
+        )
+        .execute(self.pool.writer())
+        .await?;
+        Ok(())
+    }
+
+    async fn create(&self, upload: &NewUpload, quota: Option<u64>) -> Result<Upload> {
+        let mut tx = self.pool.writer().begin().await?;
+        // the row lock makes concurrent uploads of the same owner take turns
+        let counted = sqlx::query_scalar!(
+            r#"
+            INSERT INTO storage_usage AS s (owner, bytes)
+            SELECT $1, $2 WHERE $3::BIGINT IS NULL OR $2 <= $3
+            ON CONFLICT (owner) DO UPDATE SET bytes = s.bytes + EXCLUDED.bytes
+            WHERE $3::BIGINT IS NULL OR s.bytes + EXCLUDED.bytes <= $3
+            RETURNING bytes
+            "#,
+            upload.owner,
+            upload.size,
+            quota.map(|quota| quota as i64),
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if counted.is_none() {
+            return Err(ApiError::QuotaExceeded(quota.unwrap_or_default()).into());
+        }
+        // the reservation makes way for the blob, or for the copy of one stored already below
+        let key = upload.id.to_string();
+        sqlx::query!("DELETE FROM blobs WHERE key = $1", key)
+            .execute(&mut *tx)
+            .await?;
+        // Takes the blob's row lock, so it cannot be collected before the upload is recorded.
+        // One collected already makes way for a new blob under this upload's key.
+        let blob = sqlx::query_scalar!(
+            r#"
+            INSERT INTO blobs (key, sha256, size, refs) VALUES ($1, $2, $3, 1)
+            ON CONFLICT (sha256) DO UPDATE SET refs = blobs.refs + 1
+            RETURNING key
+            "#,
+            key,
+            upload.sha256,
+            upload.size,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if blob != key {
+            // the copy is left to collect, should the caller not get to remove it
+            sqlx::query!(
+                "INSERT INTO blobs (key, size, refs) VALUES ($1, $2, 0)",
+                key,
+                upload.size,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let upload = sqlx::query_as!(
+            Upload,
+            r#"
+            INSERT INTO uploads
+                (id, owner, original_name, size, content_type, sha256, description, blob)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *
+            "#,
+            upload.id,
+            upload.owner,
+            upload.original_name,
+            upload.size,
+            upload.content_type,
+            upload.sha256,
+            upload.description,
+            blob,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(upload)
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Upload> {
+        let upload = sqlx::query_as!(Upload, "SELECT * FROM uploads WHERE id = $1", id)
+            .fetch_one(self.pool.reader())
+            .await?;
+        Ok(upload)
+    }
+
+    async fn find_by_owner(&self, owner: &str, pagination: &Pagination) -> Result<UploadList> {
+        let limit = pagination.per_page.unwrap_or(100);
+        let offset = (pagination.page.unwrap_or(1) - 1) * limit;
+
+        let data = sqlx::query_as!(
+            Upload,
+            r#"
+            SELECT * FROM uploads
+            WHERE owner = $1
+            ORDER BY created_at DESC, id
+            LIMIT $2 OFFSET $3
+            "#,
+            owner,
+            limit as i64,
+            offset as i64,
+        )
+        .fetch_all(self.pool.reader())
+        .await?;
+        let total = sqlx::query_scalar!("SELECT COUNT(*) FROM uploads WHERE owner = $1", owner)
+            .fetch_one(self.pool.reader())
+            .await?
+            .unwrap_or(0);
+        Ok(UploadList { data, total })
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<()> {
+        sqlx::query!(
+            r#"
+            WITH deleted AS (DELETE FROM uploads WHERE id = $1 RETURNING owner, size, blob),
+            usage AS (
+                UPDATE storage_usage s SET bytes = s.bytes - d.size
+                FROM deleted d WHERE s.owner = d.owner
+            )
+            UPDATE blobs b SET refs = b.refs - 1
+            FROM deleted d WHERE b.key = d.blob
+            "#,
+            id,
+        )
+        .execute(self.pool.writer())
+        .await?;
+        Ok(())
+    }
+
+    async fn usage(&self, owner: &str) -> Result<u64> {
+        let bytes = sqlx::query_scalar!("SELECT bytes FROM storage_usage WHERE owner = $1", owner)
+            .fetch_optional(self.pool.writer())
+            .await?;
+        Ok(bytes.unwrap_or(0) as u64)
+    }
+
+    async fn collect_blobs(&self, pending_before: DateTime<Utc>) -> Result<Vec<String>> {
+        let keys = sqlx::query_scalar!(
+            r#"
+            DELETE FROM blobs
+            WHERE refs = 0 AND (pending_since IS NULL OR pending_since < $1)
+            RETURNING key
+            "#,
+            pending_before,
+        )
+        .fetch_all(self.pool.writer())
+        .await?;
+        Ok(keys)
+    }
+
+    async fn is_attached(&self, id: Uuid) -> Result<bool> {
+        let attached = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM attachments WHERE upload_id = $1) AS "attached!""#,
+            id,
+        )
+        .fetch_one(self.pool.writer())
+        .await?;
+        Ok(attached)
+    }
+
+    async fn set_thumbnails(
+        &self,
+        id: Uuid,
+        sizes: &[i32],
+    ) -> Result<Option<Option<AttachmentOwner>>> {
+        let mut tx = self.pool.writer().begin().await?;
+        let row = sqlx::query!(
+            r#"
+            WITH updated AS (
+                UPDATE uploads SET thumbnails = $2 WHERE id = $1 RETURNING id
+            )
+            SELECT a.car_id AS "car_id?", a.part_id AS "part_id?"
+            FROM updated LEFT JOIN attachments a ON a.upload_id = updated.id
+            "#,
+            id,
+            sizes,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let owner = match (row.car_id, row.part_id) {
+            (Some(id), _) => Some(AttachmentOwner::Car(id)),
+            (_, Some(id)) => Some(AttachmentOwner::Part(id)),
+            _ => None,
+        };
+        if let Some(owner) = owner
+            && let Some(before) = attachment::lock_owner(&mut tx, owner).await?
+        {
+            attachment::touch(&mut tx, owner, &AuditContext::system(), &before).await?;
+        }
+        tx.commit().await?;
+        Ok(Some(owner))
+    }
+}
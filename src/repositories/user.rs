@@ -1,10 +1,16 @@
 use crate::controllers::{CommonQuery, Pagination};
 use crate::db::postgres::Db;
+use crate::models::audit::{AuditAction, AuditContext};
 use crate::models::user::{User, UserAuth, UserList, UserQuery};
 use crate::password;
+use crate::repositories::audit;
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use mockall::automock;
+use sqlx::PgConnection;
+
+const AUDIT_ENTITY: &str = "user";
 
 pub struct UserRepositoryImpl {
     pool: Db,
@@ -24,10 +30,12 @@ pub trait UserRepository {
         query: &CommonQuery,
         pagination: &Pagination,
     ) -> Result<UserList>;
-    async fn create(&self, user_data: &UserAuth) -> Result<User>;
-    async fn update(&self, user_data: &UserAuth) -> Result<User>;
-    async fn delete(&self, username: &str) -> Result<u64>;
-    async fn find_by_username(&self, username: &str) -> Result<User>;
+    async fn create(&self, user_data: &UserAuth, ctx: &AuditContext) -> Result<User>;
+    async fn update(&self, user_data: &UserAuth, ctx: &AuditContext) -> Result<User>;
+    async fn delete(&self, username: &str, ctx: &AuditContext) -> Result<u64>;
+    async fn restore(&self, username: &str, ctx: &AuditContext) -> Result<u64>;
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64>;
+    async fn find_by_username(&self, username: &str, include_deleted: bool) -> Result<User>;
 }
 
 #[async_trait]
@@ -44,109 +52,285 @@ impl UserRepository for UserRepositoryImpl {
         let data = if let Some(username) = &conditions.username {
             sqlx::query_as!(
                 User,
-                "SELECT * FROM users WHERE USERNAME LIKE $1 LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM users
+                WHERE USERNAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 format!("%{}%", username),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else if !query.ids.is_empty() {
             sqlx::query_as!(
                 User,
-                "SELECT * FROM users WHERE id IN (SELECT unnest($1::integer[])) LIMIT $2 OFFSET $3",
+                r#"
+                SELECT * FROM users
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                LIMIT $5 OFFSET $6
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32,
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         } else {
             sqlx::query_as!(
                 User,
-                "SELECT * FROM users LIMIT $1 OFFSET $2",
+                r#"
+                SELECT * FROM users
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                LIMIT $4 OFFSET $5
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
                 limit as i32,
                 offset as i32
             )
-            .fetch_all(&*self.pool)
+            .fetch_all(self.pool.reader())
             .await?
         };
         let total = if let Some(username) = &conditions.username {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM users WHERE USERNAME LIKE $1",
+                r#"
+                SELECT COUNT(*) FROM users
+                WHERE USERNAME LIKE $1
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 format!("%{}%", username),
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else if !query.ids.is_empty() {
             sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM users WHERE id IN (SELECT unnest($1::integer[]))",
+                r#"
+                SELECT COUNT(*) FROM users
+                WHERE id IN (SELECT unnest($1::integer[]))
+                  AND ($2::timestamptz IS NULL OR updated_at >= $2)
+                  AND ($3::timestamptz IS NULL OR updated_at < $3)
+                  AND ($4 OR deleted_at IS NULL)
+                "#,
                 &query.ids,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
             )
-            .fetch_one(&*self.pool)
+            .fetch_one(self.pool.reader())
             .await?
             .unwrap()
         } else {
-            sqlx::query_scalar!("SELECT COUNT(*) FROM users")
-                .fetch_one(&*self.pool)
-                .await?
-                .unwrap()
+            sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) FROM users
+                WHERE ($1::timestamptz IS NULL OR updated_at >= $1)
+                  AND ($2::timestamptz IS NULL OR updated_at < $2)
+                  AND ($3 OR deleted_at IS NULL)
+                "#,
+                query.updated_after,
+                query.updated_before,
+                query.include_deleted,
+            )
+            .fetch_one(self.pool.reader())
+            .await?
+            .unwrap()
         };
 
         Ok(UserList { data, total })
     }
 
-    async fn create(&self, user_data: &UserAuth) -> Result<User> {
+    async fn create(&self, user_data: &UserAuth, ctx: &AuditContext) -> Result<User> {
         let password_hash = password::hash(user_data.password.to_string()).await?;
 
+        let mut tx = self.pool.writer().begin().await?;
         let created_user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (username, password_hash )
-            VALUES ($1, $2)
-            RETURNING id, username, password_hash
+            INSERT INTO users (username, password_hash, created_by, updated_by)
+            VALUES ($1, $2, $3, $3)
+            RETURNING *
             "#,
         )
         .bind(&user_data.username)
         .bind(password_hash)
-        .fetch_one(&*self.pool)
+        .bind(&ctx.actor)
+        .fetch_one(&mut *tx)
         .await?;
+        audit::record(
+            &mut tx,
+            ctx,
+            AUDIT_ENTITY,
+            created_user.id,
+            AuditAction::Create,
+            None,
+            Some(&created_user),
+        )
+        .await?;
+        tx.commit().await?;
         Ok(created_user)
     }
 
-    async fn update(&self, user_data: &UserAuth) -> Result<User> {
+    async fn update(&self, user_data: &UserAuth, ctx: &AuditContext) -> Result<User> {
         let password_hash = password::hash(user_data.password.to_string()).await?;
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_username(&mut tx, &user_data.username).await?;
         let updated_user = sqlx::query_as::<_, User>(
             r#"
             UPDATE users
-            SET password_hash = $1
-            WHERE username = $2
-            RETURNING id, username, password_hash
+            SET password_hash = $2, updated_at = now(), updated_by = $3
+            WHERE username = $1 AND deleted_at IS NULL
+            RETURNING *
             "#,
         )
         .bind(&user_data.username)
         .bind(password_hash)
-        .fetch_one(&*self.pool)
+        .bind(&ctx.actor)
+        .fetch_one(&mut *tx)
+        .await?;
+        audit::record(
+            &mut tx,
+            ctx,
+            AUDIT_ENTITY,
+            updated_user.id,
+            AuditAction::Update,
+            before.as_ref(),
+            Some(&updated_user),
+        )
         .await?;
+        tx.commit().await?;
         Ok(updated_user)
     }
 
-    async fn delete(&self, username: &str) -> Result<u64> {
-        let query = sqlx::query("DELETE FROM users WHERE username = $1")
-            .bind(username)
-            .execute(&*self.pool)
+    async fn delete(&self, username: &str, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_username(&mut tx, username).await?;
+        let deleted_user = sqlx::query_as::<_, User>(
+            r#"
+            UPDATE users
+            SET deleted_at = now(), updated_at = now(), updated_by = $2
+            WHERE username = $1 AND deleted_at IS NULL
+            RETURNING *
+            "#,
+        )
+        .bind(username)
+        .bind(&ctx.actor)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(deleted) = &deleted_user {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                deleted.id,
+                AuditAction::Delete,
+                before.as_ref(),
+                Some(deleted),
+            )
             .await?;
-        Ok(query.rows_affected())
+        }
+        tx.commit().await?;
+        Ok(u64::from(deleted_user.is_some()))
     }
 
-    async fn find_by_username(&self, username: &str) -> Result<User> {
-        let row = sqlx::query_as!(User, "SELECT * FROM users WHERE username = $1", username)
-            .fetch_one(&*self.pool)
+    async fn restore(&self, username: &str, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let before = lock_by_username(&mut tx, username).await?;
+        let restored_user = sqlx::query_as::<_, User>(
+            r#"
+            UPDATE users
+            SET deleted_at = NULL, updated_at = now(), updated_by = $2
+            WHERE username = $1 AND deleted_at IS NOT NULL
+            RETURNING *
+            "#,
+        )
+        .bind(username)
+        .bind(&ctx.actor)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(restored) = &restored_user {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                restored.id,
+                AuditAction::Restore,
+                before.as_ref(),
+                Some(restored),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(u64::from(restored_user.is_some()))
+    }
+
+    async fn purge(&self, deleted_before: DateTime<Utc>, ctx: &AuditContext) -> Result<u64> {
+        let mut tx = self.pool.writer().begin().await?;
+        let purged_users =
+            sqlx::query_as::<_, User>("DELETE FROM users WHERE deleted_at < $1 RETURNING *")
+                .bind(deleted_before)
+                .fetch_all(&mut *tx)
+                .await?;
+        for purged in &purged_users {
+            audit::record(
+                &mut tx,
+                ctx,
+                AUDIT_ENTITY,
+                purged.id,
+                AuditAction::Purge,
+                Some(purged),
+                None,
+            )
             .await?;
+        }
+        tx.commit().await?;
+        Ok(purged_users.len() as u64)
+    }
+
+    async fn find_by_username(&self, username: &str, include_deleted: bool) -> Result<User> {
+        let row = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE username = $1 AND ($2 OR deleted_at IS NULL)",
+            username,
+            include_deleted,
+        )
+        .fetch_one(self.pool.reader())
+        .await?;
         Ok(row)
     }
 }
 
+// Reads the current row and locks it until the transaction ends, so the audit entry's "before"
+// is exactly what the following write replaces.
+async fn lock_by_username(conn: &mut PgConnection, username: &str) -> Result<Option<User>> {
+    let row = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1 FOR UPDATE")
+        .bind(username)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,7 +341,10 @@ mod tests {
         let conditions = UserQuery {
             username: Some("Tesla".to_string()),
         };
-        let query = CommonQuery { ids: [].to_vec() };
+        let query = CommonQuery {
+            ids: [].to_vec(),
+            ..Default::default()
+        };
         let pagination = Pagination {
             page: None,
             per_page: None,
@@ -170,11 +357,23 @@ mod tests {
                 id: 1,
                 username: "Tesla Model S".to_string(),
                 password_hash: "None".to_string(),
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+                created_by: None,
+                updated_by: None,
+                deleted_at: None,
+                is_admin: false,
             },
             User {
                 id: 2,
                 username: "Tesla Model 3".to_string(),
                 password_hash: "None".to_string(),
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+                created_by: None,
+                updated_by: None,
+                deleted_at: None,
+                is_admin: false,
             },
             ],
             total: 99,
@@ -1,5 +1,8 @@
-use crate::controllers::{auth, cars, parts, users, utils};
-use axum::Router;
+use crate::controllers::{
+    audit, auth, cache, cars, files, idempotency, parts, resumable, users, utils,
+};
+use axum::extract::DefaultBodyLimit;
+use axum::{Router, middleware};
 use tower_http::services::ServeDir;
 use tower_http::services::ServeFile;
 use utoipa::openapi::security::Http;
@@ -18,6 +21,9 @@ pub const AUTH_TAG: &str = "Auth";
 pub const USERS_TAG: &str = "Users";
 pub const CARS_TAG: &str = "Cars";
 pub const PARTS_TAG: &str = "Parts";
+pub const AUDIT_TAG: &str = "Audit";
+pub const CACHE_TAG: &str = "Cache";
+pub const FILES_TAG: &str = "Files";
 
 pub struct SecurityAddon;
 impl Modify for SecurityAddon {
@@ -39,7 +45,10 @@ impl Modify for SecurityAddon {
         (name = AUTH_TAG, description = "Auth management API"),
         (name = USERS_TAG, description = "Users management API"),
         (name = CARS_TAG, description = "Cars management API"),
-        (name = PARTS_TAG, description = "Parts management API")
+        (name = PARTS_TAG, description = "Parts management API"),
+        (name = AUDIT_TAG, description = "Audit log API"),
+        (name = CACHE_TAG, description = "Cache management API"),
+        (name = FILES_TAG, description = "File uploads API")
     )
 )]
 struct ApiDoc;
@@ -50,7 +59,10 @@ pub fn router() -> Router {
         .nest("/auth", auth_routes())
         .nest("/users", user_routes())
         .nest("/cars", car_routes())
-        .nest("/parts", part_routes());
+        .nest("/parts", part_routes())
+        .nest("/audit", audit_routes())
+        .nest("/cache", cache_routes())
+        .nest("/files", file_routes());
 
     let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .nest("/api", app)
@@ -79,24 +91,69 @@ fn user_routes() -> OpenApiRouter {
         .routes(routes!(users::view))
         .routes(routes!(users::update))
         .routes(routes!(users::delete))
+        .routes(routes!(users::restore))
 }
 
 fn car_routes() -> OpenApiRouter {
     OpenApiRouter::new()
         .routes(routes!(cars::list))
-        .routes(routes!(cars::create))
+        .merge(
+            OpenApiRouter::new()
+                .routes(routes!(cars::create))
+                .route_layer(middleware::from_fn(idempotency::idempotent)),
+        )
         .routes(routes!(cars::view))
         .routes(routes!(cars::update))
         .routes(routes!(cars::delete))
+        .routes(routes!(cars::restore))
+        .routes(routes!(cars::attachments, cars::attach))
+        .routes(routes!(cars::attachment, cars::detach))
 }
 
 fn part_routes() -> OpenApiRouter {
     OpenApiRouter::new()
         .routes(routes!(parts::list))
-        .routes(routes!(parts::create))
+        .merge(
+            OpenApiRouter::new()
+                .routes(routes!(parts::create))
+                .route_layer(middleware::from_fn(idempotency::idempotent)),
+        )
         .routes(routes!(parts::view))
         .routes(routes!(parts::update))
         .routes(routes!(parts::delete))
+        .routes(routes!(parts::restore))
+        .routes(routes!(parts::attachments, parts::attach))
+        .routes(routes!(parts::attachment, parts::detach))
+}
+
+fn audit_routes() -> OpenApiRouter {
+    OpenApiRouter::new().routes(routes!(audit::list))
+}
+
+fn cache_routes() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(cache::stats))
+        .routes(routes!(cache::lookup))
+        .routes(routes!(cache::evict))
+        .routes(routes!(cache::warm))
+}
+fn file_routes() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(files::upload))
+        .routes(routes!(files::list))
+        .routes(routes!(files::download))
+        .routes(routes!(files::thumbnail))
+        .routes(routes!(files::link))
+        .routes(routes!(files::download_signed))
+        .routes(routes!(files::delete))
+        .routes(routes!(resumable::create))
+        .routes(routes!(
+            resumable::status,
+            resumable::append,
+            resumable::terminate
+        ))
+        // sizes are limited per file while streaming, see `UploadSettings`
+        .layer(DefaultBodyLimit::disable())
 }
 
 fn auth_routes() -> OpenApiRouter {
@@ -0,0 +1,192 @@
+use crate::cache::{CacheImpl, Entity};
+use crate::error::ApiError;
+use crate::models::attachment::{AttachmentOwner, AttachmentSummary};
+use crate::models::audit::AuditContext;
+use crate::models::upload::Upload;
+use crate::repositories::attachment::AttachmentRepository;
+use crate::services;
+use crate::storage::Storage;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+pub async fn find_all<R: AttachmentRepository>(
+    repo: Arc<R>,
+    owner: AttachmentOwner,
+) -> Result<Vec<AttachmentSummary>> {
+    repo.find_by_owner(owner).await
+}
+
+// The upload behind one of `owner`'s attachments
+pub async fn find_upload<R: AttachmentRepository>(
+    repo: Arc<R>,
+    owner: AttachmentOwner,
+    upload_id: Uuid,
+) -> Result<Upload> {
+    repo.find_upload(owner, upload_id).await
+}
+
+// The caller is expected to have checked that `upload` is theirs to attach
+pub async fn attach<R: AttachmentRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    owner: AttachmentOwner,
+    upload: &Upload,
+    ctx: &AuditContext,
+) -> Result<AttachmentSummary> {
+    let attachment = repo.attach(owner, upload.id, ctx).await?;
+    invalidate(&cache, owner).await;
+    attachment.ok_or_else(|| ApiError::NotFound.into())
+}
+
+// The file itself stays with whoever uploaded it
+pub async fn detach<R: AttachmentRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    owner: AttachmentOwner,
+    upload_id: Uuid,
+    ctx: &AuditContext,
+) -> Result<()> {
+    let affected_rows = repo.detach(owner, upload_id, ctx).await?;
+    invalidate(&cache, owner).await;
+    if affected_rows == 0 {
+        return Err(ApiError::NotFound.into());
+    }
+    Ok(())
+}
+
+// Removes the files attached to cars and parts that were soft-deleted before the cutoff, ahead
+// of the rows themselves being purged. Their content is left to `uploads::collect_garbage`.
+// While an owner is only soft-deleted its attachments are kept, so restoring it brings them
+// back.
+pub async fn purge<R: AttachmentRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    deleted_before: DateTime<Utc>,
+) -> Result<usize> {
+    let uploads = repo.purge(deleted_before).await?;
+    for upload in &uploads {
+        services::uploads::remove_thumbnails(storage, upload).await;
+    }
+    if !uploads.is_empty() {
+        info!(
+            "Purged {} files attached to deleted cars and parts",
+            uploads.len()
+        );
+    }
+    Ok(uploads.len())
+}
+
+// Attaching bumps the owner's version, see `repositories::attachment`, and so do thumbnails of
+// attached images
+pub async fn invalidate(cache: &CacheImpl, owner: AttachmentOwner) {
+    let (entity, id) = match owner {
+        AttachmentOwner::Car(id) => (Entity::Car, id),
+        AttachmentOwner::Part(id) => (Entity::Part, id),
+    };
+    cache.invalidate(&entity.key(id)).await;
+    cache.invalidate_tag(&entity.list_tag()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::upload::NewUpload;
+    use crate::repositories::attachment::MockAttachmentRepository;
+    use crate::services::thumbnails;
+    use crate::storage::MemoryStorage;
+    use crate::tests::fixture::cache::cache_fixture;
+    use crate::tests::fixture::upload::upload_fixture;
+    use axum::body::Bytes;
+    use futures::{StreamExt, stream};
+    use mockall::predicate;
+
+    fn upload() -> Upload {
+        upload_fixture(&NewUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            original_name: "photo.jpg".to_string(),
+            size: 3,
+            content_type: "image/jpeg".to_string(),
+            sha256: String::new(),
+            description: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_attach_to_a_missing_owner_is_not_found() {
+        let mut mock_repo_impl = MockAttachmentRepository::new();
+        mock_repo_impl
+            .expect_attach()
+            .withf(|owner, _, ctx| *owner == AttachmentOwner::Car(7) && ctx.actor == "alice")
+            .times(1)
+            .returning(|_, _, _| Ok(None));
+        let ctx = AuditContext {
+            actor: "alice".to_string(),
+            request_id: None,
+        };
+        let err = attach(
+            Arc::new(mock_repo_impl),
+            cache_fixture(),
+            AttachmentOwner::Car(7),
+            &upload(),
+            &ctx,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detach_what_is_not_attached_is_not_found() {
+        let mut mock_repo_impl = MockAttachmentRepository::new();
+        mock_repo_impl
+            .expect_detach()
+            .with(
+                predicate::eq(AttachmentOwner::Part(2)),
+                predicate::always(),
+                predicate::always(),
+            )
+            .returning(|_, _, _| Ok(0));
+        let err = detach(
+            Arc::new(mock_repo_impl),
+            cache_fixture(),
+            AttachmentOwner::Part(2),
+            Uuid::new_v4(),
+            &AuditContext::system(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_purge_removes_the_thumbnails() {
+        let storage = MemoryStorage::default();
+        let mut upload = upload();
+        upload.thumbnails = vec![128];
+        for key in [upload.id.to_string(), thumbnails::key(upload.id, 128)] {
+            let body = stream::once(async { Ok(Bytes::from_static(b"jpg")) }).boxed();
+            storage.put(&key, body).await.unwrap();
+        }
+        let mut mock_repo_impl = MockAttachmentRepository::new();
+        mock_repo_impl
+            .expect_purge()
+            .times(1)
+            .returning(move |_| Ok(vec![upload.clone()]));
+        let purged = purge(Arc::new(mock_repo_impl), &storage, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        // the content may be shared, it is left to `collect_garbage`
+        assert_eq!(storage.len(), 1);
+    }
+}
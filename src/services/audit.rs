@@ -0,0 +1,54 @@
+use crate::controllers::Pagination;
+use crate::models::audit::{AuditList, AuditQuery};
+use crate::repositories::audit::AuditRepository;
+use anyhow::Result;
+use std::sync::Arc;
+
+pub async fn find_all<R: AuditRepository>(
+    repo: Arc<R>,
+    conditions: &AuditQuery,
+    pagination: &Pagination,
+) -> Result<AuditList> {
+    let entries = repo.find_all(conditions, pagination).await?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::audit::MockAuditRepository;
+    use mockall::predicate;
+
+    #[tokio::test]
+    async fn test_find_all_passes_filters() {
+        let mut mock_repo_impl = MockAuditRepository::new();
+        let conditions = AuditQuery {
+            entity: Some("car".to_string()),
+            entity_id: Some(1),
+            ..Default::default()
+        };
+        let pagination = Pagination {
+            page: None,
+            per_page: None,
+            field: None,
+            order: None,
+        };
+        mock_repo_impl
+            .expect_find_all()
+            .with(
+                predicate::eq(conditions.clone()),
+                predicate::eq(pagination.clone()),
+            )
+            .times(1)
+            .returning(|_, _| {
+                Ok(AuditList {
+                    data: vec![],
+                    total: 0,
+                })
+            });
+        let entries = find_all(Arc::new(mock_repo_impl), &conditions, &pagination)
+            .await
+            .unwrap();
+        assert_eq!(entries.total, 0);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::cache::{CacheImpl, Entity};
+use crate::error::{ApiError, is_not_found};
+use crate::models::cache::{EvictRequest, Evicted, WarmResult};
+use crate::repositories::car::CarRepository;
+use crate::repositories::part::PartRepository;
+use crate::repositories::user::UserRepository;
+use crate::services;
+use anyhow::Result;
+use std::sync::Arc;
+
+// Upper bound on the ids of one warm request, each one may cost a query
+const MAX_WARM_IDS: usize = 1000;
+
+pub async fn evict(cache: Arc<CacheImpl>, request: &EvictRequest) -> Result<Evicted> {
+    let keys = match (request.entity, &request.id, &request.prefix) {
+        (Some(entity), Some(id), None) => cache.invalidate(&entity.key(id)).await,
+        (Some(entity), None, None) => cache.invalidate_tag(entity.prefix()).await,
+        // the cache's own bookkeeping (tag sets, locks, counters) is off limits
+        (None, None, Some(prefix)) if is_entity_prefix(prefix) => {
+            cache.invalidate_prefix(prefix).await
+        }
+        _ => {
+            let message =
+                "Give an entity, an entity and an id, or a prefix starting with an entity";
+            return Err(ApiError::BadRequest(message.to_string()).into());
+        }
+    };
+    Ok(Evicted { keys })
+}
+
+fn is_entity_prefix(prefix: &str) -> bool {
+    Entity::ALL
+        .iter()
+        .any(|entity| prefix.starts_with(entity.prefix()))
+}
+
+// Loads the given records into the cache through the same path as their `view`, so they are
+// cached exactly as a read would cache them.
+pub async fn warm<C, P, U>(
+    car_repo: Arc<C>,
+    part_repo: Arc<P>,
+    user_repo: Arc<U>,
+    cache: Arc<CacheImpl>,
+    entity: Entity,
+    ids: &[String],
+) -> Result<WarmResult>
+where
+    C: CarRepository,
+    P: PartRepository,
+    U: UserRepository,
+{
+    if ids.len() > MAX_WARM_IDS {
+        let message = format!("At most {} ids can be warmed at once", MAX_WARM_IDS);
+        return Err(ApiError::BadRequest(message).into());
+    }
+    let mut result = WarmResult::default();
+    for id in ids {
+        let loaded = match entity {
+            Entity::Car => match id.parse() {
+                Ok(car_id) => services::cars::view(car_repo.clone(), cache.clone(), car_id, false)
+                    .await
+                    .map(drop),
+                Err(_) => Err(ApiError::NotFound.into()),
+            },
+            Entity::Part => match id.parse() {
+                Ok(part_id) => {
+                    services::parts::view(part_repo.clone(), cache.clone(), part_id, false)
+                        .await
+                        .map(drop)
+                }
+                Err(_) => Err(ApiError::NotFound.into()),
+            },
+            Entity::User => services::users::view(user_repo.clone(), cache.clone(), id, false)
+                .await
+                .map(drop),
+        };
+        match loaded {
+            Ok(()) => result.warmed.push(id.clone()),
+            Err(err) if is_not_found(&err) => result.missing.push(id.clone()),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::car::MockCarRepository;
+    use crate::repositories::part::MockPartRepository;
+    use crate::repositories::user::MockUserRepository;
+    use crate::tests::fixture::cache::local_cache_fixture;
+    use crate::tests::fixture::car::car_fixture;
+
+    #[tokio::test]
+    async fn test_warm_caches_existing_cars() {
+        let mut mock_car_repo = MockCarRepository::new();
+        mock_car_repo
+            .expect_find_by_id()
+            .times(2)
+            .returning(|car_id, _| match car_id {
+                1 => Ok(car_fixture(1)),
+                _ => Err(sqlx::Error::RowNotFound.into()),
+            });
+        let cache = local_cache_fixture();
+        let ids = ["1", "2", "x"].map(String::from);
+
+        let result = warm(
+            Arc::new(mock_car_repo),
+            Arc::new(MockPartRepository::new()),
+            Arc::new(MockUserRepository::new()),
+            cache.clone(),
+            Entity::Car,
+            &ids,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.warmed, ["1"]);
+        assert_eq!(result.missing, ["2", "x"]);
+        assert!(cache.lookup(&Entity::Car.key(1)).await.local);
+    }
+
+    #[tokio::test]
+    async fn test_evict_needs_an_entity_or_a_prefix() {
+        let cache = local_cache_fixture();
+        for request in [
+            EvictRequest::default(),
+            EvictRequest {
+                prefix: Some("tag:".to_string()),
+                ..Default::default()
+            },
+            EvictRequest {
+                entity: Some(Entity::Car),
+                prefix: Some("car:".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let err = evict(cache.clone(), &request).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ApiError>(),
+                Some(ApiError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict_by_prefix() {
+        let cache = local_cache_fixture();
+        for key in [Entity::Car.key(1), Entity::Car.key("list:ab")] {
+            cache
+                .get_or_load(Entity::Car, &key, &[], || async { Ok(1) })
+                .await
+                .unwrap();
+        }
+        let request = EvictRequest {
+            prefix: Some(Entity::Car.key("list")),
+            ..Default::default()
+        };
+        let evicted = evict(cache.clone(), &request).await.unwrap();
+        assert_eq!(evicted.keys, 1);
+        assert!(cache.lookup(&Entity::Car.key(1)).await.local);
+        assert!(!cache.lookup(&Entity::Car.key("list:ab")).await.local);
+    }
+}
@@ -1,60 +1,60 @@
-use crate::cache::CacheImpl;
+use crate::cache::{CacheImpl, Entity};
 use crate::controllers::{CommonQuery, Pagination};
+use crate::error::ApiError;
+use crate::models::audit::AuditContext;
 use crate::models::car::{Car, CarList, CarQuery, NewCar};
 use crate::repositories::car::CarRepository;
 use anyhow::{Result, bail};
-use redis::AsyncCommands;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 use tracing::info;
 use validator::Validate;
 
-const CAR_CACHE_TTL: u64 = 60;
-
 pub async fn find_all<R: CarRepository>(
     repo: Arc<R>,
+    cache: Arc<CacheImpl>,
     conditions: &CarQuery,
     query: &CommonQuery,
     pagination: &Pagination,
 ) -> Result<CarList> {
-    let cars = repo.find_all(conditions, query, pagination).await?;
-    Ok(cars)
+    // Lists with soft-deleted rows are never cached
+    if query.include_deleted {
+        return repo.find_all(conditions, query, pagination).await;
+    }
+    let key = Entity::Car.list_key(&(conditions, query.normalized(), pagination.normalized()))?;
+    cache
+        .get_or_load(Entity::Car, &key, &[Entity::Car.list_tag()], || {
+            repo.find_all(conditions, query, pagination)
+        })
+        .await
 }
 
 pub async fn view<R: CarRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     car_id: i32,
+    include_deleted: bool,
 ) -> Result<Car> {
-    // Construct the cache key
-    let cache_key = format!("car:{}", car_id);
-    let mut redis_conn = cache.redis_pool.get().await?;
-    // Attempt to retrieve the car data from cache
-    let maybe_cached: Option<String> = redis_conn.get::<String, _>(cache_key.clone()).await?;
-
-    if let Some(cached_json) = maybe_cached {
-        // Deserialize the JSON string back to a Car object
-        let cached_car: Car = serde_json::from_str(&cached_json)?;
-        info!("Found cached car {}: {:?}", car_id, cached_car);
-        return Ok(cached_car);
+    // Soft-deleted rows are never cached
+    if include_deleted {
+        return repo.find_by_id(car_id, true).await;
     }
-    info!("Fetching car {} from db...", car_id);
-    // If not in cache, query the database
-    let car = repo.find_by_id(car_id).await?;
-
-    // Serialize the car object to a JSON string
-    let car_json = serde_json::to_string(&car)?;
-
-    // Store the serialized Car in the cache
-    redis_conn
-        .set_ex::<_, _, ()>(&cache_key, car_json, CAR_CACHE_TTL)
-        .await?;
-
-    Ok(car)
+    cache
+        .get_or_load(Entity::Car, &Entity::Car.key(car_id), &[], || {
+            repo.find_by_id(car_id, false)
+        })
+        .await
 }
 
-pub async fn create<R: CarRepository>(repo: Arc<R>, new_car: &NewCar) -> Result<Car> {
+pub async fn create<R: CarRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    new_car: &NewCar,
+    ctx: &AuditContext,
+) -> Result<Car> {
     new_car.validate()?;
-    let car = repo.create(new_car).await?;
+    let car = repo.create(new_car, ctx).await?;
+    cache.invalidate_tag(&Entity::Car.list_tag()).await;
     Ok(car)
 }
 
@@ -62,31 +62,74 @@ pub async fn update<R: CarRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     car: &Car,
+    versions: &[i32],
+    ctx: &AuditContext,
 ) -> Result<Car> {
-    // Construct the cache key
-    let cache_key = format!("car:{}", car.id);
-    let mut redis_conn = cache.redis_pool.get().await?;
-    // Attempt to retrieve the car data from cache
-    let _: Option<String> = redis_conn.del::<String, _>(cache_key.clone()).await?;
-
-    let car = repo.update(car).await?;
-    Ok(car)
+    // Invalidate only once the write has committed, see `CacheImpl::invalidate`
+    let updated = repo.update(car, versions, ctx).await?;
+    cache.invalidate(&Entity::Car.key(car.id)).await;
+    cache.invalidate_tag(&Entity::Car.list_tag()).await;
+    match updated {
+        Some(car) => Ok(car),
+        None => Err(version_conflict(repo, car.id).await),
+    }
 }
 
 pub async fn delete<R: CarRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     car_id: i32,
+    versions: &[i32],
+    ctx: &AuditContext,
 ) -> Result<u64> {
-    // Construct the cache key
-    let cache_key = format!("car:{}", car_id);
-    let mut redis_conn = cache.redis_pool.get().await?;
-    // Attempt to retrieve the car data from cache
-    let _: Option<String> = redis_conn.del::<String, _>(cache_key.clone()).await?;
+    let affected_rows = repo.delete(car_id, versions, ctx).await?;
+    cache.invalidate(&Entity::Car.key(car_id)).await;
+    cache.invalidate_tag(&Entity::Car.list_tag()).await;
+    if affected_rows == 0 {
+        return Err(version_conflict(repo, car_id).await);
+    }
+    check_affected_rows(affected_rows, car_id)
+}
 
-    let affected_rows = repo.delete(car_id).await?;
+pub async fn restore<R: CarRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    car_id: i32,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.restore(car_id, ctx).await?;
+    cache.invalidate_tag(&Entity::Car.list_tag()).await;
+    check_affected_rows(affected_rows, car_id)
+}
+
+// Hard-deletes cars that were soft-deleted before the cutoff
+pub async fn purge<R: CarRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    deleted_before: DateTime<Utc>,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.purge(deleted_before, ctx).await?;
+    if affected_rows > 0 {
+        info!("Purged {} deleted cars", affected_rows);
+        // their parts were detached (`car_id` set to null), cached copies are stale
+        cache.invalidate_tag(Entity::Part.prefix()).await;
+    }
+    Ok(affected_rows)
+}
+
+// A conditional write touched nothing: either the car is gone or someone else changed it first
+async fn version_conflict<R: CarRepository>(repo: Arc<R>, car_id: i32) -> anyhow::Error {
+    match repo.find_by_id(car_id, false).await {
+        Ok(_) => ApiError::PreconditionFailed.into(),
+        Err(err) => err,
+    }
+}
+
+fn check_affected_rows(affected_rows: u64, car_id: i32) -> Result<u64> {
     if affected_rows == 0 {
-        bail!("No rows affected, car with ID {} not found", car_id);
+        info!("No rows affected, car with ID {} not found", car_id);
+        return Err(ApiError::NotFound.into());
     } else if affected_rows > 1 {
         bail!("Unexpected number of rows affected: {}", affected_rows);
     }
@@ -97,7 +140,8 @@ pub async fn delete<R: CarRepository>(
 mod tests {
     use super::*;
     use crate::repositories::car::MockCarRepository;
-    use crate::tests::fixture::car::cars_fixture;
+    use crate::tests::fixture::cache::{cache_fixture, local_cache_fixture};
+    use crate::tests::fixture::car::{car_fixture, cars_fixture};
 
     #[tokio::test]
     async fn test_find_all() {
@@ -106,16 +150,153 @@ mod tests {
             .expect_find_all()
             .returning(|_, _, _| Ok(cars_fixture(5)));
         let conditions = CarQuery { name: None };
-        let query = CommonQuery { ids: [].to_vec() };
+        let query = CommonQuery {
+            ids: [].to_vec(),
+            ..Default::default()
+        };
         let pagination = Pagination {
             page: None,
             per_page: None,
             field: None,
             order: None,
         };
-        let cars = find_all(Arc::new(mock_repo_impl), &conditions, &query, &pagination)
+        let cars = find_all(
+            Arc::new(mock_repo_impl),
+            cache_fixture(),
+            &conditions,
+            &query,
+            &pagination,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cars.data.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_find_all_is_cached_until_a_write() {
+        let mut mock_repo_impl = MockCarRepository::new();
+        mock_repo_impl
+            .expect_find_all()
+            .times(2)
+            .returning(|_, _, _| Ok(cars_fixture(2)));
+        mock_repo_impl
+            .expect_create()
+            .returning(|_, _| Ok(car_fixture(3)));
+        let repo = Arc::new(mock_repo_impl);
+        let cache = local_cache_fixture();
+        let conditions = CarQuery { name: None };
+        let pagination = Pagination {
+            page: None,
+            per_page: None,
+            field: None,
+            order: None,
+        };
+        let query = |ids: Vec<i32>| CommonQuery {
+            ids,
+            ..Default::default()
+        };
+        let list = async |ids| {
+            find_all(
+                repo.clone(),
+                cache.clone(),
+                &conditions,
+                &query(ids),
+                &pagination,
+            )
+            .await
+            .unwrap()
+        };
+
+        list(vec![1, 2]).await;
+        // same query with the ids in another order
+        list(vec![2, 1, 2]).await;
+        let new_car = NewCar {
+            name: "Ferrari Testarossa".to_string(),
+            color: None,
+            year: None,
+        };
+        create(
+            repo.clone(),
+            cache.clone(),
+            &new_car,
+            &AuditContext::system(),
+        )
+        .await
+        .unwrap();
+        list(vec![1, 2]).await;
+    }
+
+    #[tokio::test]
+    async fn test_create_records_actor() {
+        let mut mock_repo_impl = MockCarRepository::new();
+        mock_repo_impl
+            .expect_create()
+            .withf(|_, ctx| ctx.actor == "alice")
+            .times(1)
+            .returning(|_, ctx| {
+                let mut car = car_fixture(1);
+                car.created_by = Some(ctx.actor.clone());
+                car.updated_by = Some(ctx.actor.clone());
+                Ok(car)
+            });
+        let new_car = NewCar {
+            name: "Ferrari Testarossa".to_string(),
+            color: None,
+            year: None,
+        };
+        let ctx = AuditContext {
+            actor: "alice".to_string(),
+            request_id: Some("req-1".to_string()),
+        };
+        let car = create(Arc::new(mock_repo_impl), cache_fixture(), &new_car, &ctx)
             .await
             .unwrap();
-        assert_eq!(cars.data.len(), 5);
+        assert_eq!(car.created_by.as_deref(), Some("alice"));
+        assert_eq!(car.updated_by.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_restore_missing_car_is_not_found() {
+        let mut mock_repo_impl = MockCarRepository::new();
+        mock_repo_impl
+            .expect_restore()
+            .withf(|car_id, ctx| *car_id == 7 && ctx.actor == "admin")
+            .returning(|_, _| Ok(0));
+        let ctx = AuditContext {
+            actor: "admin".to_string(),
+            request_id: None,
+        };
+        let err = restore(Arc::new(mock_repo_impl), cache_fixture(), 7, &ctx)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_view_and_update_without_redis() {
+        let mut mock_repo_impl = MockCarRepository::new();
+        mock_repo_impl
+            .expect_find_by_id()
+            .times(2)
+            .returning(|car_id, _| Ok(car_fixture(car_id)));
+        mock_repo_impl
+            .expect_update()
+            .returning(|car, _, _| Ok(Some(car.clone())));
+        let repo = Arc::new(mock_repo_impl);
+        let cache = cache_fixture();
+
+        // nothing is cached, both views load from the repository
+        for _ in 0..2 {
+            let car = view(repo.clone(), cache.clone(), 3, false).await.unwrap();
+            assert_eq!(car.id, 3);
+        }
+        let ctx = AuditContext::system();
+        let car = update(repo, cache, &car_fixture(3), &[1], &ctx)
+            .await
+            .unwrap();
+        assert_eq!(car.id, 3);
     }
 }
@@ -0,0 +1,212 @@
+use crate::error::ApiError;
+use anyhow::{Result, bail};
+use std::str::FromStr;
+
+// Bytes read from the start of an upload to tell what it is
+pub const SNIFF_BYTES: usize = 8192;
+
+const GENERIC: &str = "application/octet-stream";
+
+// Content types an endpoint accepts, each `type/subtype` or `type/*`. Empty accepts anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllowedTypes(Vec<String>);
+
+impl FromStr for AllowedTypes {
+    type Err = String;
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let types = list
+            .split(',')
+            .map(|pattern| pattern.trim().to_ascii_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| match pattern.split_once('/') {
+                Some((kind, subtype))
+                    if !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/') =>
+                {
+                    Ok(pattern)
+                }
+                _ => Err(format!("{pattern:?} is not a content type")),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self(types))
+    }
+}
+
+impl AllowedTypes {
+    pub fn allows(&self, content_type: &str) -> bool {
+        let kind = content_type.split('/').next().unwrap_or_default();
+        self.0.is_empty()
+            || self.0.iter().any(|pattern| {
+                pattern == "*/*"
+                    || pattern == content_type
+                    || pattern.strip_suffix("/*") == Some(kind)
+            })
+    }
+}
+
+// The content type to store for a file starting with `head`. Whatever the client declared is
+// only taken when the bytes agree with it: a declared type the content contradicts is rejected,
+// and one that cannot be checked becomes `application/octet-stream`. Text cannot be told apart
+// by its bytes, so any textual type is taken for it.
+pub fn content_type(head: &[u8], declared: &str, allowed: &AllowedTypes) -> Result<String> {
+    let declared = essence(declared);
+    let detected = infer::get(head).map(|kind| kind.mime_type());
+    let content_type = match detected {
+        Some(detected) if is_textual(detected) && is_textual(&declared) => declared,
+        Some(detected) if declared == GENERIC || declared == detected => detected.to_string(),
+        Some(detected) => bail!(mismatch(detected, &declared)),
+        None if is_text(head) && is_textual(&declared) => declared,
+        None if is_text(head) && declared == GENERIC => "text/plain".to_string(),
+        None if is_text(head) => bail!(mismatch("text", &declared)),
+        None if declared == GENERIC => GENERIC.to_string(),
+        None if infer::is_mime_supported(&declared) => bail!(ApiError::UnsupportedMediaType(
+            format!("The file is not {declared}")
+        )),
+        None => GENERIC.to_string(),
+    };
+    if !allowed.allows(&content_type) {
+        bail!(ApiError::UnsupportedMediaType(format!(
+            "Files of type {content_type} are not accepted here"
+        )));
+    }
+    Ok(content_type)
+}
+
+// Whether a file declared as `declared` could be accepted, to turn it away before it is sent.
+// Only a declared type can be checked this early, the bytes have the last word.
+pub fn may_be_allowed(declared: &str, allowed: &AllowedTypes) -> bool {
+    let declared = essence(declared);
+    declared == GENERIC || allowed.allows(&declared)
+}
+
+fn mismatch(detected: &str, declared: &str) -> ApiError {
+    ApiError::UnsupportedMediaType(format!("The file is {detected}, not {declared}"))
+}
+
+// `type/subtype` without parameters, lowercased, with the odd spellings clients send fixed up
+fn essence(content_type: &str) -> String {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.as_str() {
+        "" => GENERIC.to_string(),
+        "image/jpg" | "image/pjpeg" => "image/jpeg".to_string(),
+        _ => essence,
+    }
+}
+
+fn is_textual(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || matches!(content_type, "application/json" | "application/xml")
+        || content_type.ends_with("+json")
+        || content_type.ends_with("+xml")
+}
+
+// UTF-8 without control characters other than whitespace and escapes, allowing for `head`
+// ending in the middle of a character
+fn is_text(head: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    };
+    valid
+        && !head
+            .iter()
+            .any(|byte| *byte < 0x20 && !b"\t\n\r\x0c\x1b".contains(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const PDF: &[u8] = b"%PDF-1.7\n";
+
+    fn allowed(list: &str) -> AllowedTypes {
+        list.parse().unwrap()
+    }
+
+    fn rejected(result: Result<String>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::UnsupportedMediaType(_))
+        )
+    }
+
+    #[test]
+    fn test_allowed_types() {
+        let types = allowed("image/*, application/PDF");
+        assert!(types.allows("image/png"));
+        assert!(types.allows("application/pdf"));
+        assert!(!types.allows("text/plain"));
+        assert!(!types.allows("application/pdf+zip"));
+        assert!(allowed("").allows("text/plain"));
+        assert!(allowed("*/*").allows("text/plain"));
+        assert!("image".parse::<AllowedTypes>().is_err());
+        assert!("image/png/x".parse::<AllowedTypes>().is_err());
+    }
+
+    #[test]
+    fn test_content_type_comes_from_the_bytes() {
+        let any = AllowedTypes::default();
+        assert_eq!(content_type(PNG, "image/png", &any).unwrap(), "image/png");
+        assert_eq!(content_type(PNG, "", &any).unwrap(), "image/png");
+        assert_eq!(content_type(PNG, GENERIC, &any).unwrap(), "image/png");
+        assert_eq!(
+            content_type(PDF, "application/pdf; x=1", &any).unwrap(),
+            "application/pdf"
+        );
+        assert!(rejected(content_type(PNG, "application/pdf", &any)));
+        assert!(rejected(content_type(b"#!/bin/sh\n", "image/png", &any)));
+        assert!(rejected(content_type(b"\x01\x02\x03", "image/png", &any)));
+        assert_eq!(
+            content_type(b"\x01\x02\x03", "model/stl", &any).unwrap(),
+            GENERIC
+        );
+    }
+
+    #[test]
+    fn test_content_type_of_text() {
+        let any = AllowedTypes::default();
+        assert_eq!(
+            content_type(b"a,b\n", "text/csv", &any).unwrap(),
+            "text/csv"
+        );
+        assert_eq!(
+            content_type(b"{}", "application/json", &any).unwrap(),
+            "application/json"
+        );
+        assert_eq!(content_type(b"hello", GENERIC, &any).unwrap(), "text/plain");
+        assert_eq!(
+            content_type("zaż".as_bytes(), "", &any).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(
+            content_type(&"ż".as_bytes()[..1], "", &any).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(
+            content_type(b"<html>", "text/plain", &any).unwrap(),
+            "text/plain"
+        );
+        assert!(rejected(content_type(b"hello", "image/jpg", &any)));
+        assert_eq!(content_type(b"a\0b", "", &any).unwrap(), GENERIC);
+    }
+
+    #[test]
+    fn test_content_type_has_to_be_allowed() {
+        let images = allowed("image/*");
+        assert_eq!(
+            content_type(PNG, "image/png", &images).unwrap(),
+            "image/png"
+        );
+        assert!(rejected(content_type(PDF, "application/pdf", &images)));
+        assert!(rejected(content_type(b"hello", "text/plain", &images)));
+        assert!(may_be_allowed("image/jpg", &images));
+        assert!(may_be_allowed("", &images));
+        assert!(!may_be_allowed("text/plain", &images));
+    }
+}
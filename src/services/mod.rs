@@ -1,3 +1,10 @@
+pub mod attachments;
+pub mod audit;
+pub mod cache;
 pub mod cars;
+pub mod file_types;
 pub mod parts;
+pub mod resumable;
+pub mod thumbnails;
+pub mod uploads;
 pub mod users;
@@ -1,62 +1,60 @@
-use crate::cache::CacheImpl;
+use crate::cache::{CacheImpl, Entity};
 use crate::controllers::{CommonQuery, Pagination};
+use crate::error::ApiError;
+use crate::models::audit::AuditContext;
 use crate::models::part::{NewPart, Part, PartList, PartQuery};
 use crate::repositories::part::PartRepository;
 use anyhow::{Result, bail};
-use bb8_redis::RedisConnectionManager;
-use bb8_redis::bb8::PooledConnection;
-use redis::AsyncCommands;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 use tracing::info;
 use validator::Validate;
 
-const PART_CACHE_TTL: u64 = 60;
-
 pub async fn find_all<R: PartRepository>(
     repo: Arc<R>,
+    cache: Arc<CacheImpl>,
     conditions: &PartQuery,
     query: &CommonQuery,
     pagination: &Pagination,
 ) -> Result<PartList> {
-    let parts = repo.find_all(conditions, query, pagination).await?;
-    Ok(parts)
+    // Lists with soft-deleted rows are never cached
+    if query.include_deleted {
+        return repo.find_all(conditions, query, pagination).await;
+    }
+    let key = Entity::Part.list_key(&(conditions, query.normalized(), pagination.normalized()))?;
+    cache
+        .get_or_load(Entity::Part, &key, &[Entity::Part.list_tag()], || {
+            repo.find_all(conditions, query, pagination)
+        })
+        .await
 }
 
 pub async fn view<R: PartRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     part_id: i32,
+    include_deleted: bool,
 ) -> Result<Part> {
-    // Construct the cache key
-    let cache_key = format!("part:{}", part_id);
-    let mut redis_conn: PooledConnection<RedisConnectionManager> = cache.redis_pool.get().await?;
-    // Attempt to retrieve the part data from cache
-    let maybe_cached: Option<String> = redis_conn.get::<String, _>(cache_key.clone()).await?;
-
-    if let Some(cached_json) = maybe_cached {
-        // Deserialize the JSON string back to a Part object
-        let cached_part: Part = serde_json::from_str(&cached_json)?;
-        info!("Found cached part {}: {:?}", part_id, cached_part);
-        return Ok(cached_part);
+    // Soft-deleted rows are never cached
+    if include_deleted {
+        return repo.find_by_id(part_id, true).await;
     }
-    info!("Fetching part {} from db...", part_id);
-    // If not in cache, query the database
-    let part = repo.find_by_id(part_id).await?;
-
-    // Serialize the part object to a JSON string
-    let part_json = serde_json::to_string(&part)?;
-
-    // Store the serialized Part in the cache
-    redis_conn
-        .set_ex::<_, _, ()>(&cache_key, part_json, PART_CACHE_TTL)
-        .await?;
-
-    Ok(part)
+    cache
+        .get_or_load(Entity::Part, &Entity::Part.key(part_id), &[], || {
+            repo.find_by_id(part_id, false)
+        })
+        .await
 }
 
-pub async fn create<R: PartRepository>(repo: Arc<R>, new_part: &NewPart) -> Result<Part> {
+pub async fn create<R: PartRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    new_part: &NewPart,
+    ctx: &AuditContext,
+) -> Result<Part> {
     new_part.validate()?;
-    let part = repo.create(new_part).await?;
+    let part = repo.create(new_part, ctx).await?;
+    cache.invalidate_tag(&Entity::Part.list_tag()).await;
     Ok(part)
 }
 
@@ -64,31 +62,71 @@ pub async fn update<R: PartRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     part: &Part,
+    versions: &[i32],
+    ctx: &AuditContext,
 ) -> Result<Part> {
-    // Construct the cache key
-    let cache_key = format!("part:{}", part.id);
-    let mut redis_conn: PooledConnection<RedisConnectionManager> = cache.redis_pool.get().await?;
-    // Attempt to retrieve the part data from cache
-    let _: Option<String> = redis_conn.del::<String, _>(cache_key.clone()).await?;
-
-    let part = repo.update(part).await?;
-    Ok(part)
+    // Invalidate only once the write has committed, see `CacheImpl::invalidate`
+    let updated = repo.update(part, versions, ctx).await?;
+    cache.invalidate(&Entity::Part.key(part.id)).await;
+    cache.invalidate_tag(&Entity::Part.list_tag()).await;
+    match updated {
+        Some(part) => Ok(part),
+        None => Err(version_conflict(repo, part.id).await),
+    }
 }
 
 pub async fn delete<R: PartRepository>(
     repo: Arc<R>,
     cache: Arc<CacheImpl>,
     part_id: i32,
+    versions: &[i32],
+    ctx: &AuditContext,
 ) -> Result<u64> {
-    // Construct the cache key
-    let cache_key = format!("part:{}", part_id);
-    let mut redis_conn: PooledConnection<RedisConnectionManager> = cache.redis_pool.get().await?;
-    // Attempt to retrieve the part data from cache
-    let _: Option<String> = redis_conn.del::<String, _>(cache_key.clone()).await?;
+    let affected_rows = repo.delete(part_id, versions, ctx).await?;
+    cache.invalidate(&Entity::Part.key(part_id)).await;
+    cache.invalidate_tag(&Entity::Part.list_tag()).await;
+    if affected_rows == 0 {
+        return Err(version_conflict(repo, part_id).await);
+    }
+    check_affected_rows(affected_rows, part_id)
+}
 
-    let affected_rows = repo.delete(part_id).await?;
+pub async fn restore<R: PartRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    part_id: i32,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.restore(part_id, ctx).await?;
+    cache.invalidate_tag(&Entity::Part.list_tag()).await;
+    check_affected_rows(affected_rows, part_id)
+}
+
+// Hard-deletes parts that were soft-deleted before the cutoff
+pub async fn purge<R: PartRepository>(
+    repo: Arc<R>,
+    deleted_before: DateTime<Utc>,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.purge(deleted_before, ctx).await?;
+    if affected_rows > 0 {
+        info!("Purged {} deleted parts", affected_rows);
+    }
+    Ok(affected_rows)
+}
+
+// A conditional write touched nothing: either the part is gone or someone else changed it first
+async fn version_conflict<R: PartRepository>(repo: Arc<R>, part_id: i32) -> anyhow::Error {
+    match repo.find_by_id(part_id, false).await {
+        Ok(_) => ApiError::PreconditionFailed.into(),
+        Err(err) => err,
+    }
+}
+
+fn check_affected_rows(affected_rows: u64, part_id: i32) -> Result<u64> {
     if affected_rows == 0 {
-        bail!("No rows affected, part with ID {} not found", part_id);
+        info!("No rows affected, part with ID {} not found", part_id);
+        return Err(ApiError::NotFound.into());
     } else if affected_rows > 1 {
         bail!("Unexpected number of rows affected: {}", affected_rows);
     }
@@ -99,6 +137,7 @@ pub async fn delete<R: PartRepository>(
 mod tests {
     use super::*;
     use crate::repositories::part::MockPartRepository;
+    use crate::tests::fixture::cache::cache_fixture;
     use crate::tests::fixture::part::parts_fixture;
 
     #[tokio::test]
@@ -108,16 +147,25 @@ mod tests {
             .expect_find_all()
             .returning(|_, _, _| Ok(parts_fixture(5)));
         let conditions = PartQuery { name: None };
-        let query = CommonQuery { ids: [].to_vec() };
+        let query = CommonQuery {
+            ids: [].to_vec(),
+            ..Default::default()
+        };
         let pagination = Pagination {
             page: None,
             per_page: None,
             field: None,
             order: None,
         };
-        let parts = find_all(Arc::new(mock_repo_impl), &conditions, &query, &pagination)
-            .await
-            .unwrap();
+        let parts = find_all(
+            Arc::new(mock_repo_impl),
+            cache_fixture(),
+            &conditions,
+            &query,
+            &pagination,
+        )
+        .await
+        .unwrap();
         assert_eq!(parts.data.len(), 5);
     }
 }
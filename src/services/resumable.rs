@@ -0,0 +1,589 @@
+use crate::error::ApiError;
+use crate::models::resumable::{Appended, Chunk, ResumableUpload};
+use crate::models::upload::{FileMeta, Upload};
+use crate::repositories::resumable::ResumableRepository;
+use crate::repositories::upload::UploadRepository;
+use crate::services;
+use crate::services::uploads::UploadSettings;
+use crate::storage::Storage;
+use anyhow::{Result, anyhow, bail};
+use axum::BoxError;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// Starts an upload of `length` bytes that is then sent in chunks with `append`. Its size, the
+// owner's quota and the declared type are checked up front, so a file that would be refused is
+// not sent at all.
+pub async fn create<R: ResumableRepository, U: UploadRepository>(
+    repo: Arc<R>,
+    uploads: Arc<U>,
+    settings: &UploadSettings,
+    owner: &str,
+    length: u64,
+    meta: FileMeta,
+) -> Result<ResumableUpload> {
+    if length > settings.max_bytes {
+        bail!(ApiError::PayloadTooLarge(settings.max_bytes));
+    }
+    if let Some(quota) = settings.quota {
+        let usage = services::uploads::usage(uploads, settings, owner).await?;
+        if usage.left().is_some_and(|left| length > left) {
+            bail!(ApiError::QuotaExceeded(quota));
+        }
+    }
+    if !services::file_types::may_be_allowed(&meta.content_type, &settings.form_types) {
+        bail!(ApiError::UnsupportedMediaType(format!(
+            "Files of type {} are not accepted here",
+            meta.content_type
+        )));
+    }
+    let upload = ResumableUpload {
+        id: Uuid::new_v4(),
+        owner: owner.to_string(),
+        length,
+        offset: 0,
+        meta,
+        parts: vec![],
+        expires_at: expiry(settings),
+    };
+    repo.create(&upload).await?;
+    Ok(upload)
+}
+
+// Unfinished uploads are only visible to whoever started them, and only until they expire
+pub async fn view<R: ResumableRepository>(
+    repo: Arc<R>,
+    id: Uuid,
+    owner: &str,
+) -> Result<ResumableUpload> {
+    let upload = repo.find(id).await?;
+    if upload.owner != owner || upload.expires_at <= Utc::now() {
+        bail!(ApiError::NotFound);
+    }
+    Ok(upload)
+}
+
+// Stores the next chunk of `upload`. A chunk is kept whole or not at all: one that breaks off,
+// fails its checksum or lost a race with another request for the same offset is dropped, and
+// the client resumes from the offset the upload is still at. The last chunk turns the upload
+// into a file like `uploads::upload` makes, with the same checks.
+pub async fn append<R, U, S, E>(
+    repo: Arc<R>,
+    uploads: Arc<U>,
+    storage: &dyn Storage,
+    settings: &UploadSettings,
+    mut upload: ResumableUpload,
+    chunk: &Chunk,
+    stream: S,
+) -> Result<Appended>
+where
+    R: ResumableRepository,
+    U: UploadRepository,
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<BoxError>,
+{
+    if chunk.offset != upload.offset {
+        bail!(ApiError::Conflict(format!(
+            "Upload-Offset has to be {}",
+            upload.offset
+        )));
+    }
+    let key = format!(
+        "{}.part-{}-{}",
+        upload.id,
+        upload.offset,
+        Uuid::new_v4().simple()
+    );
+    let remaining = upload.length - upload.offset;
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let body = stream
+        .map(|bytes| -> Result<Bytes> {
+            let bytes = bytes.map_err(|err| anyhow!(err.into()))?;
+            size += bytes.len() as u64;
+            if size > remaining {
+                bail!(ApiError::BadRequest(
+                    "The chunk goes past Upload-Length".to_string()
+                ));
+            }
+            hasher.update(&bytes);
+            Ok(bytes)
+        })
+        .boxed();
+    storage.put(&key, body).await?;
+
+    let checksum_matches = match &chunk.checksum {
+        Some(checksum) => hasher.finalize().as_slice() == checksum.as_slice(),
+        None => true,
+    };
+    if !checksum_matches || size == 0 {
+        let _ = storage.delete(&key).await;
+        if !checksum_matches {
+            bail!(ApiError::ChecksumMismatch);
+        }
+    } else {
+        let expires_at = expiry(settings);
+        let to = upload.offset + size;
+        if !repo
+            .advance(upload.id, upload.offset, to, &key, expires_at)
+            .await?
+        {
+            let _ = storage.delete(&key).await;
+            bail!(ApiError::Conflict(
+                "Another chunk was stored at this offset".to_string()
+            ));
+        }
+        upload.offset = to;
+        upload.parts.push(key);
+        upload.expires_at = expires_at;
+    }
+
+    if upload.offset < upload.length {
+        return Ok(Appended::Partial(upload));
+    }
+    let upload = finish(repo, uploads, storage, settings, upload).await?;
+    Ok(Appended::Complete(upload))
+}
+
+// Abandons an upload and what was sent of it
+pub async fn terminate<R: ResumableRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    upload: &ResumableUpload,
+) -> Result<()> {
+    if !repo.delete(upload.id).await? {
+        bail!(ApiError::NotFound);
+    }
+    remove_parts(storage, upload.id, &upload.parts).await;
+    Ok(())
+}
+
+// Removes the uploads whose last chunk is older than `UploadSettings::resumable_ttl`, along
+// with their chunks
+pub async fn expire<R: ResumableRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    now: DateTime<Utc>,
+) -> Result<usize> {
+    let mut expired = 0;
+    for id in repo.find_expired(now).await? {
+        // checked again as it is deleted, a chunk may have come in since
+        if let Some(parts) = repo.expire(id, now).await? {
+            remove_parts(storage, id, &parts).await;
+            expired += 1;
+        }
+    }
+    if expired > 0 {
+        info!("Expired {} unfinished resumable uploads", expired);
+    }
+    Ok(expired)
+}
+
+// Only one request gets to turn the upload into a file. Should that fail for a reason other
+// than the file itself, the upload is put back so the client can retry with an empty chunk.
+async fn finish<R, U>(
+    repo: Arc<R>,
+    uploads: Arc<U>,
+    storage: &dyn Storage,
+    settings: &UploadSettings,
+    upload: ResumableUpload,
+) -> Result<Upload>
+where
+    R: ResumableRepository,
+    U: UploadRepository,
+{
+    if !repo.delete(upload.id).await? {
+        bail!(ApiError::Conflict(
+            "The upload is already being completed".to_string()
+        ));
+    }
+    let parts = stream::iter(upload.parts.clone())
+        .then(|part| async move { storage.get(&part, 0, u64::MAX).await })
+        .try_flatten()
+        // boxed, or the handler's future cannot be shown to be `Send`
+        .boxed();
+    let stored = services::uploads::upload(
+        uploads,
+        storage,
+        settings,
+        &settings.form_types,
+        &upload.owner,
+        &upload.meta,
+        parts,
+    )
+    .await;
+    match &stored {
+        Err(err) if err.downcast_ref::<ApiError>().is_none() => {
+            if let Err(err) = repo.create(&upload).await {
+                warn!(%err, "Could not put back resumable upload {}", upload.id);
+            }
+        }
+        _ => remove_parts(storage, upload.id, &upload.parts).await,
+    }
+    stored
+}
+
+async fn remove_parts(storage: &dyn Storage, id: Uuid, parts: &[String]) {
+    for part in parts {
+        if let Err(err) = storage.delete(part).await {
+            warn!(%err, "Could not remove chunk {} of resumable upload {}", part, id);
+        }
+    }
+}
+
+// Whole seconds, as that is what Redis keeps
+fn expiry(settings: &UploadSettings) -> DateTime<Utc> {
+    let expires_at = Utc::now().timestamp() + settings.resumable_ttl.as_secs() as i64;
+    DateTime::from_timestamp(expires_at, 0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::resumable::MockResumableRepository;
+    use crate::repositories::upload::MockUploadRepository;
+    use crate::services::file_types::AllowedTypes;
+    use crate::storage::MemoryStorage;
+    use crate::tests::fixture::upload::upload_fixture;
+    use mockall::predicate;
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    fn settings() -> UploadSettings {
+        UploadSettings {
+            max_bytes: 100,
+            max_files: 10,
+            quota: None,
+            form_types: "text/*".parse().unwrap(),
+            body_types: AllowedTypes::default(),
+            resumable_ttl: Duration::from_secs(60),
+            thumbnail_sizes: vec![],
+            link_secret: "secret".to_string(),
+            link_ttl: Duration::from_secs(60),
+        }
+    }
+
+    fn meta(content_type: &str) -> FileMeta {
+        FileMeta {
+            name: "notes.txt".to_string(),
+            content_type: content_type.to_string(),
+            description: None,
+        }
+    }
+
+    fn started(length: u64, offset: u64, parts: Vec<String>) -> ResumableUpload {
+        ResumableUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            length,
+            offset,
+            meta: meta("text/plain"),
+            parts,
+            expires_at: Utc::now() + Duration::from_secs(60),
+        }
+    }
+
+    fn body(content: &'static str) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::once(async move { Ok(Bytes::from_static(content.as_bytes())) })
+    }
+
+    fn chunk(offset: u64, checksum: Option<&str>) -> Chunk {
+        Chunk {
+            offset,
+            checksum: checksum.map(|content| Sha256::digest(content).to_vec()),
+        }
+    }
+
+    fn api_error(result: Result<Appended>) -> ApiError {
+        match result.unwrap_err().downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(err) => panic!("not an ApiError: {err}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_checks_size_quota_and_declared_type() {
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_create()
+            .times(1)
+            .returning(|_| Ok(()));
+        let repo = Arc::new(mock_repo_impl);
+        let mut mock_uploads = MockUploadRepository::new();
+        mock_uploads.expect_usage().returning(|_| Ok(50));
+        let uploads = Arc::new(mock_uploads);
+        let create = async |length, meta, quota| {
+            let settings = UploadSettings {
+                quota,
+                ..settings()
+            };
+            create(
+                repo.clone(),
+                uploads.clone(),
+                &settings,
+                "alice",
+                length,
+                meta,
+            )
+            .await
+        };
+
+        let upload = create(100, meta("text/plain"), None).await.unwrap();
+        assert_eq!((upload.length, upload.offset), (100, 0));
+        let too_large = create(101, meta("text/plain"), None).await;
+        assert!(matches!(
+            too_large.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::PayloadTooLarge(100))
+        ));
+        let over_quota = create(51, meta("text/plain"), Some(100)).await;
+        assert!(matches!(
+            over_quota.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::QuotaExceeded(100))
+        ));
+        let image = create(10, meta("image/png"), None).await;
+        assert!(matches!(
+            image.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::UnsupportedMediaType(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_view_hides_other_owners_and_expired_uploads() {
+        let mut expired = started(10, 0, vec![]);
+        expired.expires_at = Utc::now();
+        let fresh = started(10, 0, vec![]);
+        let (fresh_id, expired_id) = (fresh.id, expired.id);
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_find()
+            .with(predicate::eq(fresh_id))
+            .returning(move |_| Ok(fresh.clone()));
+        mock_repo_impl
+            .expect_find()
+            .with(predicate::eq(expired_id))
+            .returning(move |_| Ok(expired.clone()));
+        let repo = Arc::new(mock_repo_impl);
+
+        assert!(view(repo.clone(), fresh_id, "alice").await.is_ok());
+        assert!(view(repo.clone(), fresh_id, "mallory").await.is_err());
+        assert!(view(repo, expired_id, "alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_append_stores_a_chunk_and_advances() {
+        let upload = started(10, 0, vec![]);
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_advance()
+            .withf(|_, from, to, _, _| (*from, *to) == (0, 5))
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(true));
+        let storage = MemoryStorage::default();
+
+        let appended = append(
+            Arc::new(mock_repo_impl),
+            Arc::new(MockUploadRepository::new()),
+            &storage,
+            &settings(),
+            upload,
+            &chunk(0, Some("hello")),
+            body("hello"),
+        )
+        .await
+        .unwrap();
+        let Appended::Partial(upload) = appended else {
+            panic!("the upload is not complete yet");
+        };
+        assert_eq!(upload.offset, 5);
+        assert_eq!(upload.parts.len(), 1);
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_append_drops_chunks_that_do_not_fit() {
+        let storage = MemoryStorage::default();
+        let append_to = async |upload, chunk: Chunk, content| {
+            let mut mock_repo_impl = MockResumableRepository::new();
+            mock_repo_impl.expect_advance().never();
+            append(
+                Arc::new(mock_repo_impl),
+                Arc::new(MockUploadRepository::new()),
+                &storage,
+                &settings(),
+                upload,
+                &chunk,
+                body(content),
+            )
+            .await
+        };
+
+        let wrong_offset = append_to(started(10, 5, vec![]), chunk(0, None), "hello").await;
+        assert!(matches!(api_error(wrong_offset), ApiError::Conflict(_)));
+        let wrong_checksum = append_to(started(10, 0, vec![]), chunk(0, Some("help")), "hello");
+        assert!(matches!(
+            api_error(wrong_checksum.await),
+            ApiError::ChecksumMismatch
+        ));
+        let too_long = append_to(started(10, 8, vec![]), chunk(8, None), "hello").await;
+        assert!(matches!(api_error(too_long), ApiError::BadRequest(_)));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_append_drops_a_chunk_that_lost_a_race() {
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_advance()
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(false));
+        let storage = MemoryStorage::default();
+
+        let result = append(
+            Arc::new(mock_repo_impl),
+            Arc::new(MockUploadRepository::new()),
+            &storage,
+            &settings(),
+            started(10, 0, vec![]),
+            &chunk(0, None),
+            body("hello"),
+        )
+        .await;
+        assert!(matches!(api_error(result), ApiError::Conflict(_)));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_last_chunk_completes_the_upload() {
+        let storage = MemoryStorage::default();
+        let first = "first-part".to_string();
+        storage
+            .put(
+                &first,
+                stream::once(async { Ok(Bytes::from("hello ")) }).boxed(),
+            )
+            .await
+            .unwrap();
+        let upload = started(11, 6, vec![first]);
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_advance()
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(true));
+        mock_repo_impl
+            .expect_delete()
+            .with(predicate::eq(upload.id))
+            .times(1)
+            .returning(|_| Ok(true));
+        let mut mock_uploads = MockUploadRepository::new();
+        mock_uploads
+            .expect_reserve_blob()
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_uploads
+            .expect_create()
+            .withf(|upload, _| {
+                upload.size == 11
+                    && upload.content_type == "text/plain"
+                    && upload.sha256
+                        == "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+            })
+            .times(1)
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+
+        let appended = append(
+            Arc::new(mock_repo_impl),
+            Arc::new(mock_uploads),
+            &storage,
+            &settings(),
+            upload,
+            &chunk(6, None),
+            body("world"),
+        )
+        .await
+        .unwrap();
+        let Appended::Complete(stored) = appended else {
+            panic!("the upload is complete");
+        };
+        // the chunks are gone, the file remains
+        assert_eq!(storage.len(), 1);
+        let contents: Vec<Bytes> = services::uploads::read(&storage, &stored, 0, 11)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(contents.concat(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_a_refused_file_is_dropped_with_its_chunks() {
+        let storage = MemoryStorage::default();
+        let first = "first-part".to_string();
+        storage
+            .put(
+                &first,
+                stream::once(async { Ok(Bytes::from("\x00\x01")) }).boxed(),
+            )
+            .await
+            .unwrap();
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_delete()
+            .times(1)
+            .returning(|_| Ok(true));
+        mock_repo_impl.expect_create().never();
+        let mut mock_uploads = MockUploadRepository::new();
+        mock_uploads.expect_create().never();
+
+        let result = append(
+            Arc::new(mock_repo_impl),
+            Arc::new(mock_uploads),
+            &storage,
+            &settings(),
+            started(2, 2, vec![first]),
+            &chunk(2, None),
+            body(""),
+        )
+        .await;
+        assert!(matches!(
+            api_error(result),
+            ApiError::UnsupportedMediaType(_)
+        ));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_expire_removes_expired_uploads_and_their_chunks() {
+        let storage = MemoryStorage::default();
+        let part = "part".to_string();
+        storage
+            .put(
+                &part,
+                stream::once(async { Ok(Bytes::from("hello")) }).boxed(),
+            )
+            .await
+            .unwrap();
+        let (expired_id, resumed_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut mock_repo_impl = MockResumableRepository::new();
+        mock_repo_impl
+            .expect_find_expired()
+            .returning(move |_| Ok(vec![expired_id, resumed_id]));
+        mock_repo_impl
+            .expect_expire()
+            .times(2)
+            .returning(move |id, _| Ok((id == expired_id).then(|| vec![part.clone()])));
+        mock_repo_impl.expect_find().never();
+        mock_repo_impl.expect_delete().never();
+
+        let expired = expire(Arc::new(mock_repo_impl), &storage, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(expired, 1);
+        assert_eq!(storage.len(), 0);
+    }
+}
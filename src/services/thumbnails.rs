@@ -0,0 +1,345 @@
+use crate::cache::CacheImpl;
+use crate::error::ApiError;
+use crate::models::upload::Upload;
+use crate::repositories::upload::UploadRepository;
+use crate::services;
+use crate::storage::Storage;
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task;
+use tracing::warn;
+use uuid::Uuid;
+
+const JPEG_QUALITY: u8 = 80;
+
+// Decoding holds the original and its pixels in memory, so only a few images are worked on at
+// once and larger ones are refused before their pixels are allocated
+static DECODES: Semaphore = Semaphore::const_new(2);
+const MAX_DIMENSION: u32 = 10_000;
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+
+// Thumbnails are kept next to the original, under the upload's id
+pub fn key(id: Uuid, size: u32) -> String {
+    format!("{id}.thumb-{size}")
+}
+
+// Makes the thumbnails of `upload` without holding up the response that stored it. Failing to
+// is only logged: the file is there, it just has no thumbnails.
+pub fn spawn<R>(
+    repo: Arc<R>,
+    storage: Arc<dyn Storage>,
+    cache: Arc<CacheImpl>,
+    sizes: Vec<u32>,
+    upload: Upload,
+) where
+    R: UploadRepository + Send + Sync + 'static,
+{
+    if sizes.is_empty() || readable_format(&upload.content_type).is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(err) = generate(repo, storage.as_ref(), &cache, &sizes, &upload).await {
+            warn!(%err, "Could not make thumbnails of upload {}", upload.id);
+        }
+    });
+}
+
+// Stores a JPEG of each size whose longest edge is at most that many pixels, and records which
+// were made. Images already small enough are only re-encoded, never scaled up. The car or part
+// the image is attached to by then shows them, so its cached copies are dropped.
+pub async fn generate<R: UploadRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    cache: &CacheImpl,
+    sizes: &[u32],
+    upload: &Upload,
+) -> Result<()> {
+    let Some(format) = readable_format(&upload.content_type) else {
+        return Ok(());
+    };
+    let permit = DECODES.acquire().await?;
+    let chunks: Vec<Bytes> = storage
+        .get(&upload.blob, 0, upload.size as u64)
+        .await?
+        .try_collect()
+        .await?;
+    let original = chunks.concat();
+    let owned_sizes = sizes.to_vec();
+    let thumbnails = task::spawn_blocking(move || render(&original, format, &owned_sizes))
+        .await
+        .context("panic in render()")??;
+    drop(permit);
+
+    let stored = store(storage, upload.id, &thumbnails).await;
+    let recorded = match stored {
+        Ok(()) => {
+            let sizes: Vec<i32> = thumbnails.iter().map(|(size, _)| *size as i32).collect();
+            repo.set_thumbnails(upload.id, &sizes).await
+        }
+        Err(err) => Err(err),
+    };
+    match recorded {
+        Ok(Some(owner)) => {
+            if let Some(owner) = owner {
+                services::attachments::invalidate(cache, owner).await;
+            }
+            Ok(())
+        }
+        // an upload deleted in the meantime takes its thumbnails with it
+        Ok(None) => {
+            remove(storage, upload.id, sizes).await;
+            Ok(())
+        }
+        Err(err) => {
+            remove(storage, upload.id, sizes).await;
+            Err(err)
+        }
+    }
+}
+
+// Thumbnails are shown wherever their image is: to its owner and admins, and to any logged in
+// user once it is attached to a car or part
+pub async fn view<R: UploadRepository>(
+    repo: Arc<R>,
+    id: Uuid,
+    viewer: &str,
+    admin: bool,
+) -> Result<Upload> {
+    let upload = repo.find_by_id(id).await?;
+    if upload.owner != viewer && !admin && !repo.is_attached(id).await? {
+        return Err(ApiError::NotFound.into());
+    }
+    Ok(upload)
+}
+
+// The thumbnail of `upload` of the given size, whole: they are small enough to be held in
+// memory, unlike the originals
+pub async fn read(storage: &dyn Storage, upload: &Upload, size: u32) -> Result<Bytes> {
+    if !upload.thumbnails.contains(&(size as i32)) {
+        return Err(ApiError::NotFound.into());
+    }
+    let chunks: Vec<Bytes> = storage
+        .get(&key(upload.id, size), 0, u64::MAX)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(chunks.concat().into())
+}
+
+pub async fn remove(storage: &dyn Storage, id: Uuid, sizes: &[u32]) {
+    for size in sizes {
+        if let Err(err) = storage.delete(&key(id, *size)).await {
+            warn!(%err, "Could not remove the {}px thumbnail of upload {}", size, id);
+        }
+    }
+}
+
+async fn store(storage: &dyn Storage, id: Uuid, thumbnails: &[(u32, Bytes)]) -> Result<()> {
+    for (size, bytes) in thumbnails {
+        let body = stream::once(futures::future::ok(bytes.clone())).boxed();
+        storage.put(&key(id, *size), body).await?;
+    }
+    Ok(())
+}
+
+// Only what the `image` crate is built to decode, whatever else claims to be an image
+fn readable_format(content_type: &str) -> Option<ImageFormat> {
+    ImageFormat::from_mime_type(content_type).filter(ImageFormat::reading_enabled)
+}
+
+fn render(original: &[u8], format: ImageFormat, sizes: &[u32]) -> Result<Vec<(u32, Bytes)>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::with_format(Cursor::new(original), format);
+    reader.limits(limits);
+    let image = reader.decode()?;
+    sizes
+        .iter()
+        .map(|&size| {
+            let thumbnail = if image.width() <= size && image.height() <= size {
+                image.clone()
+            } else {
+                image.thumbnail(size, size)
+            };
+            Ok((size, encode(thumbnail)?))
+        })
+        .collect()
+}
+
+// JPEG has no transparency, transparent pixels come out black
+fn encode(image: DynamicImage) -> Result<Bytes> {
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+    Ok(jpeg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Entity;
+    use crate::error::is_not_found;
+    use crate::models::attachment::AttachmentOwner;
+    use crate::models::upload::NewUpload;
+    use crate::repositories::upload::MockUploadRepository;
+    use crate::storage::MemoryStorage;
+    use crate::tests::fixture::cache::{cache_fixture, local_cache_fixture};
+    use crate::tests::fixture::upload::upload_fixture;
+    use image::{ImageBuffer, Rgb};
+    use mockall::predicate;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = ImageBuffer::from_pixel(width, height, Rgb([200u8, 30, 30]));
+        let mut png = Cursor::new(vec![]);
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    async fn stored(storage: &dyn Storage, content_type: &str, content: Vec<u8>) -> Upload {
+        let upload = upload_fixture(&NewUpload {
+            id: Uuid::new_v4(),
+            owner: "alice".to_string(),
+            original_name: "photo".to_string(),
+            size: content.len() as i64,
+            content_type: content_type.to_string(),
+            sha256: String::new(),
+            description: None,
+        });
+        let body = stream::once(futures::future::ok(Bytes::from(content))).boxed();
+        storage.put(&upload.id.to_string(), body).await.unwrap();
+        upload
+    }
+
+    fn dimensions(jpeg: &[u8]) -> (u32, u32) {
+        let image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[tokio::test]
+    async fn test_generate_fits_each_size_without_scaling_up() {
+        let storage = MemoryStorage::default();
+        let mut upload = stored(&storage, "image/png", png(400, 200)).await;
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_set_thumbnails()
+            .with(predicate::eq(upload.id), predicate::eq(vec![100, 1000]))
+            .times(1)
+            .returning(|_, _| Ok(Some(None)));
+
+        generate(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &cache_fixture(),
+            &[100, 1000],
+            &upload,
+        )
+        .await
+        .unwrap();
+
+        upload.thumbnails = vec![100, 1000];
+        let small = read(&storage, &upload, 100).await.unwrap();
+        assert_eq!(dimensions(&small), (100, 50));
+        let large = read(&storage, &upload, 1000).await.unwrap();
+        assert_eq!(dimensions(&large), (400, 200));
+        assert!(is_not_found(
+            &read(&storage, &upload, 50).await.unwrap_err()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generate_skips_files_that_are_not_images() {
+        let storage = MemoryStorage::default();
+        let upload = stored(&storage, "text/plain", b"hello".to_vec()).await;
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_set_thumbnails().never();
+
+        generate(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &cache_fixture(),
+            &[100],
+            &upload,
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_generate_fails_on_undecodable_images() {
+        let storage = MemoryStorage::default();
+        let upload = stored(&storage, "image/png", b"not a png".to_vec()).await;
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_set_thumbnails().never();
+
+        let result = generate(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &cache_fixture(),
+            &[100],
+            &upload,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_generate_removes_thumbnails_of_deleted_uploads() {
+        let storage = MemoryStorage::default();
+        let upload = stored(&storage, "image/png", png(20, 20)).await;
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_set_thumbnails()
+            .times(1)
+            .returning(|_, _| Ok(None));
+
+        generate(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &cache_fixture(),
+            &[10, 16],
+            &upload,
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_generate_drops_the_cached_owner_of_attached_images() {
+        let storage = MemoryStorage::default();
+        let upload = stored(&storage, "image/png", png(20, 20)).await;
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_set_thumbnails()
+            .times(1)
+            .returning(|_, _| Ok(Some(Some(AttachmentOwner::Car(3)))));
+        let cache = local_cache_fixture();
+        let key = Entity::Car.key(3);
+        cache
+            .get_or_load(Entity::Car, &key, &[], || async { Ok(3) })
+            .await
+            .unwrap();
+
+        generate(Arc::new(mock_repo_impl), &storage, &cache, &[10], &upload)
+            .await
+            .unwrap();
+        assert!(!cache.lookup(&key).await.local);
+    }
+
+    #[test]
+    fn test_render_refuses_images_over_the_limits() {
+        let wide = png(MAX_DIMENSION + 1, 1);
+        assert!(render(&wide, ImageFormat::Png, &[100]).is_err());
+        let fits = png(MAX_DIMENSION, 1);
+        assert!(render(&fits, ImageFormat::Png, &[100]).is_ok());
+    }
+}
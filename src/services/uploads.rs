@@ -0,0 +1,862 @@
+use crate::config::Config;
+use crate::controllers::Pagination;
+use crate::error::ApiError;
+use crate::models::upload::{FileMeta, NewUpload, StorageUsage, Upload, UploadList};
+use crate::repositories::upload::UploadRepository;
+use crate::services::file_types::{self, AllowedTypes};
+use crate::services::thumbnails;
+use crate::storage::{ByteStream, Storage};
+use anyhow::{Result, anyhow};
+use axum::body::Bytes;
+use axum::{BoxError, Extension};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const MAX_FILE_NAME_BYTES: usize = 255;
+
+pub type UploadSettingsExt = Extension<Arc<UploadSettings>>;
+
+#[derive(Debug, Clone)]
+pub struct UploadSettings {
+    // per file
+    pub max_bytes: u64,
+    // per multipart request
+    pub max_files: usize,
+    // bytes each owner may store in all, none for no limit
+    pub quota: Option<u64>,
+    // what `files::upload` and `utils::save_request_body` accept
+    pub form_types: AllowedTypes,
+    pub body_types: AllowedTypes,
+    // see `services::resumable`
+    pub resumable_ttl: Duration,
+    // longest edges of the thumbnails of images, see `services::thumbnails`
+    pub thumbnail_sizes: Vec<u32>,
+    // signs download links, see `sign_link`
+    pub link_secret: String,
+    pub link_ttl: Duration,
+}
+
+impl From<&Config> for UploadSettings {
+    fn from(config: &Config) -> Self {
+        assert!(
+            !config.download_link_secret.is_empty(),
+            "DOWNLOAD_LINK_SECRET or JWT_SECRET must be set"
+        );
+        Self {
+            max_bytes: config.upload_max_bytes,
+            max_files: config.upload_max_files,
+            quota: (config.upload_quota_bytes > 0).then_some(config.upload_quota_bytes),
+            form_types: allowed_types("UPLOAD_ALLOWED_TYPES", &config.upload_allowed_types),
+            body_types: allowed_types(
+                "UPLOAD_BODY_ALLOWED_TYPES",
+                &config.upload_body_allowed_types,
+            ),
+            resumable_ttl: Duration::from_secs(config.resumable_upload_ttl_secs),
+            thumbnail_sizes: config.thumbnail_sizes.clone(),
+            link_secret: config.download_link_secret.clone(),
+            link_ttl: Duration::from_secs(config.download_link_ttl_secs),
+        }
+    }
+}
+
+// Panics on an invalid list, like on any other invalid setting
+fn allowed_types(name: &str, list: &str) -> AllowedTypes {
+    list.parse()
+        .unwrap_or_else(|e| panic!("{name} is invalid: {e}"))
+}
+
+impl UploadSettings {
+    // Rejects a body whose declared length is already over the limit, before reading any of it
+    pub fn check_length(&self, content_length: Option<u64>) -> Result<()> {
+        match content_length {
+            Some(length) if length > self.max_bytes => {
+                Err(ApiError::PayloadTooLarge(self.max_bytes).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Streams `stream` to storage under a fresh id and records it for `owner`. The size limit and
+// what is left of the owner's quota are enforced while streaming, so a client that lies about
+// its length or sends none is cut off all the same. The content type is told from the first
+// bytes and has to be one of `allowed`, see `file_types::content_type`. Content that is stored
+// already is kept once: the new copy is stored in full all the same, and removed once the upload
+// is recorded as pointing at the old one. The same goes when the upload fails. Should the request
+// be cut off before it gets that far, the blob reserved for the key up front is left for
+// `collect_garbage` to remove what was stored.
+pub async fn upload<R, S, E>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    settings: &UploadSettings,
+    allowed: &AllowedTypes,
+    owner: &str,
+    meta: &FileMeta,
+    stream: S,
+) -> Result<Upload>
+where
+    R: UploadRepository,
+    S: Stream<Item = Result<Bytes, E>> + Send,
+    E: Into<BoxError>,
+{
+    let id = Uuid::new_v4();
+    let key = id.to_string();
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let left = match settings.quota {
+        Some(_) => usage(repo.clone(), settings, owner).await?.left(),
+        None => None,
+    };
+    let limits = Limits {
+        max_bytes: settings.max_bytes,
+        left,
+        quota: settings.quota.unwrap_or_default(),
+    };
+    let mut body = measure(stream, limits, &mut size, &mut hasher);
+    let head = read_head(&mut body, file_types::SNIFF_BYTES).await?;
+    let content_type = file_types::content_type(&head.concat(), &meta.content_type, allowed)?;
+    let body = stream::iter(head.into_iter().map(Ok)).chain(body).boxed();
+    repo.reserve_blob(&key).await?;
+    storage.put(&key, body).await?;
+    let upload = NewUpload {
+        id,
+        owner: owner.to_string(),
+        original_name: sanitize_file_name(&meta.name),
+        size: size as i64,
+        content_type,
+        sha256: format!("{:x}", hasher.finalize()),
+        description: meta.description.clone(),
+    };
+    // checked again as the upload is counted, other uploads may have used up the quota since
+    let stored = repo.create(&upload, settings.quota).await;
+    match &stored {
+        Ok(stored) if stored.blob == key => {}
+        _ => {
+            let _ = storage.delete(&key).await;
+        }
+    }
+    stored
+}
+
+pub async fn find_all<R: UploadRepository>(
+    repo: Arc<R>,
+    owner: &str,
+    pagination: &Pagination,
+) -> Result<UploadList> {
+    let uploads = repo.find_by_owner(owner, pagination).await?;
+    Ok(uploads)
+}
+
+pub async fn usage<R: UploadRepository>(
+    repo: Arc<R>,
+    settings: &UploadSettings,
+    owner: &str,
+) -> Result<StorageUsage> {
+    Ok(StorageUsage {
+        used_bytes: repo.usage(owner).await?,
+        quota_bytes: settings.quota,
+    })
+}
+
+// A file is only visible to its owner and to admins. Everyone else gets a 404, so they cannot
+// tell whether an id exists.
+pub async fn view<R: UploadRepository>(
+    repo: Arc<R>,
+    id: Uuid,
+    owner: &str,
+    admin: bool,
+) -> Result<Upload> {
+    let upload = repo.find_by_id(id).await?;
+    if upload.owner != owner && !admin {
+        return Err(ApiError::NotFound.into());
+    }
+    Ok(upload)
+}
+
+// The file a download link points to, as long as the link is unexpired and was signed by us
+pub async fn view_signed<R: UploadRepository>(
+    repo: Arc<R>,
+    settings: &UploadSettings,
+    id: Uuid,
+    expires: i64,
+    signature: &str,
+) -> Result<Upload> {
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| ApiError::Forbidden)?;
+    link_mac(settings, id, expires)
+        .verify_slice(&signature)
+        .map_err(|_| ApiError::Forbidden)?;
+    if expires < Utc::now().timestamp() {
+        return Err(ApiError::Forbidden.into());
+    }
+    let upload = repo.find_by_id(id).await?;
+    Ok(upload)
+}
+
+// Signs a link to `id` that stays valid for `UploadSettings::link_ttl`. Returns when it expires
+// and the signature; whoever holds both can download the file without logging in.
+pub fn sign_link(settings: &UploadSettings, id: Uuid) -> (DateTime<Utc>, String) {
+    let expires = Utc::now().timestamp() + settings.link_ttl.as_secs() as i64;
+    let signature = link_mac(settings, id, expires).finalize().into_bytes();
+    (
+        DateTime::from_timestamp(expires, 0).unwrap_or_default(),
+        URL_SAFE_NO_PAD.encode(signature),
+    )
+}
+
+fn link_mac(settings: &UploadSettings, id: Uuid, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(settings.link_secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(format!("{id}:{expires}").as_bytes());
+    mac
+}
+
+// `len` bytes of the file starting at `start`
+pub async fn read(
+    storage: &dyn Storage,
+    upload: &Upload,
+    start: u64,
+    len: u64,
+) -> Result<ByteStream<'static>> {
+    storage.get(&upload.blob, start, len).await
+}
+
+// Removes the record, and the thumbnails once it is gone. The content stays until
+// `collect_garbage` finds no other file with it. Files attached to a car or part have to be
+// detached first.
+pub async fn delete<R: UploadRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    id: Uuid,
+    owner: &str,
+    admin: bool,
+) -> Result<()> {
+    let upload = view(repo.clone(), id, owner, admin).await?;
+    if repo.is_attached(upload.id).await? {
+        return Err(ApiError::Conflict(
+            "The file is attached to a car or part, detach it first".to_string(),
+        )
+        .into());
+    }
+    repo.delete(upload.id).await?;
+    remove_thumbnails(storage, &upload).await;
+    Ok(())
+}
+
+// Undoes the uploads of a request that failed part way, so a multi-file upload either stores
+// all of its files or none
+pub async fn discard<R: UploadRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+    uploads: &[Upload],
+) -> Result<()> {
+    for upload in uploads {
+        repo.delete(upload.id).await?;
+        remove_thumbnails(storage, upload).await;
+    }
+    Ok(())
+}
+
+// Of an upload whose record is gone. They are made per upload, unlike the content.
+pub async fn remove_thumbnails(storage: &dyn Storage, upload: &Upload) {
+    let sizes: Vec<u32> = upload.thumbnails.iter().map(|size| *size as u32).collect();
+    thumbnails::remove(storage, upload.id, &sizes).await;
+}
+
+// Uploads still being stored after this long are taken to have been cut off, their content is
+// removed
+const UNFINISHED_UPLOAD_SECS: i64 = 24 * 60 * 60;
+
+// Removes the content no file points at anymore. Failing to remove some of it is only logged:
+// it cannot be reached once it is forgotten.
+pub async fn collect_garbage<R: UploadRepository>(
+    repo: Arc<R>,
+    storage: &dyn Storage,
+) -> Result<usize> {
+    let pending_before = Utc::now() - chrono::Duration::seconds(UNFINISHED_UPLOAD_SECS);
+    let keys = repo.collect_blobs(pending_before).await?;
+    for key in &keys {
+        if let Err(err) = storage.delete(key).await {
+            warn!(%err, "Could not remove unreferenced blob {}", key);
+        }
+    }
+    if !keys.is_empty() {
+        info!("Removed {} unreferenced blobs", keys.len());
+    }
+    Ok(keys.len())
+}
+
+// Chunks from the start of `body` until there are at least `len` bytes or it ends
+async fn read_head(body: &mut ByteStream<'_>, len: usize) -> Result<Vec<Bytes>> {
+    let mut head = vec![];
+    let mut read = 0;
+    while read < len {
+        let Some(chunk) = body.try_next().await? else {
+            break;
+        };
+        read += chunk.len();
+        head.push(chunk);
+    }
+    Ok(head)
+}
+
+// The name a file is shown and downloaded under: its last path component, without control
+// characters or ones that are not allowed in file names on common systems, and at most
+// `MAX_FILE_NAME_BYTES` long
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        let c = match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => continue,
+            c => c,
+        };
+        if sanitized.len() + c.len_utf8() > MAX_FILE_NAME_BYTES {
+            break;
+        }
+        sanitized.push(c);
+    }
+    let sanitized = sanitized.trim_matches(|c: char| c.is_whitespace() || c == '.');
+    if sanitized.is_empty() {
+        "file".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+struct Limits {
+    max_bytes: u64,
+    // of the quota
+    left: Option<u64>,
+    quota: u64,
+}
+
+// Passes `stream` on while counting its bytes into `size` and hashing them, failing once there
+// are more than `limits` allow
+fn measure<'a, S, E>(
+    stream: S,
+    limits: Limits,
+    size: &'a mut u64,
+    hasher: &'a mut Sha256,
+) -> ByteStream<'a>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'a,
+    E: Into<BoxError>,
+{
+    stream
+        .map(move |chunk| {
+            let chunk = chunk.map_err(|err| anyhow!(err.into()))?;
+            *size += chunk.len() as u64;
+            if *size > limits.max_bytes {
+                return Err(ApiError::PayloadTooLarge(limits.max_bytes).into());
+            }
+            if limits.left.is_some_and(|left| *size > left) {
+                return Err(ApiError::QuotaExceeded(limits.quota).into());
+            }
+            hasher.update(&chunk);
+            Ok(chunk)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::upload::MockUploadRepository;
+    use crate::storage::MemoryStorage;
+    use crate::tests::fixture::upload::upload_fixture;
+    use mockall::predicate;
+    use std::convert::Infallible;
+
+    fn settings(max_bytes: u64) -> UploadSettings {
+        UploadSettings {
+            max_bytes,
+            max_files: 10,
+            quota: None,
+            form_types: AllowedTypes::default(),
+            body_types: AllowedTypes::default(),
+            resumable_ttl: Duration::from_secs(60),
+            thumbnail_sizes: vec![],
+            link_secret: "secret".to_string(),
+            link_ttl: Duration::from_secs(60),
+        }
+    }
+
+    fn body(chunks: &'static [&'static str]) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))),
+        )
+    }
+
+    fn recording_repo() -> MockUploadRepository {
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_create()
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+        mock_repo_impl
+    }
+
+    fn reserving_repo() -> MockUploadRepository {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_reserve_blob().returning(|_| Ok(()));
+        mock_repo_impl
+    }
+
+    fn new_upload(id: Uuid) -> NewUpload {
+        NewUpload {
+            id,
+            owner: "alice".to_string(),
+            original_name: "notes.txt".to_string(),
+            size: 11,
+            content_type: "text/plain".to_string(),
+            sha256: String::new(),
+            description: None,
+        }
+    }
+
+    fn meta() -> FileMeta {
+        FileMeta {
+            name: "notes.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            description: None,
+        }
+    }
+
+    async fn contents(storage: &dyn Storage, upload: &Upload, start: u64, len: u64) -> Vec<u8> {
+        let chunks: Vec<Bytes> = read(storage, upload, start, len)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn test_upload_records_size_and_hash() {
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_create()
+            .withf(|upload, _| {
+                upload.owner == "alice"
+                    && upload.original_name == "notes.txt"
+                    && upload.size == 11
+                    && upload.sha256
+                        == "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+            })
+            .times(1)
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+        let storage = MemoryStorage::default();
+        let body = body(&["hello", " ", "world"]);
+        let upload = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body,
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents(&storage, &upload, 0, 11).await, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_the_limit_leaves_nothing_behind() {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_create().never();
+        let storage = MemoryStorage::default();
+        let body = body(&["hello", " ", "world"]);
+        let result = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(8),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body,
+        )
+        .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::PayloadTooLarge(8))
+        ));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_upload_of_stored_content_keeps_one_copy() {
+        let stored = Uuid::new_v4().to_string();
+        let blob = stored.clone();
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_create()
+            .times(1)
+            .returning(move |upload, _| {
+                Ok(Upload {
+                    blob: blob.clone(),
+                    ..upload_fixture(upload)
+                })
+            });
+        let storage = MemoryStorage::default();
+        let content = stream::once(async { Ok(Bytes::from_static(b"hello world")) }).boxed();
+        storage.put(&stored, content).await.unwrap();
+
+        let upload = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body(&["hello", " ", "world"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(upload.blob, stored);
+        assert_eq!(storage.len(), 1);
+        assert_eq!(contents(&storage, &upload, 0, 11).await, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage_removes_unreferenced_blobs() {
+        let storage = MemoryStorage::default();
+        for key in ["a", "b", "kept"] {
+            let body = stream::once(async { Ok(Bytes::from_static(b"blob")) }).boxed();
+            storage.put(key, body).await.unwrap();
+        }
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_collect_blobs()
+            .withf(|pending_before| *pending_before < Utc::now() - chrono::Duration::hours(1))
+            .times(1)
+            .returning(|_| Ok(vec!["a".to_string(), "b".to_string(), "gone".to_string()]));
+
+        let collected = collect_garbage(Arc::new(mock_repo_impl), &storage)
+            .await
+            .unwrap();
+        assert_eq!(collected, 3);
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_the_quota_leaves_nothing_behind() {
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_usage()
+            .with(predicate::eq("alice"))
+            .returning(|_| Ok(15));
+        mock_repo_impl
+            .expect_create()
+            .with(predicate::always(), predicate::eq(Some(26)))
+            .times(1)
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+        let repo = Arc::new(mock_repo_impl);
+        let storage = MemoryStorage::default();
+        let upload_with_quota = async |quota| {
+            let settings = UploadSettings {
+                quota: Some(quota),
+                ..settings(100)
+            };
+            let body = body(&["hello", " ", "world"]);
+            let any = AllowedTypes::default();
+            upload(
+                repo.clone(),
+                &storage,
+                &settings,
+                &any,
+                "alice",
+                &meta(),
+                body,
+            )
+            .await
+        };
+
+        let result = upload_with_quota(25).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::QuotaExceeded(25))
+        ));
+        assert_eq!(storage.len(), 0);
+        assert!(upload_with_quota(26).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upload_stores_the_sniffed_type_and_a_safe_name() {
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_create()
+            .withf(|upload, _| {
+                upload.content_type == "application/pdf" && upload.original_name == "a_b.pdf"
+            })
+            .times(1)
+            .returning(|upload, _| Ok(upload_fixture(upload)));
+        let meta = FileMeta {
+            name: "../../etc/a:b.pdf\0".to_string(),
+            content_type: "application/octet-stream".to_string(),
+            description: None,
+        };
+        let storage = MemoryStorage::default();
+        let upload = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(100),
+            &"application/pdf".parse().unwrap(),
+            "alice",
+            &meta,
+            body(&["%PDF", "-1.7\n"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents(&storage, &upload, 0, 9).await, b"%PDF-1.7\n");
+    }
+
+    #[tokio::test]
+    async fn test_upload_of_a_type_not_allowed_leaves_nothing_behind() {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_create().never();
+        let storage = MemoryStorage::default();
+        let result = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(100),
+            &"image/*".parse().unwrap(),
+            "alice",
+            &meta(),
+            body(&["hello"]),
+        )
+        .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::UnsupportedMediaType(_))
+        ));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(sanitize_file_name("notes.txt"), "notes.txt");
+        assert_eq!(
+            sanitize_file_name("C:\\Users\\a\\zdjęcie 1.jpg"),
+            "zdjęcie 1.jpg"
+        );
+        assert_eq!(sanitize_file_name("../../.env"), "env");
+        assert_eq!(sanitize_file_name("a\nb<c>?.txt"), "ab_c__.txt");
+        assert_eq!(sanitize_file_name(" .. "), "file");
+        assert_eq!(sanitize_file_name(""), "file");
+        let long = "ż".repeat(200);
+        assert_eq!(sanitize_file_name(&long), "ż".repeat(127));
+    }
+
+    #[tokio::test]
+    async fn test_upload_removes_the_file_when_it_cannot_be_recorded() {
+        let mut mock_repo_impl = reserving_repo();
+        mock_repo_impl
+            .expect_create()
+            .times(1)
+            .returning(|_, _| Err(anyhow!("database is down")));
+        let storage = MemoryStorage::default();
+        let result = upload(
+            Arc::new(mock_repo_impl),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body(&["hello"]),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_discard_removes_records_and_leaves_content_to_collect() {
+        let storage = MemoryStorage::default();
+        let mut mock_repo_impl = recording_repo();
+        mock_repo_impl
+            .expect_delete()
+            .times(1)
+            .returning(|_| Ok(()));
+        let repo = Arc::new(mock_repo_impl);
+        let stored = upload(
+            repo.clone(),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body(&["hi"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.len(), 1);
+        discard(repo, &storage, &[stored]).await.unwrap();
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_view_hides_other_owners_files() {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl.expect_find_by_id().returning(|id| {
+            let mut upload = upload_fixture(&new_upload(id));
+            upload.owner = "alice".to_string();
+            Ok(upload)
+        });
+        let repo = Arc::new(mock_repo_impl);
+        let id = Uuid::new_v4();
+        assert!(view(repo.clone(), id, "alice", false).await.is_ok());
+        assert!(view(repo.clone(), id, "bob", true).await.is_ok());
+        let hidden = view(repo, id, "bob", false).await.unwrap_err();
+        assert!(crate::error::is_not_found(&hidden));
+    }
+
+    #[tokio::test]
+    async fn test_read_returns_the_requested_bytes() {
+        let storage = MemoryStorage::default();
+        let stored = upload(
+            Arc::new(recording_repo()),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body(&["hello world"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents(&storage, &stored, 6, 3).await, b"wor");
+    }
+
+    #[tokio::test]
+    async fn test_delete_removes_the_record_and_thumbnails() {
+        let storage = MemoryStorage::default();
+        let mut stored = upload(
+            Arc::new(recording_repo()),
+            &storage,
+            &settings(100),
+            &AllowedTypes::default(),
+            "alice",
+            &meta(),
+            body(&["hi"]),
+        )
+        .await
+        .unwrap();
+        stored.thumbnails = vec![128];
+        let thumbnail = stream::once(async { Ok(Bytes::from_static(b"jpg")) }).boxed();
+        storage
+            .put(&thumbnails::key(stored.id, 128), thumbnail)
+            .await
+            .unwrap();
+        let mut mock_repo_impl = MockUploadRepository::new();
+        let found = stored.clone();
+        mock_repo_impl
+            .expect_find_by_id()
+            .returning(move |_| Ok(found.clone()));
+        mock_repo_impl.expect_is_attached().returning(|_| Ok(false));
+        mock_repo_impl
+            .expect_delete()
+            .with(predicate::eq(stored.id))
+            .times(1)
+            .returning(|_| Ok(()));
+        delete(
+            Arc::new(mock_repo_impl),
+            &storage,
+            stored.id,
+            "alice",
+            false,
+        )
+        .await
+        .unwrap();
+        // the content may be shared, it is left to `collect_garbage`
+        assert_eq!(storage.len(), 1);
+        assert_eq!(contents(&storage, &stored, 0, 2).await, b"hi");
+    }
+
+    #[tokio::test]
+    async fn test_delete_refuses_attached_files() {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_find_by_id()
+            .returning(|id| Ok(upload_fixture(&new_upload(id))));
+        mock_repo_impl.expect_is_attached().returning(|_| Ok(true));
+        mock_repo_impl.expect_delete().never();
+        let storage = MemoryStorage::default();
+        let result = delete(
+            Arc::new(mock_repo_impl),
+            &storage,
+            Uuid::new_v4(),
+            "alice",
+            false,
+        )
+        .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ApiError>(),
+            Some(ApiError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_signed_links_open_only_their_file_until_they_expire() {
+        let mut mock_repo_impl = MockUploadRepository::new();
+        mock_repo_impl
+            .expect_find_by_id()
+            .returning(|id| Ok(upload_fixture(&new_upload(id))));
+        let repo = Arc::new(mock_repo_impl);
+        let settings = settings(100);
+        let id = Uuid::new_v4();
+        let (expires_at, signature) = sign_link(&settings, id);
+        let expires = expires_at.timestamp();
+        assert!(expires > Utc::now().timestamp());
+
+        let upload = view_signed(repo.clone(), &settings, id, expires, &signature)
+            .await
+            .unwrap();
+        assert_eq!(upload.id, id);
+
+        let forbidden = |result: Result<Upload>| {
+            matches!(
+                result.unwrap_err().downcast_ref::<ApiError>(),
+                Some(ApiError::Forbidden)
+            )
+        };
+        let other = Uuid::new_v4();
+        assert!(forbidden(
+            view_signed(repo.clone(), &settings, other, expires, &signature).await
+        ));
+        assert!(forbidden(
+            view_signed(repo.clone(), &settings, id, expires + 60, &signature).await
+        ));
+        assert!(forbidden(
+            view_signed(repo.clone(), &settings, id, expires, "not-a-signature").await
+        ));
+
+        let past = Utc::now().timestamp() - 1;
+        let signature =
+            URL_SAFE_NO_PAD.encode(link_mac(&settings, id, past).finalize().into_bytes());
+        assert!(forbidden(
+            view_signed(repo, &settings, id, past, &signature).await
+        ));
+    }
+
+    #[test]
+    fn test_declared_length_over_the_limit_is_rejected() {
+        let settings = settings(10);
+        assert!(settings.check_length(None).is_ok());
+        assert!(settings.check_length(Some(10)).is_ok());
+        assert!(settings.check_length(Some(11)).is_err());
+    }
+}
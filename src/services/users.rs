@@ -1,7 +1,11 @@
+use crate::cache::{CacheImpl, Entity};
 use crate::controllers::{CommonQuery, Pagination};
+use crate::error::ApiError;
+use crate::models::audit::AuditContext;
 use crate::models::user::{User, UserAuth, UserList, UserQuery};
 use crate::repositories::user::UserRepository;
 use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 use tracing::info;
 use validator::Validate;
@@ -16,22 +20,41 @@ pub async fn find_all<R: UserRepository>(
     Ok(users)
 }
 
-pub async fn view<R: UserRepository>(repo: Arc<R>, username: &str) -> Result<User> {
-    info!("Fetching user {} from db...", username);
-    // query the database
-    let user = repo.find_by_username(username).await?;
-
-    Ok(user)
+pub async fn view<R: UserRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    username: &str,
+    include_deleted: bool,
+) -> Result<User> {
+    // Soft-deleted rows are never cached
+    if include_deleted {
+        return repo.find_by_username(username, true).await;
+    }
+    cache
+        .get_or_load(Entity::User, &Entity::User.key(username), &[], || {
+            repo.find_by_username(username, false)
+        })
+        .await
 }
 
-pub async fn create<R: UserRepository>(repo: Arc<R>, new_user: &UserAuth) -> Result<User> {
+pub async fn create<R: UserRepository>(
+    repo: Arc<R>,
+    new_user: &UserAuth,
+    ctx: &AuditContext,
+) -> Result<User> {
     new_user.validate()?;
-    let user = repo.create(new_user).await?;
+    let user = repo.create(new_user, ctx).await?;
     Ok(user)
 }
 
-pub async fn update<R: UserRepository>(repo: Arc<R>, user: &UserAuth) -> Result<User> {
-    let user = repo.update(user).await?;
+pub async fn update<R: UserRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    user: &UserAuth,
+    ctx: &AuditContext,
+) -> Result<User> {
+    let user = repo.update(user, ctx).await?;
+    cache.invalidate(&Entity::User.key(&user.username)).await;
     Ok(user)
 }
 
@@ -41,7 +64,7 @@ pub async fn login<R: UserRepository>(repo: Arc<R>, user: &UserAuth) -> Result<U
         anyhow::bail!("MissingCredentials");
     }
 
-    let db_user = repo.find_by_username(&user.username).await?;
+    let db_user = repo.find_by_username(&user.username, false).await?;
     //verrify
     let verified =
         crate::password::verify(user.password.clone(), db_user.password_hash.to_string()).await?;
@@ -52,10 +75,43 @@ pub async fn login<R: UserRepository>(repo: Arc<R>, user: &UserAuth) -> Result<U
     Ok(db_user)
 }
 
-pub async fn delete<R: UserRepository>(repo: Arc<R>, username: &str) -> Result<u64> {
-    let affected_rows = repo.delete(username).await?;
+pub async fn delete<R: UserRepository>(
+    repo: Arc<R>,
+    cache: Arc<CacheImpl>,
+    username: &str,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.delete(username, ctx).await?;
+    cache.invalidate(&Entity::User.key(username)).await;
+    check_affected_rows(affected_rows, username)
+}
+
+pub async fn restore<R: UserRepository>(
+    repo: Arc<R>,
+    username: &str,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.restore(username, ctx).await?;
+    check_affected_rows(affected_rows, username)
+}
+
+// Hard-deletes users that were soft-deleted before the cutoff
+pub async fn purge<R: UserRepository>(
+    repo: Arc<R>,
+    deleted_before: DateTime<Utc>,
+    ctx: &AuditContext,
+) -> Result<u64> {
+    let affected_rows = repo.purge(deleted_before, ctx).await?;
+    if affected_rows > 0 {
+        info!("Purged {} deleted users", affected_rows);
+    }
+    Ok(affected_rows)
+}
+
+fn check_affected_rows(affected_rows: u64, username: &str) -> Result<u64> {
     if affected_rows == 0 {
-        bail!("No rows affected, user with ID {} not found", username);
+        info!("No rows affected, user with ID {} not found", username);
+        return Err(ApiError::NotFound.into());
     } else if affected_rows > 1 {
         bail!("Unexpected number of rows affected: {}", affected_rows);
     }
@@ -66,7 +122,8 @@ pub async fn delete<R: UserRepository>(repo: Arc<R>, username: &str) -> Result<u
 mod tests {
     use super::*;
     use crate::repositories::user::MockUserRepository;
-    use crate::tests::fixture::user::users_fixture;
+    use crate::tests::fixture::cache::local_cache_fixture;
+    use crate::tests::fixture::user::{user_fixture, users_fixture};
 
     #[tokio::test]
     async fn test_find_all() {
@@ -75,7 +132,10 @@ mod tests {
             .expect_find_all()
             .returning(|_, _, _| Ok(users_fixture(5)));
         let conditions = UserQuery { username: None };
-        let query = CommonQuery { ids: [].to_vec() };
+        let query = CommonQuery {
+            ids: [].to_vec(),
+            ..Default::default()
+        };
         let pagination = Pagination {
             page: None,
             per_page: None,
@@ -87,4 +147,22 @@ mod tests {
             .unwrap();
         assert_eq!(users.data.len(), 5);
     }
+
+    #[tokio::test]
+    async fn test_view_does_not_cache_the_password_hash() {
+        let mut mock_repo_impl = MockUserRepository::new();
+        mock_repo_impl
+            .expect_find_by_username()
+            .times(1)
+            .returning(|_, _| Ok(user_fixture(1)));
+        let repo = Arc::new(mock_repo_impl);
+        let cache = local_cache_fixture();
+
+        view(repo.clone(), cache.clone(), "ferrari 1", false)
+            .await
+            .unwrap();
+        let cached = view(repo, cache, "ferrari 1", false).await.unwrap();
+        assert_eq!(cached.username, "ferrari 1");
+        assert!(cached.password_hash.is_empty());
+    }
 }
@@ -0,0 +1,81 @@
+use super::{ByteStream, Storage};
+use crate::error::ApiError;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+// A file being written. Removed unless it was kept, also when the write is cancelled midway.
+struct Partial {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// One file per key in a directory, see `Config::upload_dir`
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .await
+            .unwrap_or_else(|e| panic!("UPLOAD_DIR {} cannot be created: {e}", dir.display()));
+        Self { dir }
+    }
+
+    async fn write(path: &Path, mut body: ByteStream<'_>) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        while let Some(chunk) = body.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    // Written next to its final place and renamed once complete, so the key never names a
+    // partial file
+    async fn put(&self, key: &str, body: ByteStream<'_>) -> Result<()> {
+        let mut partial = Partial {
+            path: self.dir.join(format!(".{key}.tmp-{}", Uuid::new_v4())),
+            kept: false,
+        };
+        Self::write(&partial.path, body).await?;
+        fs::rename(&partial.path, self.dir.join(key)).await?;
+        partial.kept = true;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, start: u64, len: u64) -> Result<ByteStream<'static>> {
+        let mut file = match File::open(self.dir.join(key)).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(ApiError::NotFound.into()),
+            file => file?,
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(ReaderStream::new(file.take(len)).err_into().boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.dir.join(key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use super::{ByteStream, Storage};
+use crate::error::ApiError;
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Keeps everything in the process, for tests and throwaway instances
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryStorage {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, body: ByteStream<'_>) -> Result<()> {
+        let chunks: Vec<Bytes> = body.try_collect().await?;
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), chunks.concat().into());
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, start: u64, len: u64) -> Result<ByteStream<'static>> {
+        let object = self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or(ApiError::NotFound)?;
+        let start = (start as usize).min(object.len());
+        let end = start.saturating_add(len as usize).min(object.len());
+        Ok(stream::once(async move { Ok(object.slice(start..end)) }).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
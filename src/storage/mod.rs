@@ -0,0 +1,130 @@
+use crate::config::Config;
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::Extension;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use std::sync::Arc;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+mod local;
+mod memory;
+mod s3;
+
+pub type ByteStream<'a> = BoxStream<'a, Result<Bytes>>;
+
+pub type StorageExt = Extension<Arc<dyn Storage>>;
+
+// Where the bytes of uploads are kept. Keys are upload ids, never anything a client chose.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Stores everything `body` yields under `key`. When the stream fails its error is returned
+    // as is and nothing is kept under `key`.
+    async fn put(&self, key: &str, body: ByteStream<'_>) -> Result<()>;
+    // `len` bytes starting at `start`, fewer when the object ends first, `ApiError::NotFound`
+    // when there is no such key
+    async fn get(&self, key: &str, start: u64, len: u64) -> Result<ByteStream<'static>>;
+    // Removing a key that is not there is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+// Picks the backend named by `Config::storage_backend`
+pub async fn create_storage(config: &Config) -> Arc<dyn Storage> {
+    match config.storage_backend.as_str() {
+        "local" => Arc::new(LocalStorage::new(&config.upload_dir).await),
+        "memory" => Arc::new(MemoryStorage::default()),
+        "s3" => Arc::new(S3Storage::new(config)),
+        other => panic!("STORAGE_BACKEND is invalid: {other:?}, expected local, memory or s3"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ApiError, is_not_found};
+    use anyhow::anyhow;
+    use futures::{StreamExt, TryStreamExt, stream};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn body(chunks: &'static [&'static str]) -> ByteStream<'static> {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))),
+        )
+        .boxed()
+    }
+
+    async fn read(storage: &dyn Storage, key: &str, start: u64, len: u64) -> Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = storage.get(key, start, len).await?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    // What every backend has to do the same way
+    async fn behaves_like_storage(storage: &dyn Storage) {
+        let key = Uuid::new_v4().to_string();
+        storage
+            .put(&key, body(&["hello", " ", "world"]))
+            .await
+            .unwrap();
+        assert_eq!(read(storage, &key, 0, 11).await.unwrap(), b"hello world");
+        assert_eq!(read(storage, &key, 6, 3).await.unwrap(), b"wor");
+        assert_eq!(read(storage, &key, 11, 0).await.unwrap(), b"");
+
+        storage.delete(&key).await.unwrap();
+        assert!(is_not_found(&read(storage, &key, 0, 1).await.unwrap_err()));
+        storage.delete(&key).await.unwrap();
+
+        let failed = Uuid::new_v4().to_string();
+        let failing = stream::iter([
+            Ok(Bytes::from_static(b"part")),
+            Err(anyhow!(ApiError::PayloadTooLarge(4))),
+        ])
+        .boxed();
+        let err = storage.put(&failed, failing).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::PayloadTooLarge(4))
+        ));
+        assert!(is_not_found(
+            &read(storage, &failed, 0, 1).await.unwrap_err()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        behaves_like_storage(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = std::env::temp_dir().join(format!("uploads-{}", Uuid::new_v4()));
+        behaves_like_storage(&LocalStorage::new(&dir).await).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_storage_drops_cancelled_writes() {
+        let dir = std::env::temp_dir().join(format!("uploads-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(&dir).await;
+        let key = Uuid::new_v4().to_string();
+        let stalled = stream::iter([Ok(Bytes::from_static(b"part"))])
+            .chain(stream::pending())
+            .boxed();
+        let put = tokio::time::timeout(Duration::from_millis(50), storage.put(&key, stalled));
+        assert!(put.await.is_err());
+        assert!(is_not_found(&read(&storage, &key, 0, 1).await.unwrap_err()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    // needs an S3 compatible server, see the `storage` service in compose.yaml
+    #[tokio::test]
+    #[ignore]
+    async fn test_s3_storage() {
+        dotenv::dotenv().ok();
+        behaves_like_storage(&S3Storage::new(&Config::init())).await;
+    }
+}
@@ -0,0 +1,110 @@
+use super::{ByteStream, Storage};
+use crate::config::Config;
+use crate::error::ApiError;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt, stream};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::{GetOptions, GetRange, ObjectStore, WriteMultipart};
+use tracing::warn;
+
+// Parts being sent to the bucket at the same time for one upload
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+// A multipart upload being written. Aborted unless it was finished, also when the write is
+// cancelled midway. The abort is sent in the background as it cannot be awaited here.
+struct Partial(Option<WriteMultipart>);
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if let Some(writer) = self.0.take()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move {
+                if let Err(err) = writer.abort().await {
+                    warn!(%err, "Could not abort a multipart upload");
+                }
+            });
+        }
+    }
+}
+
+// A bucket of S3 or of anything speaking its API, like MinIO
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    // Credentials left empty in the config are taken from the usual `AWS_*` variables
+    pub fn new(config: &Config) -> Self {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.s3_bucket)
+            .with_region(&config.s3_region);
+        if !config.s3_endpoint.is_empty() {
+            builder = builder
+                .with_endpoint(&config.s3_endpoint)
+                .with_allow_http(true);
+        }
+        if !config.s3_access_key_id.is_empty() {
+            builder = builder
+                .with_access_key_id(&config.s3_access_key_id)
+                .with_secret_access_key(&config.s3_secret_access_key);
+        }
+        let store = builder
+            .build()
+            .unwrap_or_else(|e| panic!("S3 storage is misconfigured: {e}"));
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    // Streams as a multipart upload, which is aborted when the body fails or the write is
+    // cancelled, so no object appears. One cancelled just as it is started or finished can still
+    // be left open, the bucket should have a rule aborting incomplete multipart uploads too.
+    async fn put(&self, key: &str, mut body: ByteStream<'_>) -> Result<()> {
+        let upload = self.store.put_multipart(&Path::from(key)).await?;
+        let mut partial = Partial(Some(WriteMultipart::new(upload)));
+        let writer = partial.0.as_mut().expect("the upload is open");
+        while let Some(chunk) = body.try_next().await? {
+            writer.wait_for_capacity(MAX_CONCURRENT_PARTS).await?;
+            writer.put(chunk);
+        }
+        let writer = partial.0.take().expect("the upload is open");
+        writer.finish().await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, start: u64, len: u64) -> Result<ByteStream<'static>> {
+        // S3 refuses empty ranges
+        if len == 0 {
+            self.store.head(&Path::from(key)).await.map_err(not_found)?;
+            return Ok(stream::empty().boxed());
+        }
+        let options = GetOptions {
+            range: Some(GetRange::Bounded(start..start + len)),
+            ..Default::default()
+        };
+        let object = self
+            .store
+            .get_opts(&Path::from(key), options)
+            .await
+            .map_err(not_found)?;
+        Ok(object.into_stream().err_into().boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self.store.delete(&Path::from(key)).await {
+            Err(err) if !matches!(err, object_store::Error::NotFound { .. }) => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn not_found(err: object_store::Error) -> anyhow::Error {
+    match err {
+        object_store::Error::NotFound { .. } => ApiError::NotFound.into(),
+        err => err.into(),
+    }
+}
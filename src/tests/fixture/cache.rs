@@ -0,0 +1,33 @@
+use crate::cache::{CacheImpl, CacheSettings, CacheTtl};
+use std::sync::Arc;
+use std::time::Duration;
+
+// A cache without Redis or a local tier, every read goes to the repository
+#[allow(dead_code)]
+pub fn cache_fixture() -> Arc<CacheImpl> {
+    Arc::new(CacheImpl::new(None, settings(0)))
+}
+
+// A cache without Redis that keeps entries in its local tier, for tests of what gets cached and
+// invalidated
+#[allow(dead_code)]
+pub fn local_cache_fixture() -> Arc<CacheImpl> {
+    Arc::new(CacheImpl::new(None, settings(100)))
+}
+
+fn settings(local_capacity: usize) -> CacheSettings {
+    CacheSettings {
+        ttl: CacheTtl {
+            car: 60,
+            part: 60,
+            user: 60,
+        },
+        timeout: Duration::from_millis(100),
+        breaker_threshold: 5,
+        breaker_cooldown: Duration::from_secs(30),
+        local_capacity,
+        local_ttl: Duration::from_secs(5),
+        stale: Duration::ZERO,
+        lock: Duration::from_secs(2),
+    }
+}
@@ -1,4 +1,5 @@
 use crate::models::car::{Car, CarList};
+use chrono::Utc;
 
 #[allow(dead_code)]
 pub fn car_fixture(id: i32) -> Car {
@@ -7,6 +8,12 @@ pub fn car_fixture(id: i32) -> Car {
         name: String::from("ferrari"),
         color: Some(String::from("black")),
         year: Some(1980),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        created_by: None,
+        updated_by: None,
+        deleted_at: None,
+        version: 1,
     }
 }
 
@@ -1,3 +1,5 @@
+pub mod cache;
 pub mod car;
 pub mod part;
+pub mod upload;
 pub mod user;
@@ -1,4 +1,5 @@
 use crate::models::part::{Part, PartList};
+use chrono::Utc;
 
 #[allow(dead_code)]
 pub fn part_fixture(id: usize) -> Part {
@@ -6,6 +7,12 @@ pub fn part_fixture(id: usize) -> Part {
         id: id as i32,
         car_id: Some(1),
         name: String::from("alternator"),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        created_by: None,
+        updated_by: None,
+        deleted_at: None,
+        version: 1,
     }
 }
 
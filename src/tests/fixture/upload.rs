@@ -0,0 +1,18 @@
+use crate::models::upload::{NewUpload, Upload};
+use chrono::Utc;
+
+#[allow(dead_code)]
+pub fn upload_fixture(upload: &NewUpload) -> Upload {
+    Upload {
+        id: upload.id,
+        owner: upload.owner.clone(),
+        original_name: upload.original_name.clone(),
+        size: upload.size,
+        content_type: upload.content_type.clone(),
+        sha256: upload.sha256.clone(),
+        description: upload.description.clone(),
+        created_at: Utc::now(),
+        thumbnails: vec![],
+        blob: upload.id.to_string(),
+    }
+}
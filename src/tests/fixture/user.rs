@@ -1,4 +1,5 @@
 use crate::models::user::{User, UserList};
+use chrono::Utc;
 
 #[allow(dead_code)]
 pub fn user_fixture(id: i32) -> User {
@@ -6,6 +7,12 @@ pub fn user_fixture(id: i32) -> User {
         id,
         username: format!("ferrari {}", id),
         password_hash: String::from("black"),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        created_by: None,
+        updated_by: None,
+        deleted_at: None,
+        is_admin: false,
     }
 }
 